@@ -0,0 +1,78 @@
+//! Benchmarks for the map processing functions, over arenas of a few sizes.
+//!
+//! Run with `cargo bench -p common`.
+
+#[macro_use] extern crate criterion;
+extern crate common;
+
+use criterion::Criterion;
+use common::prelude::*;
+
+use map_utils::{Map, MapBuilder};
+
+// the sizes of the maps to benchmark, in cells along each side.
+const SIZES: [u32; 3] = [64, 128, 256];
+
+// builds a square arena with walls around the edge, a few obstacles spread
+// through the middle, and some noise.
+fn arena(size: u32) -> Map
+{
+    let n = size as usize;
+    let mut builder = MapBuilder::new(size, size)
+        .fill(-1)
+        .rect((1, 1), (n - 2, n - 2), 0)
+        .rect((0, 0), (0, n - 1), 100)
+        .rect((n - 1, 0), (n - 1, n - 1), 100)
+        .rect((0, 0), (n - 1, 0), 100)
+        .rect((0, n - 1), (n - 1, n - 1), 100);
+
+    for i in 1..4
+    {
+        let c = i * n / 4;
+
+        builder = builder
+            .ring((c, c), n as Num / 16.0, 100)
+            .rect((c, n - c - 2), (c + 4, n - c + 2), 100);
+    }
+
+    builder.noise(0.01, 100, 726).build()
+}
+
+fn filter_map(c: &mut Criterion)
+{
+    c.bench_function_over_inputs("filter_map", |b, &size|
+    {
+        let map = arena(size);
+        b.iter(|| map_utils::filter_map(&map, |v| v > 3));
+    }, SIZES.to_vec());
+}
+
+fn extract_groups(c: &mut Criterion)
+{
+    c.bench_function_over_inputs("extract_groups", |b, &size|
+    {
+        let map = arena(size);
+        b.iter(|| map_utils::extract_groups(&map, |v| v > 3, 3));
+    }, SIZES.to_vec());
+}
+
+fn extract_groups_dense(c: &mut Criterion)
+{
+    c.bench_function_over_inputs("extract_groups_dense", |b, &size|
+    {
+        let map = arena(size);
+        b.iter(|| map_utils::extract_groups_dense(&map, |v| v > 3, 3));
+    }, SIZES.to_vec());
+}
+
+fn extract_groups_tiled(c: &mut Criterion)
+{
+    c.bench_function_over_inputs("extract_groups_tiled", |b, &size|
+    {
+        let map = arena(size);
+        b.iter(|| map_utils::extract_groups_tiled(&map, |v| v > 3, 3, map_utils::Connectivity::Eight, 32));
+    }, SIZES.to_vec());
+}
+
+criterion_group!(benches, filter_map, extract_groups, extract_groups_dense, extract_groups_tiled);
+criterion_main!(benches);
@@ -2,7 +2,12 @@
 rosmsg_main!(
     "nav_msgs/OccupancyGrid",
     "geometry_msgs/Pose2D",
+    "geometry_msgs/PoseArray",
     "geometry_msgs/Twist",
-    "sensor_msgs/LaserScan"
+    "sensor_msgs/LaserScan",
+    "sensor_msgs/PointCloud2",
+    "std_msgs/Float64",
+    "tf2_msgs/TFMessage",
+    "od2rs_msgs/ClassifyPoints"
 );
 
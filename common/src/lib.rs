@@ -9,12 +9,39 @@
 
 extern crate fnv;
 extern crate rayon;
+extern crate serde;
+
+/// Numeric traits, for code that works in both `f32` and `f64`; see
+/// `num_traits::Float`.
+pub extern crate num_traits;
+
+#[cfg(feature = "image")]
+extern crate image;
 
 /// This module contains ROS messages generated by the `rosrust_codegen` crate.
 rosmsg_include!();
 
 /// The prelude, which provides some common stuff. This should be `use`d as
 /// `use common::prelude::*;`.
+///
+/// This includes the `HashMap` and `HashSet` from `map_utils`, which use the
+/// fast FNV hash rather than the default one, so there's no need to reach for
+/// `std::collections`. Since they don't use the default hasher, make them with
+/// `default()` rather than `new()`:
+///
+/// ```
+/// extern crate common;
+/// use common::prelude::*;
+///
+/// let mut cells: HashSet<(usize, usize)> = HashSet::default();
+/// cells.insert((1, 2));
+///
+/// let mut counts: HashMap<&str, usize> = HashMap::default();
+/// *counts.entry("occupied").or_insert(0) += 1;
+///
+/// assert!(cells.contains(&(1, 2)));
+/// assert_eq!(counts["occupied"], 1);
+/// ```
 pub mod prelude
 {
     pub use rosrust;
@@ -22,18 +49,469 @@ pub mod prelude
 
     pub use rayon::prelude::*;
 
+    pub use map_utils::{HashMap, HashSet};
+
     /// The numeric type to use for all calculations.
     /// This should be either `f32` or `f64`.
     pub type Num = f64;
 
+    /// A position and heading in the plane; `theta` is in radians,
+    /// anticlockwise from the `x` axis.
+    ///
+    /// Use this rather than passing around bare tuples, and convert to and from
+    /// the ROS message types at the edges with `to_ros` and `from_ros`.
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub struct Pose2D
+    {
+        pub x: Num,
+        pub y: Num,
+        pub theta: Num,
+    }
+
+    impl Pose2D
+    {
+        /// Creates a new pose.
+        pub fn new(x: Num, y: Num, theta: Num) -> Self
+        {
+            Pose2D { x, y, theta }
+        }
+
+        /// Converts the pose into a ROS `Pose`, at `z = 0` and with the heading
+        /// as a rotation about the `z` axis.
+        pub fn to_ros(&self) -> msg::geometry_msgs::Pose
+        {
+            let mut pose = msg::geometry_msgs::Pose::default();
+
+            pose.position.x = self.x;
+            pose.position.y = self.y;
+
+            pose.orientation.z = (self.theta / 2.0).sin();
+            pose.orientation.w = (self.theta / 2.0).cos();
+
+            pose
+        }
+
+        /// Converts a ROS `Pose` into a pose in the plane, by dropping `z` and
+        /// keeping only the yaw of the orientation. `theta` ends up in
+        /// `(-pi, pi]`.
+        pub fn from_ros(pose: &msg::geometry_msgs::Pose) -> Self
+        {
+            let q = &pose.orientation;
+
+            let theta = (2.0 * (q.w*q.z + q.x*q.y)).atan2(1.0 - 2.0 * (q.y*q.y + q.z*q.z));
+
+            Pose2D { x: pose.position.x, y: pose.position.y, theta }
+        }
+    }
+
+    /// Wraps an angle (in radians) into `(-pi, pi]`, e.g `3*pi/2` becomes
+    /// `-pi/2`, and `-pi` becomes `pi`.
+    pub fn wrap_angle(theta: Num) -> Num
+    {
+        use std::f64::consts::PI;
+
+        let turn = 2.0 * PI;
+
+        // this lands in `[-pi, pi]`, so only one end needs fixing up.
+        let wrapped = theta - turn * (theta / turn).round();
+
+        if wrapped <= -PI { wrapped + turn } else { wrapped }
+    }
+
+    /// The shortest signed angle (in radians) that takes `b` to `a`, in
+    /// `(-pi, pi]`; e.g from `170` degrees to `-170` degrees is `20` degrees,
+    /// not `-340`. Use this rather than subtracting the angles directly
+    /// whenever two angles are compared.
+    pub fn angle_diff(a: Num, b: Num) -> Num
+    {
+        wrap_angle(a - b)
+    }
+
     pub use super::*;
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+
+        #[test]
+        fn poses_survive_the_round_trip_through_ros()
+        {
+            for &theta in [0.0, 0.3, -2.0, 3.0, 3.14159].iter()
+            {
+                let pose = Pose2D::from_ros(&Pose2D::new(1.0, -2.0, theta).to_ros());
+
+                assert!((pose.x - 1.0).abs() < 1e-12 && (pose.y + 2.0).abs() < 1e-12, "{:?}", pose);
+                assert!((pose.theta - theta).abs() < 1e-9, "{} came back as {}", theta, pose.theta);
+            }
+
+            // only the heading is kept, and it's wrapped.
+            let pose = Pose2D::from_ros(&Pose2D::new(0.0, 0.0, 3.0 * ::std::f64::consts::PI / 2.0).to_ros());
+            assert!((pose.theta + ::std::f64::consts::PI / 2.0).abs() < 1e-9, "{:?}", pose);
+        }
+
+        #[test]
+        fn angles_wrap_into_minus_pi_to_pi()
+        {
+            use std::f64::consts::PI;
+
+            // `pi` is the one end that's included.
+            assert_eq!(wrap_angle(PI), PI);
+            assert_eq!(wrap_angle(-PI), PI);
+            assert!((wrap_angle(PI + 1e-9) + PI).abs() < 1e-6, "{}", wrap_angle(PI + 1e-9));
+
+            assert!((wrap_angle(1.5 * PI) + 0.5 * PI).abs() < 1e-12);
+            assert!((wrap_angle(0.3 + 1000.0 * 2.0 * PI) - 0.3).abs() < 1e-9, "{}", wrap_angle(0.3 + 1000.0 * 2.0 * PI));
+            assert!((wrap_angle(0.3 - 1000.0 * 2.0 * PI) - 0.3).abs() < 1e-9, "{}", wrap_angle(0.3 - 1000.0 * 2.0 * PI));
+        }
+
+        #[test]
+        fn angle_diff_takes_the_short_way_round()
+        {
+            let deg = |d: Num| d.to_radians();
+
+            assert!((angle_diff(deg(170.0), deg(-170.0)) - deg(-20.0)).abs() < 1e-12);
+            assert!((angle_diff(deg(-170.0), deg(170.0)) - deg(20.0)).abs() < 1e-12);
+            assert!((angle_diff(deg(30.0), deg(10.0)) - deg(20.0)).abs() < 1e-12);
+        }
+    }
+}
+
+/// Module containing utils for controlling how much parallelism is used.
+///
+/// Everything in this crate that runs in parallel (`filter_map`,
+/// `par_transform`, etc) uses `rayon`'s global thread pool by default, which
+/// uses every core. Running it inside `with_pool` instead lets you cap the
+/// number of threads, so that e.g a ROS callback doesn't starve the rest of
+/// the process.
+pub mod parallel
+{
+    pub use rayon::{ThreadPool, ThreadPoolBuildError};
+
+    /// Creates a thread pool with the given number of threads.
+    pub fn thread_pool(threads: usize) -> Result<ThreadPool, ThreadPoolBuildError>
+    {
+        ::rayon::ThreadPoolBuilder::new().num_threads(threads).build()
+    }
+
+    /// Runs `f` inside the given thread pool, so that any parallel work it
+    /// does is limited to that pool's threads.
+    pub fn with_pool<F, R>(pool: &ThreadPool, f: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send
+    {
+        pool.install(f)
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+        use ::rayon::prelude::*;
+
+        #[test]
+        fn with_pool_caps_the_threads()
+        {
+            let pool = thread_pool(2).unwrap();
+
+            assert_eq!(with_pool(&pool, || ::rayon::current_num_threads()), 2);
+
+            // the work still gets done, and the result comes back out.
+            let sum: u64 = with_pool(&pool, || (1..101u64).into_par_iter().sum());
+            assert_eq!(sum, 5050);
+        }
+    }
+}
+
+/// Module containing utils for retrying things that can fail for a while, e.g
+/// setting up topics while the ROS master is restarting.
+pub mod retry
+{
+    use std::fmt::Debug;
+    use std::thread;
+    use std::time::Duration;
+
+    /// How long to wait between attempts, and when to give up.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Backoff
+    {
+        /// How long to wait after the first failure. The wait doubles after
+        /// each failure after that.
+        pub initial: Duration,
+
+        /// The longest to wait between two attempts.
+        pub max_delay: Duration,
+
+        /// How many attempts to make in total (including the first) before
+        /// giving up. At least one attempt is always made.
+        pub max_attempts: usize,
+    }
+
+    impl Default for Backoff
+    {
+        /// Waits 0.1s, then 0.2s, 0.4s etc up to 5s, for about half a minute
+        /// in total, which is long enough for the master to be restarted.
+        fn default() -> Self
+        {
+            Backoff
+            {
+                initial: Duration::from_millis(100),
+                max_delay: Duration::from_secs(5),
+                max_attempts: 10,
+            }
+        }
+    }
+
+    impl Backoff
+    {
+        /// How long to wait after the `n`th failure, counting from zero.
+        pub fn delay(&self, n: usize) -> Duration
+        {
+            // past 2^31 the cap has long since kicked in anyway.
+            let factor = 1u32 << n.min(31);
+
+            self.initial.checked_mul(factor).map_or(self.max_delay, |d| d.min(self.max_delay))
+        }
+    }
+
+    /// Calls `f` until it succeeds, waiting longer and longer between each
+    /// attempt, and gives back the last error if it still hasn't succeeded
+    /// after `backoff.max_attempts` attempts. Each failure is logged, as
+    /// "Could not <what>".
+    pub fn with_backoff<T, E, F>(backoff: Backoff, what: &str, f: F) -> Result<T, E>
+    where
+        E: Debug,
+        F: FnMut() -> Result<T, E>
+    {
+        with_backoff_using(backoff, what, f, thread::sleep)
+    }
+
+    /// Like `with_backoff`, but waits by calling `sleep`, e.g to check the
+    /// waits without actually waiting.
+    pub fn with_backoff_using<T, E, F, S>(backoff: Backoff, what: &str, mut f: F, mut sleep: S) -> Result<T, E>
+    where
+        E: Debug,
+        F: FnMut() -> Result<T, E>,
+        S: FnMut(Duration)
+    {
+        let mut failures = 0;
+
+        loop
+        {
+            let e = match f()
+            {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+
+            failures += 1;
+
+            if failures >= backoff.max_attempts
+            {
+                println!("ERROR! Could not {}: {:?}. Giving up after {} attempts", what, e, failures);
+                return Err(e);
+            }
+
+            let delay = backoff.delay(failures - 1);
+
+            println!("WARNING! Could not {}: {:?}. Trying again in {}.{:03}s",
+                what, e, delay.as_secs(), delay.subsec_nanos() / 1_000_000);
+
+            sleep(delay);
+        }
+    }
+
+    /// Notices when the ROS master has been restarted, so that the topics can
+    /// be set up again with the new one; the old subscriptions and
+    /// publications went with the old master.
+    ///
+    /// The master picks a new `/run_id` each time it starts, so a restart shows
+    /// up as a change in it.
+    #[derive(Debug, Clone, Default)]
+    pub struct RunIdWatcher
+    {
+        run_id: Option<String>,
+    }
+
+    impl RunIdWatcher
+    {
+        /// Starts watching from the current run.
+        pub fn new() -> Self
+        {
+            let mut watcher = RunIdWatcher::default();
+            watcher.saw(current_run_id());
+            watcher
+        }
+
+        /// Whether the master has been restarted since the last check (or
+        /// since `new`).
+        pub fn restarted(&mut self) -> bool
+        {
+            self.saw(current_run_id())
+        }
+
+        // takes note of `run_id`, and says whether it's a new run. While the
+        // master is down, there is no run id at all, which isn't a new run
+        // yet; the restart is only noticed once the new master is up.
+        fn saw(&mut self, run_id: Option<String>) -> bool
+        {
+            let run_id = match run_id
+            {
+                Some(run_id) => run_id,
+                None => return false,
+            };
+
+            let restarted = self.run_id.as_ref().map_or(false, |last| *last != run_id);
+
+            self.run_id = Some(run_id);
+
+            restarted
+        }
+    }
+
+    // the master's current `/run_id`, or `None` if it can't be reached.
+    fn current_run_id() -> Option<String>
+    {
+        ::rosrust::param("/run_id").and_then(|p| p.get::<String>().ok())
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+
+        use std::cell::Cell;
+
+        #[test]
+        fn retries_wait_longer_each_time_and_give_up()
+        {
+            let backoff = Backoff
+            {
+                initial: Duration::from_millis(100),
+                max_delay: Duration::from_millis(500),
+                max_attempts: 5,
+            };
+
+            // always fails.
+            let attempts = Cell::new(0);
+            let mut waits = Vec::new();
+
+            let result: Result<(), &str> = with_backoff_using(backoff, "fail", || { attempts.set(attempts.get() + 1); Err("no master") }, |d| waits.push(d));
+
+            assert_eq!(result, Err("no master"));
+            assert_eq!(attempts.get(), 5);
+
+            // no wait after the last attempt, and the doubling stops at the cap.
+            let millis: Vec<u64> = waits.iter().map(|d| d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64).collect();
+            assert_eq!(millis, vec![100, 200, 400, 500]);
+
+            // succeeds on the third attempt.
+            let attempts = Cell::new(0);
+            let mut waits = Vec::new();
+
+            let result: Result<usize, &str> = with_backoff_using(backoff, "succeed", ||
+            {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 { Err("no master") } else { Ok(attempts.get()) }
+            },
+            |d| waits.push(d));
+
+            assert_eq!(result, Ok(3));
+            assert_eq!(waits.len(), 2);
+
+            // at least one attempt is always made.
+            let attempts = Cell::new(0);
+            let none = Backoff { max_attempts: 0, ..backoff };
+            let _: Result<(), &str> = with_backoff_using(none, "fail", || { attempts.set(attempts.get() + 1); Err("no master") }, |_| panic!("waited"));
+            assert_eq!(attempts.get(), 1);
+        }
+
+        #[test]
+        fn a_new_run_id_means_the_master_restarted()
+        {
+            let mut watcher = RunIdWatcher::default();
+
+            assert!(!watcher.saw(Some("first".to_string())));
+            assert!(!watcher.saw(Some("first".to_string())));
+
+            // while the master is down, there's no run id to go by.
+            assert!(!watcher.saw(None));
+
+            assert!(watcher.saw(Some("second".to_string())));
+            assert!(!watcher.saw(Some("second".to_string())));
+        }
+    }
+}
+
+/// Module containing utils for reading settings from the ROS parameter server.
+pub mod params
+{
+    use serde::de::DeserializeOwned;
+
+    /// Reads the parameter `name`, or gives back `default` if it isn't set (or
+    /// isn't a `T`).
+    ///
+    /// Use this for anything that someone might want to change without
+    /// recompiling, e.g topic names, so that they can be set from a launch
+    /// file instead.
+    pub fn get_or<T: DeserializeOwned>(name: &str, default: T) -> T
+    {
+        or_default(::rosrust::param(name).map(|p| p.get::<T>()), default)
+    }
+
+    // the value of a parameter, which might not be set (`None`), or might not
+    // be a `T` (`Err`); either way, it's `default` instead. This is kept apart
+    // from `get_or` since reading a parameter needs a running node.
+    fn or_default<T, E>(value: Option<Result<T, E>>, default: T) -> T
+    {
+        value.and_then(|value| value.ok()).unwrap_or(default)
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+
+        #[test]
+        fn missing_and_mistyped_parameters_get_the_default()
+        {
+            assert_eq!(or_default::<i32, ()>(None, 3), 3);
+            assert_eq!(or_default::<i32, ()>(Some(Err(())), 3), 3);
+            assert_eq!(or_default::<i32, ()>(Some(Ok(5)), 3), 5);
+        }
+    }
 }
 
 /// Module containing utils for working with the OccupancyGrid.
+///
+/// # Cell indices
+///
+/// The map data comes in as a flat array, stored row-by-row, so each row is
+/// `width` cells long and there are `height` rows. A `Point` is a `(row, col)`
+/// pair, so `row` is always less than `height` and `col` is always less than
+/// `width`. Use `index_of` and `point_of` to convert between the two, rather
+/// than doing the arithmetic by hand; it's easy to mix up `width` and `height`
+/// and not notice on a square map.
+///
+/// `transform` turns a `Point` into map coordinates (in metres), with the
+/// origin at the centre of the map: `col` increases with `x`, and `row`
+/// increases as `y` decreases. For example, on a map that is 7 cells wide and
+/// 2 cells high, with a resolution of 1 metre:
+///
+/// ```text
+/// index   point    (x, y)
+///   0     (0, 0)   (-3.5,  1.0)
+///   6     (0, 6)   ( 2.5,  1.0)
+///   7     (1, 0)   (-3.5,  0.0)
+///  13     (1, 6)   ( 2.5,  0.0)
+/// ```
 pub mod map_utils
 {
     use ::prelude::*;
     use ::std;
+    use std::f64::{INFINITY, NEG_INFINITY};
+    use num_traits::{self, Float};
 
     /// An alias for the `OccupancyGrid` message type.
     pub type Map = msg::nav_msgs::OccupancyGrid;
@@ -53,164 +531,3165 @@ pub mod map_utils
     /// A set of points.
     pub type Points = HashSet<Point>;
 
-    /// Filters the map using the predicate.
-    ///
-    /// Returns a set of `(usize, usize)`; the row-column indices of the points
-    /// which satisfy the predicate, i.e, for which the predicate is true.
-    ///
-    /// This function is handy because the map comes in as a 1D array, but the
-    /// output of this function lets you think in terms of cell indices.
-    pub fn filter_map<F>(map: &Map, f: F) -> Points
-    where
-        F: Fn(i8) -> bool + Sync
+    /// The ways in which a map can be malformed.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum MapError
     {
-        let len = map.info.height * map.info.width;
+        /// The map has a width or height of zero.
+        BadDimensions,
 
-        (0..len).into_par_iter()
-        .zip(map.data.par_iter())
-        .filter_map(|(index, cell_value)|
-        {
-            if f(*cell_value)
-            {
-                let row = index / map.info.height;
-                let col = index % map.info.width;
+        /// The length of `map.data` is not `width * height`.
+        DataLengthMismatch,
 
-                Some((row as usize, col as usize))
-            }
+        /// The resolution of the map is not a positive number.
+        ZeroResolution,
 
-            else { None }
-        })
-        .collect()
+        /// Two maps that should line up with each other have different sizes,
+        /// resolutions or origins.
+        GeometryMismatch,
     }
 
-    // helper for transforming cell indices into map coordinates.
-    fn tf_helper(map: &Map, p: Point) -> (Num, Num)
+    /// Checks that the map is well-formed, i.e, that it has non-zero
+    /// dimensions, the right amount of data, and a positive resolution.
+    ///
+    /// A truncated bag or a misbehaving publisher can produce a map that breaks
+    /// these assumptions, and most of the functions in this module will quietly
+    /// produce garbage if given such a map.
+    pub fn validate_map(map: &Map) -> Result<(), MapError>
     {
-        let row = p.0 as Num;
-        let col = p.1 as Num;
+        if map.info.width == 0 || map.info.height == 0
+        {
+            return Err(MapError::BadDimensions);
+        }
 
-        let height = map.info.height as Num;
-        let width  = map.info.width  as Num;
+        if map.info.width as usize * map.info.height as usize != map.data.len()
+        {
+            return Err(MapError::DataLengthMismatch);
+        }
 
-        let res = map.info.resolution as Num;
+        if map.info.resolution.is_nan() || map.info.resolution <= 0.0
+        {
+            return Err(MapError::ZeroResolution);
+        }
 
-        (
-            -( ((width /2.0) - col) * res ),
-             ( ((height/2.0) - row) * res ),
-        )
+        Ok(())
     }
 
-    /// Transforms cell indices into map coordinates.
-    pub fn transform<Items: IntoIterator<Item=Point>>(map: &Map, items: Items) -> Vec<(Num, Num)>
+    /// A hash of the map's contents and geometry, for telling whether two maps
+    /// are the same without keeping both around.
+    ///
+    /// The header (and so the stamp) is left out, as is the load time, so a map
+    /// that was republished unchanged has the same fingerprint as before.
+    pub fn fingerprint(map: &Map) -> u64
     {
-        items.into_iter().map(|p| tf_helper(map, p)).collect()
+        use std::hash::Hasher;
+
+        let mut hasher = fnv::FnvHasher::default();
+
+        hash_geometry(&mut hasher, map);
+
+        for &value in map.data.iter()
+        {
+            hasher.write_i8(value);
+        }
+
+        hasher.finish()
     }
 
-    /// Transforms cell indices into map coordinates, in parallel.
-    pub fn par_transform<Items: IntoParallelIterator<Item=Point>>(map: &Map, items: Items) -> Vec<(Num, Num)>
+    // hashes the size, resolution and origin of the map, which between them
+    // decide where each cell is.
+    fn hash_geometry<H: std::hash::Hasher>(hasher: &mut H, map: &Map)
     {
-        items.into_par_iter().map(|p| tf_helper(map, p)).collect()
+        let origin = &map.info.origin.position;
+
+        hasher.write_u32(map.info.width);
+        hasher.write_u32(map.info.height);
+        hasher.write_u32(map.info.resolution.to_bits());
+        hasher.write_u64(origin.x.to_bits());
+        hasher.write_u64(origin.y.to_bits());
     }
 
-    /// Alias for `usize`.
-    pub type GroupNumber = usize;
+    /// A short summary of the map's geometry, for logging, e.g
+    /// `"384x384 @ 0.05 m/cell, origin (-10.00,-10.00,0.00)"`. The origin is
+    /// given in metres, with the yaw in radians.
+    pub fn describe(map: &Map) -> String
+    {
+        let origin = Pose2D::from_ros(&map.info.origin);
 
-    /// A hash table that keeps track of contiguous blocks of cells which satisfied
-    /// a predicate. See `extract_groups`.
-    pub type GroupTable = HashMap<GroupNumber, Points>;
+        format!("{}x{} @ {} m/cell, origin ({:.2},{:.2},{:.2})",
+            map.info.width, map.info.height, map.info.resolution,
+            origin.x, origin.y, origin.theta)
+    }
 
-    /// Extracts groups from the map. A "group" is a contiguous block of cells
-    /// whose value results in the predicate returning true.
+    /// Removes the cells that are occupied in `background` from `map`, by
+    /// marking them as unknown (`-1`). A cell counts as occupied if it has a
+    /// positive value.
     ///
-    /// Recursive flood-fill takes up too much memory and blows the stack real
-    /// quick, so instead we use a "towers-of-babylon" type approach, where
-    /// cell indices that need to be checked are moved from an index into a
-    /// "staging" area.
-    ///
-    /// For each cell in the set of cells which satisfy the predicate:
-    /// 
-    /// * move the cell from the cells set into the "staging" set.
-    /// * that cell is added to the current group.
-    /// * find all the neighbours of that cell that also satisfy the predicate.
-    /// * those cells should also belong to the current group.
-    /// * finally, add the current cell to the "group" index.
-    ///
-    /// Once all the cells in the occupied set have been processed, we will now
-    /// have sets of points that are known to be close together and make up a 
-    /// group.
-    ///
-    /// `kernel_size` is the region for which a cell is considered a "neighbour".
-    pub fn extract_groups<F>(map: &Map, pred: F, kernel_size: usize) -> GroupTable
-    where
-        F: Fn(i8) -> bool + Sync
+    /// Given a map of the empty arena, this gets rid of the walls, so that only
+    /// the obstacles are left. After `gmapping` relocalises, the background can
+    /// have a different origin, size or resolution to the map; if so, it is
+    /// lined up with the map by world coordinates first (see `resample_onto`).
+    pub fn subtract(map: &Map, background: &Map) -> Result<Map, MapError>
     {
-        // first, get the whole set of cells which satisfy the predicate
-        let mut cells = filter_map(map, pred);
+        check_data_length(map)?;
+        check_data_length(background)?;
 
-        // now, initialise some stuff
-        let mut current_group = 0;
-        let mut staging = Vec::new();
-        let mut group_table = GroupTable::default();
+        let resampled;
 
-        // we play "towers of babylon".
-        while cells.len() != 0
+        let background = if same_geometry(map, background) { background } else
         {
-            // take the next one. Unfortunately, it seems there is no method for
-            // this for HashSet.
-            let mut iterator = cells.into_iter();
-            let index = iterator.next().unwrap();
-            cells = iterator.collect();
+            resampled = resample_onto(background, map);
+            &resampled
+        };
 
-            staging.push(index);
-            while let Some(current_index) = staging.pop()
-            {
-                // move all of the neighbours
-                process_neighbours(current_index, &mut staging, &mut cells, kernel_size);
-                group_table.entry(current_group).or_insert(Points::default()).insert(current_index);
-            }
+        let mut result = map.clone();
 
-            current_group += 1;
-        }
+        result.data.par_iter_mut()
+        .zip(background.data.par_iter())
+        .for_each(|(cell, background)| if *background > 0 { *cell = -1; });
 
-        return group_table;
+        Ok(result)
     }
 
-    // Helper for extract_groups
-    fn process_neighbours(
-        p: Point,
-        staging: &mut Vec<Point>,
-        cells: &mut Points,
-        kernel_size: usize,
-    )
+    /// The cells of `current` whose value is different in `previous`, e.g to
+    /// only look again at the parts of the map that `gmapping` has updated.
+    ///
+    /// Like `subtract`, the two maps are lined up by world coordinates, so they
+    /// don't have to have the same geometry. Cells of `current` that `previous`
+    /// doesn't cover count as unknown in `previous`.
+    pub fn changed_cells(previous: &Map, current: &Map) -> Result<Points, MapError>
     {
-        let mut to_check = neighbours(p, kernel_size);
+        check_data_length(previous)?;
+        check_data_length(current)?;
 
-        // add points that are "to_check" but still in occupied
-        cells.intersection(&to_check).for_each(|p| staging.push(*p));
+        let resampled;
+
+        let previous = if same_geometry(current, previous) { previous } else
+        {
+            resampled = resample_onto(previous, current);
+            &resampled
+        };
 
-        // remove those cells from list.
-        to_check.iter().for_each(|p| { cells.remove(p); });
+        Ok(current.data.par_iter()
+        .zip(previous.data.par_iter())
+        .enumerate()
+        .filter(|&(_, (now, before))| now != before)
+        .map(|(i, _)| point_of(current, i))
+        .collect())
     }
 
-    /// Returns the set of neighbours of a cell.
-    pub fn neighbours(
-        p: Point,
-        kernel_size: usize,
-    ) -> Points
+    /// Resamples `source` onto the grid of `target`, by world coordinates
+    /// (`Convention::RosMapFrame`): each cell of the result takes the value of
+    /// whichever cell of `source` its centre falls in, or unknown (`-1`) if it
+    /// falls outside of `source` altogether. Like `Convention::RosMapFrame`,
+    /// this ignores the rotation of the origins.
+    ///
+    /// The result has the header and geometry of `target`, and the data of
+    /// `source`.
+    pub fn resample_onto(source: &Map, target: &Map) -> Map
     {
-        let mut neighbours: Points = Points::default();
+        let res = Resolution::of(target);
 
-        for i in 0..kernel_size
+        let mut result = target.clone();
+
+        result.data = (0..target.data.len()).into_par_iter()
+        .map(|i|
         {
-            for j in 0..kernel_size
+            let xy = tf_helper(target, point_of(target, i), res, Convention::RosMapFrame);
+
+            match world_cell_at(source, xy)
             {
-                neighbours.insert((p.0.saturating_add(i), p.1.saturating_add(j)));
-                neighbours.insert((p.0.saturating_add(i), p.1.saturating_sub(j)));
-                neighbours.insert((p.0.saturating_sub(i), p.1.saturating_add(j)));
-                neighbours.insert((p.0.saturating_sub(i), p.1.saturating_sub(j)));
+                Some(p) => source.data[index_of(source, p)],
+                None => -1,
             }
+        })
+        .collect();
+
+        result
+    }
+
+    // the cell that the point (in world coordinates) falls in, if any; the
+    // inverse of `Convention::RosMapFrame`.
+    fn world_cell_at(map: &Map, xy: (Num, Num)) -> Option<Point>
+    {
+        let res = Resolution::of(map);
+        let origin = &map.info.origin.position;
+
+        let col = ((xy.0 - origin.x as Num) / res.x).floor();
+        let row = ((xy.1 - origin.y as Num) / res.y).floor();
+
+        if !(col >= 0.0 && row >= 0.0) || col >= map.info.width as Num || row >= map.info.height as Num
+        {
+            return None;
         }
 
-        return neighbours;
+        Some((row as usize, col as usize))
+    }
+
+    // checks that the map has as much data as its dimensions say.
+    fn check_data_length(map: &Map) -> Result<(), MapError>
+    {
+        if map.info.width as usize * map.info.height as usize != map.data.len()
+        {
+            return Err(MapError::DataLengthMismatch);
+        }
+
+        Ok(())
+    }
+
+    // checks whether the cells of two maps line up with each other.
+    fn same_geometry(a: &Map, b: &Map) -> bool
+    {
+        let a = &a.info;
+        let b = &b.info;
+
+        a.width  == b.width  &&
+        a.height == b.height &&
+        a.resolution == b.resolution &&
+        a.origin.position.x == b.origin.position.x &&
+        a.origin.position.y == b.origin.position.y &&
+        a.origin.position.z == b.origin.position.z &&
+        a.origin.orientation.x == b.origin.orientation.x &&
+        a.origin.orientation.y == b.origin.orientation.y &&
+        a.origin.orientation.z == b.origin.orientation.z &&
+        a.origin.orientation.w == b.origin.orientation.w
+    }
+
+    /// Converts the row-column indices of a cell into the index of that cell in
+    /// `map.data`.
+    ///
+    /// The map data is stored row-by-row, so each row is `width` cells long.
+    pub fn index_of(map: &Map, p: Point) -> usize
+    {
+        p.0 * map.info.width as usize + p.1
+    }
+
+    /// Converts an index into `map.data` into the row-column indices of that
+    /// cell. This is the inverse of `index_of`.
+    pub fn point_of(map: &Map, idx: usize) -> Point
+    {
+        let width = map.info.width as usize;
+
+        (idx / width, idx % width)
+    }
+
+    /// Walks along the straight line of cells from `from` to `to` (both
+    /// included), and returns the first one that satisfies the predicate, e.g
+    /// the first occupied cell that a laser beam would hit. Returns `None` if
+    /// the line is clear.
+    ///
+    /// The line is traced with Bresenham's algorithm, so it visits exactly one
+    /// cell per step along its longer axis. The trace stops at the edge of the
+    /// map.
+    pub fn raytrace<F>(map: &Map, from: Point, to: Point, pred: F) -> Option<Point>
+    where
+        F: Fn(i8) -> bool
+    {
+        let height = map.info.height as isize;
+        let width  = map.info.width  as isize;
+
+        let (mut row, mut col) = (from.0 as isize, from.1 as isize);
+        let (end_row, end_col) = (to.0 as isize, to.1 as isize);
+
+        let d_row = (end_row - row).abs();
+        let d_col = (end_col - col).abs();
+        let step_row = if end_row > row { 1 } else { -1 };
+        let step_col = if end_col > col { 1 } else { -1 };
+
+        let mut error = d_col - d_row;
+
+        loop
+        {
+            if row < 0 || col < 0 || row >= height || col >= width { return None; }
+
+            let p = (row as usize, col as usize);
+
+            if map.data.get(index_of(map, p)).map_or(false, |value| pred(*value))
+            {
+                return Some(p);
+            }
+
+            if row == end_row && col == end_col { return None; }
+
+            let e2 = 2 * error;
+
+            if e2 > -d_row
+            {
+                error -= d_row;
+                col += step_col;
+            }
+
+            if e2 < d_col
+            {
+                error += d_col;
+                row += step_row;
+            }
+        }
+    }
+
+    /// Builds a map from scratch, e.g for benchmarking, or for trying things
+    /// out without a robot.
+    ///
+    /// Every cell starts out free (`0`), the origin is at `(0, 0)`, and the
+    /// resolution is 5cm, which is what `gmapping` uses by default. Shapes that
+    /// hang off the edge of the map are clipped.
+    #[derive(Debug, Clone)]
+    pub struct MapBuilder
+    {
+        map: Map,
+    }
+
+    impl MapBuilder
+    {
+        /// Starts a map that is `width` cells wide and `height` cells high.
+        pub fn new(width: u32, height: u32) -> Self
+        {
+            let mut map = Map::default();
+
+            map.info.width = width;
+            map.info.height = height;
+            map.info.resolution = 0.05;
+            map.info.origin.orientation.w = 1.0;
+            map.data = vec![0; width as usize * height as usize];
+
+            MapBuilder { map }
+        }
+
+        /// Sets the size of each cell, in metres.
+        pub fn resolution(mut self, resolution: f32) -> Self
+        {
+            self.map.info.resolution = resolution;
+            self
+        }
+
+        /// Sets every cell to `value`.
+        pub fn fill(mut self, value: i8) -> Self
+        {
+            for cell in self.map.data.iter_mut() { *cell = value; }
+            self
+        }
+
+        /// Sets a single cell to `value`.
+        pub fn cell(mut self, p: Point, value: i8) -> Self
+        {
+            self.set(p, value);
+            self
+        }
+
+        /// Sets every cell in the rectangle between the two corners (inclusive)
+        /// to `value`.
+        pub fn rect(mut self, top_left: Point, bottom_right: Point, value: i8) -> Self
+        {
+            for row in top_left.0..bottom_right.0 + 1
+            {
+                for col in top_left.1..bottom_right.1 + 1
+                {
+                    self.set((row, col), value);
+                }
+            }
+
+            self
+        }
+
+        /// Sets the cells that lie on the outline of a circle to `value`. This
+        /// is what an obstacle looks like to the laser scanner, since it can't
+        /// see inside.
+        pub fn ring(mut self, centre: Point, radius: Num, value: i8) -> Self
+        {
+            let reach = radius.ceil() as usize + 1;
+
+            for row in centre.0.saturating_sub(reach)..centre.0 + reach + 1
+            {
+                for col in centre.1.saturating_sub(reach)..centre.1 + reach + 1
+                {
+                    let dr = row as Num - centre.0 as Num;
+                    let dc = col as Num - centre.1 as Num;
+
+                    if (dr.hypot(dc) - radius).abs() <= 0.5
+                    {
+                        self.set((row, col), value);
+                    }
+                }
+            }
+
+            self
+        }
+
+        /// Sets each cell to `value` with probability `density`, to imitate
+        /// sensor noise. The same `seed` always gives the same cells.
+        pub fn noise(mut self, density: Num, value: i8, seed: u64) -> Self
+        {
+            // xorshift; doesn't need to be good, just repeatable.
+            let mut state = seed.max(1);
+
+            for cell in self.map.data.iter_mut()
+            {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                if (state % 1_000_000) as Num / 1_000_000.0 < density
+                {
+                    *cell = value;
+                }
+            }
+
+            self
+        }
+
+        /// Finishes the map.
+        pub fn build(self) -> Map
+        {
+            self.map
+        }
+
+        // sets a single cell, ignoring cells that are off the map.
+        fn set(&mut self, p: Point, value: i8)
+        {
+            if p.0 < self.map.info.height as usize && p.1 < self.map.info.width as usize
+            {
+                let idx = index_of(&self.map, p);
+                self.map.data[idx] = value;
+            }
+        }
+    }
+
+    /// Normalises a cell value that was published as an unsigned byte.
+    ///
+    /// Occupancy values are supposed to be signed, with `-1` meaning unknown,
+    /// but some bridges treat them as unsigned, so that unknown comes through
+    /// as `255` and other out-of-range values can show up. Interpreting the
+    /// value as unsigned, `255` becomes `-1` (unknown) and anything else above
+    /// `100` is clamped to `100` (occupied).
+    pub fn as_signed(v: i8) -> i8
+    {
+        match v as u8
+        {
+            255 => -1,
+            u if u > 100 => 100,
+            u => u as i8,
+        }
+    }
+
+    /// Wraps a predicate so that it sees cell values normalised with
+    /// `as_signed`. Use this with `filter_map`, `extract_groups` etc when the
+    /// map data was published as unsigned.
+    pub fn unsigned<F>(f: F) -> impl Fn(i8) -> bool + Sync
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        move |v| f(as_signed(v))
+    }
+
+    /// How the cell values in the map were published. See `filter_map_encoded`
+    /// and `extract_groups_encoded`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Encoding
+    {
+        /// As signed bytes, which is what `OccupancyGrid` says they should be.
+        Signed,
+
+        /// As unsigned bytes; see `as_signed`.
+        Unsigned,
+    }
+
+    impl Encoding
+    {
+        /// The cell value, as it would have been if it was published signed.
+        pub fn decode(self, v: i8) -> i8
+        {
+            match self
+            {
+                Encoding::Signed => v,
+                Encoding::Unsigned => as_signed(v),
+            }
+        }
+    }
+
+    impl Default for Encoding
+    {
+        fn default() -> Self
+        {
+            Encoding::Signed
+        }
+    }
+
+    /// A predicate for cells that are occupied, i.e whose value is greater
+    /// than `threshold`.
+    ///
+    /// Cell values are the probability (in percent) that the cell is occupied,
+    /// or `-1` if nothing is known about the cell. So with a threshold of `50`,
+    /// this gives the cells that are more likely to be occupied than not.
+    pub fn occupied(threshold: i8) -> impl Fn(i8) -> bool + Sync + Copy
+    {
+        move |v| v > threshold
+    }
+
+    /// Like `occupied`, but never matches one of the given unknown values,
+    /// even if it's greater than `threshold`.
+    pub fn occupied_in(threshold: i8, unknowns: UnknownValues) -> impl Fn(i8) -> bool + Sync + Copy
+    {
+        move |v| !unknowns.contains(v) && v > threshold
+    }
+
+    /// A predicate for cells that are free, i.e whose value is known and is
+    /// no more than `threshold`. This is the opposite of `occupied`, apart
+    /// from the unknown cells, which are neither. Every negative value is
+    /// unknown; see `free_in` for other unknown values.
+    pub fn free(threshold: i8) -> impl Fn(i8) -> bool + Sync + Copy
+    {
+        free_in(threshold, UnknownValues::default())
+    }
+
+    /// Like `free`, but with the given unknown values. Any other value is
+    /// taken to be known, so it's free if it's no more than `threshold`.
+    pub fn free_in(threshold: i8, unknowns: UnknownValues) -> impl Fn(i8) -> bool + Sync + Copy
+    {
+        move |v| !unknowns.contains(v) && v <= threshold
+    }
+
+    /// A predicate for cells that nothing is known about. These should have a
+    /// value of `-1`, but any negative value is treated as unknown.
+    pub fn unknown() -> impl Fn(i8) -> bool + Sync + Copy
+    {
+        unknown_in(UnknownValues::negative())
+    }
+
+    /// The cell values that mean "unknown".
+    ///
+    /// This should only be `-1`, but some SLAM backends use other values for
+    /// different kinds of unknown (e.g `-2` for cells that are out of range).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct UnknownValues
+    {
+        // one bit for each possible value, indexed by the value as a `u8`.
+        mask: [u64; 4],
+    }
+
+    impl UnknownValues
+    {
+        /// Every negative value is unknown. This is the default.
+        pub fn negative() -> Self
+        {
+            UnknownValues::only(&(-128..0).collect::<Vec<i8>>())
+        }
+
+        /// Only the given values are unknown.
+        pub fn only(values: &[i8]) -> Self
+        {
+            let mut mask = [0; 4];
+
+            for &v in values
+            {
+                let bit = v as u8 as usize;
+                mask[bit / 64] |= 1 << (bit % 64);
+            }
+
+            UnknownValues { mask }
+        }
+
+        /// Whether the value means "unknown".
+        pub fn contains(&self, v: i8) -> bool
+        {
+            let bit = v as u8 as usize;
+
+            self.mask[bit / 64] & (1 << (bit % 64)) != 0
+        }
+    }
+
+    impl Default for UnknownValues
+    {
+        fn default() -> Self
+        {
+            UnknownValues::negative()
+        }
+    }
+
+    /// A predicate for cells whose value is one of the given unknown values.
+    pub fn unknown_in(values: UnknownValues) -> impl Fn(i8) -> bool + Sync + Copy
+    {
+        move |v| values.contains(v)
+    }
+
+    /// Wraps a predicate so that it never matches an unknown cell, whatever
+    /// its value. Use this with `filter_map`, `extract_groups` etc, along with
+    /// the other predicates, when the map uses unknown values that they would
+    /// otherwise take for real occupancies.
+    pub fn known<F>(values: UnknownValues, f: F) -> impl Fn(i8) -> bool + Sync
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        move |v| !values.contains(v) && f(v)
+    }
+
+    /// Filters the map using the predicate.
+    ///
+    /// Returns a set of `(usize, usize)`; the row-column indices of the points
+    /// which satisfy the predicate, i.e, for which the predicate is true.
+    ///
+    /// This function is handy because the map comes in as a 1D array, but the
+    /// output of this function lets you think in terms of cell indices.
+    pub fn filter_map<F>(map: &Map, f: F) -> Points
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        // do the multiplication in `usize`, so that it can't wrap around.
+        let len = map.info.width as usize * map.info.height as usize;
+
+        if len != map.data.len()
+        {
+            println!("filter_map: map is {}x{} but contains {} cells, ignoring it",
+                map.info.width, map.info.height, map.data.len());
+
+            return Points::default();
+        }
+
+        (0..len).into_par_iter()
+        .zip(map.data.par_iter())
+        .filter_map(|(index, cell_value)|
+        {
+            if f(*cell_value)
+            {
+                Some(point_of(map, index))
+            }
+
+            else { None }
+        })
+        .collect()
+    }
+
+    /// Like `filter_map`, but the predicate sees the cell values decoded with
+    /// `encoding`, so the same predicates work whichever way the map was
+    /// published.
+    pub fn filter_map_encoded<F>(map: &Map, f: F, encoding: Encoding) -> Points
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        filter_map(map, move |v| f(encoding.decode(v)))
+    }
+
+    /// Like `filter_map`, but checks the map with `validate_map` first, and
+    /// returns the problem instead of quietly giving back an empty set.
+    pub fn try_filter_map<F>(map: &Map, f: F) -> Result<Points, MapError>
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        validate_map(map)?;
+
+        Ok(filter_map(map, f))
+    }
+
+    /// Like `filter_map`, but selects the cells whose value is between `lo`
+    /// and `hi` (inclusive), e.g `filter_map_range(map, 1, 99)` for the cells
+    /// that are neither known to be free nor known to be occupied. Unknown
+    /// cells (`-1`) are only included if `lo` is negative.
+    pub fn filter_map_range(map: &Map, lo: i8, hi: i8) -> Points
+    {
+        filter_map(map, move |v| v >= lo && v <= hi)
+    }
+
+    /// Like `filter_map`, but only looks at the cells in the region of
+    /// interest `roi`, given as the top-left and bottom-right corners
+    /// (inclusive), like `MapBuilder::rect`. Cells outside of it are left out
+    /// even if they satisfy the predicate. The points are still indices into
+    /// the whole map.
+    ///
+    /// The part of `roi` that hangs off the edge of the map is ignored.
+    pub fn filter_map_roi<F>(map: &Map, f: F, roi: (Point, Point)) -> Points
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        let width  = map.info.width  as usize;
+        let height = map.info.height as usize;
+
+        if width * height != map.data.len() || width == 0 || height == 0
+        {
+            return Points::default();
+        }
+
+        let ((top, left), (bottom, right)) = roi;
+        let bottom = bottom.min(height - 1);
+        let right  = right.min(width - 1);
+
+        if top > bottom || left > right
+        {
+            return Points::default();
+        }
+
+        let f = &f;
+
+        (top..bottom + 1).into_par_iter()
+        .flat_map(|row| (left..right + 1).into_par_iter().filter_map(move |col|
+        {
+            if f(map.data[index_of(map, (row, col))]) { Some((row, col)) } else { None }
+        }))
+        .collect()
+    }
+
+    /// Like `filter_map`, but the predicate also gets to see the values of the
+    /// eight cells surrounding each cell, which is handy for rejecting isolated
+    /// speckle.
+    ///
+    /// The neighbour values are given in row-major order, starting from the
+    /// top-left neighbour and skipping the cell itself. Neighbours that fall off
+    /// the edge of the map are given as `-1` (unknown).
+    pub fn filter_map_context<F>(map: &Map, f: F) -> Points
+    where
+        F: Fn(i8, &[i8]) -> bool + Sync
+    {
+        let width  = map.info.width  as isize;
+        let height = map.info.height as isize;
+
+        // `point_of` divides by the width, and there's nothing to find in an
+        // empty map anyway, whatever its data says.
+        if width == 0 || height == 0
+        {
+            return Points::default();
+        }
+
+        let value_at = |row: isize, col: isize|
+        {
+            if row < 0 || col < 0 || row >= height || col >= width { return -1; }
+
+            map.data.get(index_of(map, (row as usize, col as usize))).cloned().unwrap_or(-1)
+        };
+
+        map.data.par_iter()
+        .enumerate()
+        .filter_map(|(index, cell_value)|
+        {
+            let (row, col) = point_of(map, index);
+            let (row, col) = (row as isize, col as isize);
+
+            let mut context = [-1; 8];
+            let mut n = 0;
+
+            for i in -1..2
+            {
+                for j in -1..2
+                {
+                    if i == 0 && j == 0 { continue; }
+
+                    context[n] = value_at(row + i, col + j);
+                    n += 1;
+                }
+            }
+
+            if f(*cell_value, &context)
+            {
+                Some((row as usize, col as usize))
+            }
+
+            else { None }
+        })
+        .collect()
+    }
+
+    /// Converts the map into a greyscale image, e.g for viewing it or for
+    /// further processing with an image library. Only available with the
+    /// `image` feature.
+    ///
+    /// Follows the same convention as `map_server`: free cells are white,
+    /// occupied cells are black, and unknown cells are grey (127). The first
+    /// row of the map is the top row of the image.
+    #[cfg(feature = "image")]
+    pub fn to_gray_image(map: &Map) -> image::GrayImage
+    {
+        image::GrayImage::from_fn(map.info.width, map.info.height, |x, y|
+        {
+            let value = map.data.get(index_of(map, (y as usize, x as usize))).cloned().unwrap_or(-1);
+
+            let level = if value < 0 { 127 } else { 255 - (value.min(100) as u32 * 255 / 100) as u8 };
+
+            image::Luma { data: [level] }
+        })
+    }
+
+    /// The size of a cell, in metres, along each axis of the map.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Resolution
+    {
+        /// The width of a cell, i.e along a row.
+        pub x: Num,
+
+        /// The height of a cell, i.e along a column.
+        pub y: Num,
+    }
+
+    impl Resolution
+    {
+        /// The resolution given in the map's metadata, which is the same along
+        /// both axes.
+        pub fn of(map: &Map) -> Self
+        {
+            let res = map.info.resolution as Num;
+
+            Resolution { x: res, y: res }
+        }
+    }
+
+    /// Which way round the coordinates given by `transform` (and friends) are.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Convention
+    {
+        /// The origin is the centre of the map, which is where `gmapping` puts
+        /// the robot when it starts. `x` increases along a row and `y`
+        /// increases towards the *first* row, i.e the map is read like an
+        /// image. A cell's coordinates are those of its top-left corner.
+        ///
+        /// E.g in a 10x10 map with 0.5m cells, cell `(2, 7)` is at `(1.0, 1.5)`.
+        ///
+        /// This is what the nodes in this workspace have always used, and is
+        /// the default.
+        RobotCentric,
+
+        /// The map frame as `map_server` and REP-103 have it: the first cell
+        /// of the map is at `info.origin`, `x` increases along a row and `y`
+        /// increases towards the *last* row. A cell's coordinates are those of
+        /// its centre. The rotation of `info.origin` is ignored, since none of
+        /// the maps we get are rotated.
+        ///
+        /// E.g in a 10x10 map with 0.5m cells and the origin at `(-2.5, -2.5)`,
+        /// cell `(2, 7)` is at `(1.25, -1.25)`.
+        RosMapFrame,
+    }
+
+    impl Default for Convention
+    {
+        fn default() -> Self
+        {
+            Convention::RobotCentric
+        }
+    }
+
+    // helper for transforming cell indices into map coordinates.
+    fn tf_helper(map: &Map, p: Point, res: Resolution, convention: Convention) -> (Num, Num)
+    {
+        let row = p.0 as Num;
+        let col = p.1 as Num;
+
+        match convention
+        {
+            Convention::RobotCentric =>
+            {
+                let height = map.info.height as Num;
+                let width  = map.info.width  as Num;
+
+                (
+                    -( ((width /2.0) - col) * res.x ),
+                     ( ((height/2.0) - row) * res.y ),
+                )
+            },
+
+            Convention::RosMapFrame =>
+            {
+                let origin = &map.info.origin.position;
+
+                (
+                    origin.x as Num + (col + 0.5) * res.x,
+                    origin.y as Num + (row + 0.5) * res.y,
+                )
+            },
+        }
+    }
+
+    /// Transforms cell indices into map coordinates, using the default
+    /// `Convention`.
+    ///
+    /// The coordinates can be either `f32` or `f64` (usually `Num`), whichever
+    /// the caller is working in. They're always worked out in `Num` first, so
+    /// an `f32` result is just as accurate as `f32` allows.
+    pub fn transform<T: Float, Items: IntoIterator<Item=Point>>(map: &Map, items: Items) -> Vec<(T, T)>
+    {
+        let res = Resolution::of(map);
+
+        items.into_iter()
+        .map(|p|
+        {
+            let (x, y) = tf_helper(map, p, res, Convention::RobotCentric);
+
+            (num_traits::cast(x).unwrap(), num_traits::cast(y).unwrap())
+        })
+        .collect()
+    }
+
+    /// Like `transform`, but with the given coordinate convention instead of
+    /// the default one.
+    pub fn transform_in<Items: IntoIterator<Item=Point>>(map: &Map, items: Items, convention: Convention) -> Vec<(Num, Num)>
+    {
+        let res = Resolution::of(map);
+
+        items.into_iter().map(|p| tf_helper(map, p, res, convention)).collect()
+    }
+
+    /// Like `transform`, but writes the coordinates straight into a flat buffer
+    /// of `x, y, z` triples (with `z = 0`), which is the layout that
+    /// `sensor_msgs/PointCloud2` wants. This saves building a `Vec` of tuples
+    /// only to pack it up again.
+    pub fn transform_packed<Items: IntoIterator<Item=Point>>(map: &Map, items: Items) -> Vec<f32>
+    {
+        let res = Resolution::of(map);
+        let items = items.into_iter();
+
+        let mut packed = Vec::with_capacity(3 * items.size_hint().0);
+
+        for p in items
+        {
+            let (x, y) = tf_helper(map, p, res, Convention::RobotCentric);
+
+            packed.push(x as f32);
+            packed.push(y as f32);
+            packed.push(0.0);
+        }
+
+        packed
+    }
+
+    /// Like `transform`, but checks the map with `validate_map` first. A map
+    /// with a bad resolution would otherwise give coordinates that are all
+    /// zero, or infinite, or NaN.
+    pub fn try_transform<Items: IntoIterator<Item=Point>>(map: &Map, items: Items) -> Result<Vec<(Num, Num)>, MapError>
+    {
+        validate_map(map)?;
+
+        Ok(transform(map, items))
+    }
+
+    /// Transforms cell indices into map coordinates, in parallel.
+    pub fn par_transform<Items: IntoParallelIterator<Item=Point>>(map: &Map, items: Items) -> Vec<(Num, Num)>
+    {
+        par_transform_with(map, items, Resolution::of(map))
+    }
+
+    /// Like `transform`, but the coordinates are given relative to `reference`
+    /// (e.g the goal, or another robot) rather than the centre of the map.
+    pub fn transform_relative_to<Items: IntoIterator<Item=Point>>(map: &Map, items: Items, reference: (Num, Num)) -> Vec<(Num, Num)>
+    {
+        let res = Resolution::of(map);
+
+        items.into_iter()
+        .map(|p|
+        {
+            let (x, y) = tf_helper(map, p, res, Convention::RobotCentric);
+
+            (x - reference.0, y - reference.1)
+        })
+        .collect()
+    }
+
+    /// Like `transform`, but uses the given resolution instead of the one in
+    /// the map's metadata. Use this for maps whose cells aren't square.
+    pub fn transform_with<Items: IntoIterator<Item=Point>>(map: &Map, items: Items, res: Resolution) -> Vec<(Num, Num)>
+    {
+        items.into_iter().map(|p| tf_helper(map, p, res, Convention::RobotCentric)).collect()
+    }
+
+    /// The corners of the area that the map covers, in the world (i.e
+    /// `Convention::RosMapFrame`), as `((min x, min y), (max x, max y))`.
+    ///
+    /// These are the outer edges of the outermost cells, so a map of 10x10
+    /// cells of 0.5m with its origin at `(-2.0, 1.0)` covers `(-2.0, 1.0)` to
+    /// `(3.0, 6.0)`. Like `Convention::RosMapFrame`, this ignores the rotation
+    /// of the origin.
+    pub fn world_bounds(map: &Map) -> ((Num, Num), (Num, Num))
+    {
+        let res = Resolution::of(map);
+        let origin = &map.info.origin.position;
+
+        let min = (origin.x as Num, origin.y as Num);
+
+        (
+            min,
+            (
+                min.0 + map.info.width  as Num * res.x,
+                min.1 + map.info.height as Num * res.y,
+            ),
+        )
+    }
+
+    /// How far the coordinates (as given by `transform`) of a fixed point in the
+    /// world move between two versions of a map, e.g when `gmapping` grows the
+    /// map or moves its origin.
+    ///
+    /// The coordinates are measured from the centre of the map, so they shift
+    /// when the origin moves, and also when the map gets wider or taller. Both
+    /// maps are assumed to have the resolution of `current`.
+    pub fn coordinate_shift(previous: &msg::nav_msgs::MapMetaData, current: &msg::nav_msgs::MapMetaData) -> (Num, Num)
+    {
+        let res = current.resolution as Num;
+
+        let dx = (current.origin.position.x - previous.origin.position.x) as Num;
+        let dy = (current.origin.position.y - previous.origin.position.y) as Num;
+
+        let dw = current.width  as Num - previous.width  as Num;
+        let dh = current.height as Num - previous.height as Num;
+
+        // rows count downwards in `transform`, hence the flip in `y`.
+        (
+            -dx - dw * res / 2.0,
+             dy + dh * res / 2.0,
+        )
+    }
+
+    /// The cell that the map coordinates `(x, y)` fall in, i.e the inverse of
+    /// `transform`. Returns `None` if the coordinates are off the edge of the
+    /// map.
+    pub fn cell_at(map: &Map, xy: (Num, Num)) -> Option<Point>
+    {
+        let res = Resolution::of(map);
+
+        let height = map.info.height as Num;
+        let width  = map.info.width  as Num;
+
+        // `transform` gives the top-left corner of each cell, so the cell
+        // reaches to the right of and below its coordinates.
+        let row = ((height / 2.0) - xy.1 / res.y).floor();
+        let col = ((width  / 2.0) + xy.0 / res.x).floor();
+
+        if row < 0.0 || col < 0.0 || row >= height || col >= width || row.is_nan() || col.is_nan()
+        {
+            return None;
+        }
+
+        Some((row as usize, col as usize))
+    }
+
+    /// Like `par_transform`, but uses the given resolution instead of the one
+    /// in the map's metadata. Use this for maps whose cells aren't square.
+    pub fn par_transform_with<Items: IntoParallelIterator<Item=Point>>(map: &Map, items: Items, res: Resolution) -> Vec<(Num, Num)>
+    {
+        items.into_par_iter().map(|p| tf_helper(map, p, res, Convention::RobotCentric)).collect()
+    }
+
+    /// How many maps a `TransformCache` usually remembers.
+    pub const TRANSFORM_CACHE_SIZE: usize = 4;
+
+    /// Remembers the coordinates of every cell of the last few maps that it
+    /// was asked about, so that transforming the cells of another map with the
+    /// same geometry is just a lookup, e.g for each map that `gmapping`
+    /// publishes. Keep one for as long as the maps keep coming, and share it
+    /// between the threads working on them.
+    ///
+    /// Maps are told apart by the part of their `fingerprint` that doesn't
+    /// depend on the cells, i.e their size, resolution and origin, so a map
+    /// whose cells have changed (e.g because more of the arena has been seen)
+    /// can still use the coordinates that were worked out for the last one.
+    #[derive(Debug)]
+    pub struct TransformCache
+    {
+        capacity: usize,
+
+        // the most recently used map is at the back.
+        entries: std::sync::Mutex<std::collections::VecDeque<(u64, std::sync::Arc<Vec<(Num, Num)>>)>>,
+
+        computed: std::sync::atomic::AtomicUsize,
+    }
+
+    impl TransformCache
+    {
+        /// A cache that remembers up to `capacity` maps.
+        pub fn new(capacity: usize) -> Self
+        {
+            TransformCache
+            {
+                capacity,
+                entries: std::sync::Mutex::new(std::collections::VecDeque::new()),
+                computed: std::sync::atomic::AtomicUsize::new(0),
+            }
+        }
+
+        /// How many times the coordinates of a whole map have had to be worked
+        /// out, i.e how many lookups missed.
+        pub fn computed(&self) -> usize
+        {
+            self.computed.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        /// Like `transform`, but looks the coordinates up, working them out for
+        /// the whole map first if this cache hasn't seen its geometry before.
+        pub fn transform<Items: IntoIterator<Item=Point>>(&self, map: &Map, items: Items) -> Vec<(Num, Num)>
+        {
+            let table = self.table(map);
+            let res = Resolution::of(map);
+            let width = map.info.width as usize;
+
+            items.into_iter()
+            .map(|p|
+            {
+                // points off the edge of the map aren't in the table.
+                if p.1 < width
+                {
+                    if let Some(&xy) = table.get(index_of(map, p)) { return xy; }
+                }
+
+                tf_helper(map, p, res, Convention::RobotCentric)
+            })
+            .collect()
+        }
+
+        // the coordinates of every cell of the map, in index order.
+        fn table(&self, map: &Map) -> std::sync::Arc<Vec<(Num, Num)>>
+        {
+            use std::hash::Hasher;
+
+            let mut hasher = fnv::FnvHasher::default();
+            hash_geometry(&mut hasher, map);
+            let key = hasher.finish();
+
+            // the table is worked out while the lock is held, so that the
+            // threads that want it at the same time wait for it rather than
+            // each working it out again. It's worked out on this thread alone,
+            // since if it were handed to rayon, this thread might pick up
+            // another job in the meantime that wants the lock too.
+            let mut entries = self.entries.lock().unwrap();
+
+            if let Some(i) = entries.iter().position(|e| e.0 == key)
+            {
+                let entry = entries.remove(i).unwrap();
+                let table = entry.1.clone();
+                entries.push_back(entry);
+
+                return table;
+            }
+
+            self.computed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+            let len = map.info.width as usize * map.info.height as usize;
+            let table = std::sync::Arc::new(transform(map, (0..len).map(|i| point_of(map, i))));
+
+            if self.capacity > 0
+            {
+                if entries.len() >= self.capacity { entries.pop_front(); }
+
+                entries.push_back((key, table.clone()));
+            }
+
+            table
+        }
+    }
+
+    /// Alias for `usize`.
+    pub type GroupNumber = usize;
+
+    /// A hash table that keeps track of contiguous blocks of cells which satisfied
+    /// a predicate. See `extract_groups`.
+    pub type GroupTable = HashMap<GroupNumber, Points>;
+
+    /// Extracts groups from the map. A "group" is a contiguous block of cells
+    /// whose value results in the predicate returning true.
+    ///
+    /// Recursive flood-fill takes up too much memory and blows the stack real
+    /// quick, so instead we use a "towers-of-babylon" type approach, where
+    /// cell indices that need to be checked are moved from an index into a
+    /// "staging" area.
+    ///
+    /// For each cell in the set of cells which satisfy the predicate:
+    /// 
+    /// * move the cell from the cells set into the "staging" set.
+    /// * that cell is added to the current group.
+    /// * find all the neighbours of that cell that also satisfy the predicate.
+    /// * those cells should also belong to the current group.
+    /// * finally, add the current cell to the "group" index.
+    ///
+    /// Once all the cells in the occupied set have been processed, we will now
+    /// have sets of points that are known to be close together and make up a 
+    /// group.
+    ///
+    /// Groups are numbered in the order that their first cell (in row-major
+    /// order) appears in the map, so the same map always gives the same groups
+    /// with the same numbers.
+    ///
+    /// `kernel_size` is the region for which a cell is considered a "neighbour".
+    /// Diagonal cells count as neighbours too, so two obstacles that only touch
+    /// at a corner end up in the same group; use `extract_groups_with` with
+    /// `Connectivity::Four` to keep them apart.
+    pub fn extract_groups<F>(map: &Map, pred: F, kernel_size: usize) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        // first, get the whole set of cells which satisfy the predicate
+        let cells = filter_map(map, pred);
+
+        group_cells(cells, kernel_size, Connectivity::Eight)
+    }
+
+    /// Like `extract_groups`, but the predicate sees the cell values decoded
+    /// with `encoding`; see `filter_map_encoded`.
+    pub fn extract_groups_encoded<F>(map: &Map, pred: F, kernel_size: usize, encoding: Encoding) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        group_cells(filter_map_encoded(map, pred, encoding), kernel_size, Connectivity::Eight)
+    }
+
+    /// Like `extract_groups`, but checks the map with `validate_map` first. A
+    /// map with no cells (e.g while `gmapping` is starting up) gives
+    /// `MapError::BadDimensions` rather than an empty table, so that it can be
+    /// told apart from a map with nothing in it.
+    pub fn try_extract_groups<F>(map: &Map, pred: F, kernel_size: usize) -> Result<GroupTable, MapError>
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        validate_map(map)?;
+
+        Ok(extract_groups(map, pred, kernel_size))
+    }
+
+    /// Like `extract_groups`, but only the cells in the region of interest
+    /// `roi` are grouped; see `filter_map_roi`. The groups can't grow out of
+    /// the region, so an obstacle that straddles its edge is cut off there.
+    pub fn extract_groups_roi<F>(map: &Map, pred: F, kernel_size: usize, roi: (Point, Point)) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        group_cells(filter_map_roi(map, pred, roi), kernel_size, Connectivity::Eight)
+    }
+
+    /// Like `extract_groups`, but with two thresholds, like the hysteresis in
+    /// Canny edge detection: groups are only started from cells that satisfy
+    /// `strong`, but they can grow through cells that only satisfy `weak`.
+    ///
+    /// With a single threshold, cells near the edge of an obstacle flicker in
+    /// and out, which makes the outline ragged. A low `weak` threshold fills in
+    /// the outline, while the high `strong` threshold stops faint noise that
+    /// isn't near anything from becoming a group of its own. Cells that satisfy
+    /// `strong` are always included, whether or not they satisfy `weak`.
+    ///
+    /// Like in `extract_groups_with`, `connectivity` says whether the groups
+    /// can grow across corners.
+    pub fn extract_groups_hysteresis<F1, F2>(map: &Map, strong: F1, weak: F2, kernel_size: usize, connectivity: Connectivity) -> GroupTable
+    where
+        F1: Fn(i8) -> bool + Sync,
+        F2: Fn(i8) -> bool + Sync
+    {
+        let strong_cells = filter_map(map, strong);
+        let weak_cells = filter_map(map, weak);
+
+        let group_table = group_cells(union(&strong_cells, &weak_cells), kernel_size, connectivity);
+
+        // only keep the groups that have a strong cell in them, numbering them
+        // in the same order as before.
+        let mut groups: Vec<(GroupNumber, Points)> = group_table.into_iter()
+        .filter(|&(_, ref points)| points.iter().any(|p| strong_cells.contains(p)))
+        .collect();
+
+        groups.sort_by_key(|&(group, _)| group);
+
+        groups.into_iter().enumerate()
+        .map(|(group, (_, points))| (group, points))
+        .collect()
+    }
+
+    /// How `extract_groups_with` treats unknown (`-1`) cells.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum UnknownCells
+    {
+        /// Unknown cells split groups apart, like any other cell that doesn't
+        /// satisfy the predicate. This is what `extract_groups` does.
+        Blocking,
+
+        /// An unknown cell that lies between two cells that satisfy the
+        /// predicate (along a row, column or diagonal, and within reach of the
+        /// kernel on both sides) joins them into the same group. The unknown
+        /// cells themselves aren't part of any group. With
+        /// `Connectivity::Four`, the diagonals don't count.
+        ///
+        /// This is useful when `gmapping` hasn't seen all of an obstacle yet,
+        /// so that its outline has gaps in it.
+        Bridged,
+    }
+
+    /// Like `extract_groups`, but lets you choose whether diagonal cells are
+    /// connected (see `Connectivity`), how unknown cells are treated (see
+    /// `UnknownCells`), which values they have (see `UnknownValues`), and how
+    /// the map was published (see `Encoding`).
+    ///
+    /// `pred` sees every value, unknown or not, so pair `unknowns` with a
+    /// predicate that leaves them out, e.g `occupied_in`.
+    pub fn extract_groups_with<F>(
+        map: &Map,
+        pred: F,
+        kernel_size: usize,
+        connectivity: Connectivity,
+        unknown: UnknownCells,
+        unknowns: UnknownValues,
+        encoding: Encoding) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        let cells = filter_map_encoded(map, pred, encoding);
+
+        if unknown == UnknownCells::Blocking
+        {
+            return group_cells(cells, kernel_size, connectivity);
+        }
+
+        let bridges = bridge_cells(map, &cells, kernel_size, connectivity, unknowns, encoding);
+
+        let mut group_table = group_cells(union(&cells, &bridges), kernel_size, connectivity);
+
+        for points in group_table.values_mut()
+        {
+            points.retain(|p| !bridges.contains(p));
+        }
+
+        return group_table;
+    }
+
+    // Helper for extract_groups_with; finds the unknown cells that have a cell
+    // from `cells` on either side of them, within reach of the kernel.
+    fn bridge_cells(map: &Map, cells: &Points, kernel_size: usize, connectivity: Connectivity, unknowns: UnknownValues, encoding: Encoding) -> Points
+    {
+        let height = map.info.height as isize;
+        let width  = map.info.width  as isize;
+        let reach  = kernel_size.saturating_sub(1) as isize;
+
+        // the cell `i` steps along `d` from `p`, if it's on the map.
+        let step = |p: Point, d: (isize, isize), i: isize|
+        {
+            let row = p.0 as isize + i * d.0;
+            let col = p.1 as isize + i * d.1;
+
+            if row < 0 || col < 0 || row >= height || col >= width { None } else { Some((row as usize, col as usize)) }
+        };
+
+        let reaches = |p: Point, d: (isize, isize), sign: isize|
+        {
+            (1..reach + 1).any(|i| step(p, d, sign * i).map_or(false, |n| cells.contains(&n)))
+        };
+
+        let directions: &[(isize, isize)] = match connectivity
+        {
+            Connectivity::Four => &[(0, 1), (1, 0)],
+            Connectivity::Eight => &[(0, 1), (1, 0), (1, 1), (1, -1)],
+        };
+
+        filter_map_encoded(map, unknown_in(unknowns), encoding).into_par_iter()
+        .filter(|p|
+        {
+            directions.iter().any(|d| reaches(*p, *d, 1) && reaches(*p, *d, -1))
+        })
+        .collect()
+    }
+
+    // Helper for extract_groups; splits the cells up into groups.
+    fn group_cells(cells: Points, kernel_size: usize, connectivity: Connectivity) -> GroupTable
+    {
+        GroupStream::new(cells, Kernel::square(kernel_size), connectivity).enumerate().collect()
+    }
+
+    /// Which cells count as neighbours, as a number of cells along each axis,
+    /// like `kernel_size` elsewhere: a cell's neighbours are those up to
+    /// `rows - 1` rows and `cols - 1` columns away from it.
+    ///
+    /// On a map whose cells aren't square, the same number of cells along each
+    /// axis covers a different distance, so use `for_radius` to get the cells
+    /// within a given distance instead.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Kernel
+    {
+        /// The size of the kernel along a column, i.e how many rows it covers.
+        pub rows: usize,
+
+        /// The size of the kernel along a row, i.e how many columns it covers.
+        pub cols: usize,
+    }
+
+    impl Kernel
+    {
+        /// The same size along both axes.
+        pub fn square(kernel_size: usize) -> Self
+        {
+            Kernel { rows: kernel_size, cols: kernel_size }
+        }
+
+        /// The kernel that reaches (at least) `radius` metres along each axis,
+        /// given the size of the cells.
+        pub fn for_radius(radius: Num, res: Resolution) -> Self
+        {
+            let reach = |size: Num| if size > 0.0 { (radius / size).ceil().max(0.0) as usize } else { 0 };
+
+            Kernel { rows: reach(res.y) + 1, cols: reach(res.x) + 1 }
+        }
+    }
+
+    /// Like `extract_groups`, but with a kernel that can be a different size
+    /// along each axis; see `Kernel`.
+    pub fn extract_groups_kernel<F>(map: &Map, pred: F, kernel: Kernel) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        GroupStream::new(filter_map(map, pred), kernel, Connectivity::Eight).enumerate().collect()
+    }
+
+    /// Whether cells that are diagonal to each other are connected, i.e can be
+    /// part of the same group.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Connectivity
+    {
+        /// Only cells in the same row or column (and within reach of the
+        /// kernel) are neighbours, so groups can't join up across a corner.
+        Four,
+
+        /// Every cell within reach of the kernel is a neighbour, diagonals
+        /// included. This is what `extract_groups` does.
+        Eight,
+    }
+
+    impl Connectivity
+    {
+        // whether `n`, which is within reach of the kernel of `p`, is one of
+        // its neighbours.
+        fn connects(self, p: Point, n: Point) -> bool
+        {
+            self == Connectivity::Eight || n.0 == p.0 || n.1 == p.1
+        }
+    }
+
+    /// Like `extract_groups`, but gives back each group as soon as it has been
+    /// found, rather than waiting for all of them. This lets the groups be
+    /// classified while the rest are still being found, or the search be cut
+    /// short when time runs out.
+    ///
+    /// The groups come out in the same order as `extract_groups` numbers them,
+    /// so numbering them with `enumerate` gives exactly the same table.
+    pub fn extract_groups_stream<F>(map: &Map, pred: F, kernel_size: usize) -> impl Iterator<Item=Points>
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        GroupStream::new(filter_map(map, pred), Kernel::square(kernel_size), Connectivity::Eight)
+    }
+
+    // finds the groups among a set of cells one at a time; see
+    // `extract_groups_stream`.
+    struct GroupStream
+    {
+        cells: Points,
+        seeds: std::vec::IntoIter<Point>,
+        staging: Vec<Point>,
+        kernel: Kernel,
+        connectivity: Connectivity,
+    }
+
+    impl GroupStream
+    {
+        fn new(cells: Points, kernel: Kernel, connectivity: Connectivity) -> Self
+        {
+            // each group is started from the first of its cells in row-major
+            // order, rather than from whichever cell the hash set happens to
+            // give back first. This keeps the numbering of the groups the same
+            // from one run to the next, and saves rebuilding the whole set for
+            // every group.
+            let mut seeds: Vec<Point> = cells.iter().cloned().collect();
+            seeds.sort();
+
+            GroupStream { cells, seeds: seeds.into_iter(), staging: Vec::new(), kernel, connectivity }
+        }
+    }
+
+    impl Iterator for GroupStream
+    {
+        type Item = Points;
+
+        fn next(&mut self) -> Option<Points>
+        {
+            // we play "towers of babylon".
+            while let Some(index) = self.seeds.next()
+            {
+                // already part of an earlier group.
+                if !self.cells.remove(&index) { continue; }
+
+                let mut group = Points::default();
+
+                self.staging.push(index);
+                while let Some(current_index) = self.staging.pop()
+                {
+                    // move all of the neighbours
+                    process_neighbours(current_index, &mut self.staging, &mut self.cells, self.kernel, self.connectivity);
+                    group.insert(current_index);
+                }
+
+                return Some(group);
+            }
+
+            None
+        }
+    }
+
+    /// Like `extract_groups`, but keeps track of which cells are left to check
+    /// with a flat array of flags (one per cell, indexed like `map.data`)
+    /// rather than a `HashSet`.
+    ///
+    /// Checking whether a neighbour is part of the group is then a plain array
+    /// lookup instead of a hash probe, which is much friendlier to the cache;
+    /// on the benchmark arenas (`cargo bench -p common`) it takes between an
+    /// eighth and a quarter of the time. The groups are only converted back
+    /// into `Points` at the end. The result is exactly the same as
+    /// `extract_groups`, group numbers included.
+    pub fn extract_groups_dense<F>(map: &Map, pred: F, kernel_size: usize) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        let height = map.info.height as usize;
+        let width  = map.info.width  as usize;
+
+        let mut group_table = GroupTable::default();
+
+        if width * height != map.data.len()
+        {
+            println!("ERROR! Map has {} cells, but is {}x{}. Ignoring it.", map.data.len(), width, height);
+            return group_table;
+        }
+
+        // the cells which satisfy the predicate, and haven't been grouped yet.
+        let mut remaining: Vec<bool> = map.data.par_iter().map(|value| pred(*value)).collect();
+
+        let mut current_group = 0;
+        let mut staging = Vec::new();
+
+        for start in 0..remaining.len()
+        {
+            if !remaining[start] { continue; }
+
+            remaining[start] = false;
+            staging.push(start);
+
+            let mut group = Points::default();
+
+            while let Some(current) = staging.pop()
+            {
+                let p = point_of(map, current);
+
+                for_each_neighbour(p, kernel_size, |n|
+                {
+                    if n.0 >= height || n.1 >= width { return; }
+
+                    let idx = index_of(map, n);
+
+                    if remaining[idx]
+                    {
+                        remaining[idx] = false;
+                        staging.push(idx);
+                    }
+                });
+
+                group.insert(p);
+            }
+
+            group_table.insert(current_group, group);
+            current_group += 1;
+        }
+
+        return group_table;
+    }
+
+    /// Like `extract_groups`, but splits the map up into square tiles of
+    /// `tile` cells each side, and groups each tile in parallel. This is
+    /// worthwhile for very large maps.
+    ///
+    /// Groups that cross the edge of a tile get split up by the tiling, so
+    /// once each tile is done, any groups with cells that neighbour each other
+    /// across a tile edge are merged back together. The result is the same as
+    /// grouping the whole map at once with the same `connectivity` (apart from
+    /// the group numbers).
+    pub fn extract_groups_tiled<F>(map: &Map, pred: F, kernel_size: usize, connectivity: Connectivity, tile: usize) -> GroupTable
+    where
+        F: Fn(i8) -> bool + Sync
+    {
+        let tile = tile.max(1);
+        let tile_of = |p: &Point| (p.0 / tile, p.1 / tile);
+
+        // sort the cells into their tiles.
+        let mut tiles: HashMap<(usize, usize), Points> = HashMap::default();
+
+        for p in filter_map(map, pred)
+        {
+            tiles.entry(tile_of(&p)).or_insert(Points::default()).insert(p);
+        }
+
+        // group each tile on its own.
+        let pieces: Vec<Points> = tiles.into_par_iter()
+        .flat_map(|(_tile, cells)| group_cells(cells, kernel_size, connectivity).into_iter().map(|(_group, points)| points).collect::<Vec<_>>())
+        .collect();
+
+        let mut piece_of: HashMap<Point, usize> = HashMap::default();
+
+        for (piece, points) in pieces.iter().enumerate()
+        {
+            for p in points { piece_of.insert(*p, piece); }
+        }
+
+        // stitch the pieces back together. Only cells within reach of a tile
+        // edge can have neighbours in another tile.
+        let reach = kernel_size.saturating_sub(1);
+        let near_edge = |x: usize| x % tile < reach || x % tile + reach >= tile;
+
+        let mut pieces_set = DisjointSet::new(pieces.len());
+
+        for (p, piece) in piece_of.iter()
+        {
+            if !near_edge(p.0) && !near_edge(p.1) { continue; }
+
+            for_each_neighbour(*p, kernel_size, |n|
+            {
+                if tile_of(&n) == tile_of(p) || !connectivity.connects(*p, n) { return; }
+
+                if let Some(other) = piece_of.get(&n)
+                {
+                    pieces_set.union(*piece, *other);
+                }
+            });
+        }
+
+        // finally, merge the pieces and number the groups.
+        let mut group_of_root: HashMap<usize, GroupNumber> = HashMap::default();
+        let mut group_table = GroupTable::default();
+
+        for (piece, points) in pieces.into_iter().enumerate()
+        {
+            let root = pieces_set.find(piece);
+            let next_group = group_of_root.len();
+            let group = *group_of_root.entry(root).or_insert(next_group);
+
+            group_table.entry(group).or_insert(Points::default()).extend(points);
+        }
+
+        return group_table;
+    }
+
+    // a minimal union-find, for merging groups that turn out to be connected.
+    struct DisjointSet
+    {
+        parent: Vec<usize>,
+    }
+
+    impl DisjointSet
+    {
+        fn new(len: usize) -> Self
+        {
+            DisjointSet { parent: (0..len).collect() }
+        }
+
+        fn find(&mut self, mut x: usize) -> usize
+        {
+            while self.parent[x] != x
+            {
+                // path halving, to keep the trees flat.
+                self.parent[x] = self.parent[self.parent[x]];
+                x = self.parent[x];
+            }
+
+            x
+        }
+
+        fn union(&mut self, a: usize, b: usize)
+        {
+            let a = self.find(a);
+            let b = self.find(b);
+
+            if a != b { self.parent[a] = b; }
+        }
+    }
+
+    /// Extracts the single group containing `seed`, using the same notion of
+    /// connectivity as `extract_groups`.
+    ///
+    /// This only visits the cells of that one group, so it is much cheaper
+    /// than `extract_groups` when you already know which obstacle you are
+    /// interested in. If `seed` itself doesn't satisfy the predicate, the
+    /// result is empty.
+    pub fn flood_fill_from<F>(map: &Map, seed: Point, pred: F, kernel_size: usize) -> Points
+    where
+        F: Fn(i8) -> bool
+    {
+        let height = map.info.height as usize;
+        let width  = map.info.width  as usize;
+
+        let satisfies = |p: Point|
+        {
+            p.0 < height && p.1 < width &&
+            map.data.get(index_of(map, p)).map_or(false, |value| pred(*value))
+        };
+
+        let mut group = Points::default();
+
+        if !satisfies(seed) { return group; }
+
+        let mut staging = vec![seed];
+        group.insert(seed);
+
+        while let Some(current) = staging.pop()
+        {
+            for_each_neighbour(current, kernel_size, |p|
+            {
+                if !group.contains(&p) && satisfies(p)
+                {
+                    group.insert(p);
+                    staging.push(p);
+                }
+            });
+        }
+
+        return group;
+    }
+
+    // Helper for extract_groups
+    fn process_neighbours(
+        p: Point,
+        staging: &mut Vec<Point>,
+        cells: &mut Points,
+        kernel: Kernel,
+        connectivity: Connectivity,
+    )
+    {
+        // move the neighbours that are still in the set of cells to check into
+        // the staging area.
+        for_each_neighbour_in(p, kernel, |n|
+        {
+            if !connectivity.connects(p, n) { return; }
+
+            if cells.remove(&n) { staging.push(n); }
+        });
+    }
+
+    /// Calls `f` on each of the neighbours of a cell; the same cells as those
+    /// returned by `neighbours`, but without building a set of them first.
+    pub fn for_each_neighbour<F>(
+        p: Point,
+        kernel_size: usize,
+        f: F,
+    )
+    where
+        F: FnMut(Point)
+    {
+        for_each_neighbour_in(p, Kernel::square(kernel_size), f)
+    }
+
+    /// Like `for_each_neighbour`, but with a kernel that can be a different
+    /// size along each axis.
+    pub fn for_each_neighbour_in<F>(
+        p: Point,
+        kernel: Kernel,
+        mut f: F,
+    )
+    where
+        F: FnMut(Point)
+    {
+        if kernel.rows == 0 || kernel.cols == 0 { return; }
+
+        let row_reach = kernel.rows - 1;
+        let col_reach = kernel.cols - 1;
+
+        for row in p.0.saturating_sub(row_reach)..=p.0.saturating_add(row_reach)
+        {
+            for col in p.1.saturating_sub(col_reach)..=p.1.saturating_add(col_reach)
+            {
+                f((row, col));
+            }
+        }
+    }
+
+    /// Returns the set of neighbours of a cell.
+    pub fn neighbours(
+        p: Point,
+        kernel_size: usize,
+    ) -> Points
+    {
+        let mut neighbours: Points = Points::default();
+
+        for i in 0..kernel_size
+        {
+            for j in 0..kernel_size
+            {
+                neighbours.insert((p.0.saturating_add(i), p.1.saturating_add(j)));
+                neighbours.insert((p.0.saturating_add(i), p.1.saturating_sub(j)));
+                neighbours.insert((p.0.saturating_sub(i), p.1.saturating_add(j)));
+                neighbours.insert((p.0.saturating_sub(i), p.1.saturating_sub(j)));
+            }
+        }
+
+        return neighbours;
+    }
+
+    /// Returns the cells at exactly `radius` steps from `p`, counting diagonal
+    /// steps as one, i.e the square ring around the cell rather than the whole
+    /// filled square that `neighbours` gives. This is handy for following
+    /// boundaries.
+    ///
+    /// Cells that would be off the top or left edge of the map are left out,
+    /// so the ring is cut short near the edges. A radius of `0` gives just `p`.
+    pub fn neighbour_ring(p: Point, radius: usize) -> Points
+    {
+        let r = radius as isize;
+        let (row, col) = (p.0 as isize, p.1 as isize);
+
+        let mut ring = Points::default();
+
+        for dr in -r..r + 1
+        {
+            for dc in -r..r + 1
+            {
+                if dr.abs().max(dc.abs()) != r { continue; }
+
+                let (nr, nc) = (row + dr, col + dc);
+
+                if nr >= 0 && nc >= 0
+                {
+                    ring.insert((nr as usize, nc as usize));
+                }
+            }
+        }
+
+        ring
+    }
+
+    /// Erodes a set of cells: only the cells whose whole neighbourhood (as in
+    /// `for_each_neighbour`) is in the set are kept, so the set shrinks by
+    /// `kernel_size - 1` cells on every side, and anything thinner than that
+    /// disappears.
+    ///
+    /// A cell near the top or left edge of the map doesn't have a whole
+    /// neighbourhood, so it's eroded, just like one near the bottom or right
+    /// edge, whose neighbourhood runs off the edge of the set.
+    pub fn erode(points: &Points, kernel_size: usize) -> Points
+    {
+        let reach = kernel_size.saturating_sub(1);
+
+        points.par_iter()
+        .filter(|p|
+        {
+            if p.0 < reach || p.1 < reach { return false; }
+
+            let mut inside = true;
+            for_each_neighbour(**p, kernel_size, |n| inside = inside && points.contains(&n));
+            inside
+        })
+        .cloned()
+        .collect()
+    }
+
+    /// Dilates a set of cells: every cell in the neighbourhood (as in
+    /// `for_each_neighbour`) of a cell in the set is added, so the set grows by
+    /// `kernel_size - 1` cells on every side.
+    pub fn dilate(points: &Points, kernel_size: usize) -> Points
+    {
+        let mut dilated = points.clone();
+
+        for p in points.iter()
+        {
+            for_each_neighbour(*p, kernel_size, |n| { dilated.insert(n); });
+        }
+
+        dilated
+    }
+
+    /// Erodes and then dilates a set of cells, which gets rid of specks and thin
+    /// spurs that are smaller than the kernel, while leaving bigger blobs about
+    /// the same size.
+    pub fn open(points: &Points, kernel_size: usize) -> Points
+    {
+        dilate(&erode(points, kernel_size), kernel_size)
+    }
+
+    /// Dilates and then erodes a set of cells, which fills in holes and gaps
+    /// that are smaller than the kernel, while leaving bigger blobs about the
+    /// same size.
+    pub fn close(points: &Points, kernel_size: usize) -> Points
+    {
+        erode(&dilate(points, kernel_size), kernel_size)
+    }
+
+    /// Finds the orientation of the principal axis of a group, i.e, the
+    /// direction in which the group is most spread out.
+    ///
+    /// This is the angle (in radians, in map coordinates) of the eigenvector of
+    /// the covariance of the points with the largest eigenvalue. The result is
+    /// in the range `(-pi/2, pi/2]`. For a group with no clear orientation
+    /// (e.g a circle) the result is not very meaningful; check
+    /// `group_anisotropy` first.
+    pub fn group_principal_axis(map: &Map, points: &Points) -> Num
+    {
+        match group_covariance(map, points)
+        {
+            Some((sxx, syy, sxy)) => 0.5 * (2.0 * sxy).atan2(sxx - syy),
+            None => 0.0,
+        }
+    }
+
+    /// How much more spread out a group is along its principal axis than across
+    /// it, as the ratio of the two eigenvalues of the covariance of the points.
+    ///
+    /// This is `1` for a group with no orientation at all (e.g a circle or a
+    /// square, whichever way round it is), and goes up as the group gets longer
+    /// and thinner, so it says how far to trust `group_principal_axis`. A
+    /// straight line gives infinity, and an empty group `1`.
+    pub fn group_anisotropy(map: &Map, points: &Points) -> Num
+    {
+        let (sxx, syy, sxy) = match group_covariance(map, points)
+        {
+            Some(c) => c,
+            None => return 1.0,
+        };
+
+        let mean = (sxx + syy) / 2.0;
+        let spread = ((sxx - syy) / 2.0).hypot(sxy);
+
+        let (major, minor) = (mean + spread, mean - spread);
+
+        if major <= 0.0 { 1.0 } else if minor <= 0.0 { INFINITY } else { major / minor }
+    }
+
+    // the covariance of the points of a group, in map coordinates, as the sums
+    // `(xx, yy, xy)` about the mean; `None` if the group is empty.
+    fn group_covariance(map: &Map, points: &Points) -> Option<(Num, Num, Num)>
+    {
+        let items = par_transform(map, points.par_iter().cloned());
+
+        if items.len() == 0 { return None; }
+
+        let len = items.len() as Num;
+
+        // these sums are done in order rather than in parallel. A parallel sum
+        // can come out slightly differently from run to run, and for a
+        // symmetric group that's enough to flip the sign of a tiny angle.
+        let (sx, sy) = items.iter()
+        .fold((0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1));
+
+        let (mx, my) = (sx / len, sy / len);
+
+        let covariance = items.iter()
+        .map(|&(x, y)|
+        {
+            let dx = x - mx;
+            let dy = y - my;
+
+            (dx*dx, dy*dy, dx*dy)
+        })
+        .fold((0.0, 0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+
+        Some(covariance)
+    }
+
+    /// Returns the cells of a group which lie on its perimeter, i.e, the cells
+    /// which have at least one 4-neighbour (up, down, left, right) that is not
+    /// in the group. Cells on the edge of the map count as perimeter cells.
+    ///
+    /// Comparing the size of this to the size of the whole group tells you
+    /// whether the group is a filled blob or a hollow outline.
+    pub fn group_perimeter_cells(map: &Map, points: &Points) -> Points
+    {
+        let height = map.info.height as usize;
+        let width  = map.info.width  as usize;
+
+        let is_member = |row: Option<usize>, col: Option<usize>| match (row, col)
+        {
+            (Some(row), Some(col)) if row < height && col < width => points.contains(&(row, col)),
+            _ => false,
+        };
+
+        points.iter()
+        .filter(|&&(row, col)|
+        {
+            !is_member(row.checked_sub(1), Some(col)) ||
+            !is_member(row.checked_add(1), Some(col)) ||
+            !is_member(Some(row), col.checked_sub(1)) ||
+            !is_member(Some(row), col.checked_add(1))
+        })
+        .cloned()
+        .collect()
+    }
+
+    /// A group of cells, along with some commonly-needed information about it.
+    /// All of the positions are in map coordinates (see `transform`).
+    #[derive(Debug, Clone)]
+    pub struct Group
+    {
+        /// The group number, from the `GroupTable`.
+        pub id: GroupNumber,
+
+        /// The cells that make up the group.
+        pub points: Points,
+
+        /// The mean position of the cells.
+        pub centroid: (Num, Num),
+
+        /// The corners of the bounding box of the cells, as `(min, max)`.
+        pub bbox: ((Num, Num), (Num, Num)),
+
+        /// The mean value of the cells in the map, leaving out unknown cells.
+        /// If every cell is unknown, this is `0`.
+        pub mean_occupancy: Num,
+    }
+
+    impl Group
+    {
+        /// Works out the information about a group of cells. Cells with a
+        /// negative value are unknown.
+        pub fn new(map: &Map, id: GroupNumber, points: Points) -> Self
+        {
+            Group::with_unknowns(map, id, points, UnknownValues::default())
+        }
+
+        /// Like `new`, but with the given unknown values.
+        pub fn with_unknowns(map: &Map, id: GroupNumber, points: Points, unknowns: UnknownValues) -> Self
+        {
+            let items = par_transform(map, points.par_iter().cloned());
+            let len = items.len().max(1) as Num;
+
+            let (sx, sy) = items.iter()
+            .fold((0.0, 0.0), |acc, p| (acc.0 + p.0, acc.1 + p.1));
+
+            let bbox = items.iter()
+            .fold(((INFINITY, INFINITY), (NEG_INFINITY, NEG_INFINITY)), |(min, max), p|
+            {
+                ((min.0.min(p.0), min.1.min(p.1)), (max.0.max(p.0), max.1.max(p.1)))
+            });
+
+            // an unknown value isn't an occupancy, so it would only drag the
+            // mean down.
+            let (occupancy, known) = points.iter()
+            .filter_map(|p| map.data.get(index_of(map, *p)))
+            .filter(|value| !unknowns.contains(**value))
+            .fold((0.0, 0), |acc, value| (acc.0 + *value as Num, acc.1 + 1));
+
+            Group
+            {
+                id,
+                centroid: (sx / len, sy / len),
+                bbox,
+                mean_occupancy: occupancy / known.max(1) as Num,
+                points,
+            }
+        }
+    }
+
+    /// Works out the information about every group in the table. The groups
+    /// are returned in order of group number.
+    pub fn describe_groups(map: &Map, groups: GroupTable) -> Vec<Group>
+    {
+        let mut groups: Vec<Group> = groups.into_par_iter()
+        .map(|(id, points)| Group::new(map, id, points))
+        .collect();
+
+        groups.sort_by_key(|group| group.id);
+
+        groups
+    }
+
+    /// Checks whether a group looks like the border of the arena, i.e, whether
+    /// any of its cells lie on the edge of the map.
+    pub fn is_arena_border(points: &Points, map: &Map) -> bool
+    {
+        let last_row = (map.info.height as usize).saturating_sub(1);
+        let last_col = (map.info.width  as usize).saturating_sub(1);
+
+        points.iter().any(|&(row, col)| row == 0 || col == 0 || row >= last_row || col >= last_col)
+    }
+
+    /// Finds the groups that look like the walls of the arena: those that
+    /// touch the edge of the map (see `is_arena_border`), and those that
+    /// surround all of the other groups, i.e whose bounding box has every
+    /// other group inside it. The maps from `gmapping` usually reach well past
+    /// the walls, so it's normally the second that catches them. A group on its
+    /// own doesn't surround anything, so it isn't taken to be a wall.
+    pub fn arena_border_groups(map: &Map, groups: &[Group]) -> HashSet<GroupNumber>
+    {
+        // a little slack, since the cells of two groups can be a cell apart.
+        let slack = map.info.resolution as Num;
+
+        let inside = |inner: &Group, outer: &Group|
+        {
+            let ((ixmin, iymin), (ixmax, iymax)) = inner.bbox;
+            let ((oxmin, oymin), (oxmax, oymax)) = outer.bbox;
+
+            ixmin >= oxmin - slack && iymin >= oymin - slack && ixmax <= oxmax + slack && iymax <= oymax + slack
+        };
+
+        groups.iter()
+        .filter(|group|
+        {
+            is_arena_border(&group.points, map) || (groups.len() > 1 && groups.iter()
+            .filter(|other| other.id != group.id)
+            .all(|other| inside(other, group)))
+        })
+        .map(|group| group.id)
+        .collect()
+    }
+
+    /// Returns the groups in the table, ordered from largest to smallest.
+    ///
+    /// The larger groups are the ones most likely to be real obstacles, so it
+    /// makes sense to process those first. Groups of the same size are ordered
+    /// by group number, so that the ordering is always the same.
+    pub fn groups_by_size_desc(groups: &GroupTable) -> Vec<(GroupNumber, &Points)>
+    {
+        let mut sorted: Vec<(GroupNumber, &Points)> = groups.iter()
+        .map(|(group, points)| (*group, points))
+        .collect();
+
+        sorted.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+
+        sorted
+    }
+
+    /// Keeps only the `n` largest groups (by number of cells), and throws the
+    /// rest away; e.g on a cluttered map, most of the groups are a cell or two
+    /// of noise. Ties are broken the same way as in `groups_by_size_desc`.
+    pub fn top_n_groups(groups: GroupTable, n: usize) -> GroupTable
+    {
+        if groups.len() <= n { return groups; }
+
+        let keep: Vec<GroupNumber> = groups_by_size_desc(&groups).into_iter()
+        .take(n)
+        .map(|(group, _)| group)
+        .collect();
+
+        let mut groups = groups;
+        groups.retain(|group, _| keep.contains(group));
+
+        groups
+    }
+
+    /// The points that are in either `a` or `b`.
+    ///
+    /// These set operations return `Points`, so the result keeps using the
+    /// fast hasher; collecting one of the `HashSet` iterators by hand makes it
+    /// easy to end up with a `std` `HashSet` instead.
+    pub fn union(a: &Points, b: &Points) -> Points
+    {
+        a.union(b).cloned().collect()
+    }
+
+    /// The points that are in both `a` and `b`.
+    pub fn intersection(a: &Points, b: &Points) -> Points
+    {
+        a.intersection(b).cloned().collect()
+    }
+
+    /// The points that are in `a` but not in `b`.
+    pub fn difference(a: &Points, b: &Points) -> Points
+    {
+        a.difference(b).cloned().collect()
+    }
+
+    /// The points that are in exactly one of `a` and `b`.
+    pub fn symmetric_difference(a: &Points, b: &Points) -> Points
+    {
+        a.symmetric_difference(b).cloned().collect()
+    }
+
+    /// Encodes a set of points as runs of consecutive cells, each given as
+    /// `(start, length)`, where `start` is the index of the first cell of the
+    /// run in `map.data`. The runs are in order.
+    ///
+    /// A group is usually a few solid blobs, so this is far smaller than
+    /// printing every point, which makes it handy for logging groups and
+    /// comparing them between runs. Use `rle_to_points` to get the points
+    /// back. Points that are off the edge of the map are left out.
+    pub fn points_to_rle(map: &Map, points: &Points) -> Vec<(usize, usize)>
+    {
+        let height = map.info.height as usize;
+        let width  = map.info.width  as usize;
+
+        let mut indices: Vec<usize> = points.iter()
+        .filter(|&&(row, col)| row < height && col < width)
+        .map(|&p| index_of(map, p))
+        .collect();
+
+        indices.sort();
+
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+
+        for index in indices
+        {
+            match runs.last_mut()
+            {
+                Some(&mut (start, ref mut length)) if start + *length == index => *length += 1,
+                _ => runs.push((index, 1)),
+            }
+        }
+
+        runs
+    }
+
+    /// The points encoded by `points_to_rle`.
+    pub fn rle_to_points(map: &Map, runs: &[(usize, usize)]) -> Points
+    {
+        runs.iter()
+        .flat_map(|&(start, length)| start..start + length)
+        .map(|index| point_of(map, index))
+        .collect()
+    }
+
+    // the worked example in the module documentation.
+    #[cfg(test)]
+    mod index_tests
+    {
+        use super::*;
+
+        #[test]
+        fn cells_round_trip_through_the_worked_example()
+        {
+            let mut map = MapBuilder::new(7, 2).resolution(1.0).build();
+
+            for &index in [0, 6, 7, 13].iter()
+            {
+                map.data[index] = 100;
+            }
+
+            let cells = filter_map(&map, occupied(3));
+            let expected = [(0, (0, 0), (-3.5, 1.0)), (6, (0, 6), (2.5, 1.0)), (7, (1, 0), (-3.5, 0.0)), (13, (1, 6), (2.5, 0.0))];
+
+            assert_eq!(cells.len(), expected.len());
+
+            for &(index, point, xy) in expected.iter()
+            {
+                assert!(cells.contains(&point), "{:?} is missing from {:?}", point, cells);
+                assert_eq!(index_of(&map, point), index);
+                assert_eq!(point_of(&map, index), point);
+
+                let transformed: Vec<(Num, Num)> = transform(&map, vec![point]);
+                assert!((transformed[0].0 - xy.0).abs() < 1e-9 && (transformed[0].1 - xy.1).abs() < 1e-9, "{:?} went to {:?}", point, transformed);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::*;
+
+        #[test]
+        fn groups_by_size_desc_puts_the_biggest_group_first()
+        {
+            let mut groups = GroupTable::default();
+
+            for &(group, size) in [(0, 2), (1, 5), (2, 3), (3, 2)].iter()
+            {
+                groups.insert(group, (0..size).map(|col| (group, col)).collect());
+            }
+
+            let order: Vec<GroupNumber> = groups_by_size_desc(&groups).iter().map(|g| g.0).collect();
+
+            // groups of the same size come in order of group number.
+            assert_eq!(order, vec![1, 2, 0, 3]);
+        }
+
+        #[test]
+        fn index_of_and_point_of_round_trip()
+        {
+            let map = MapBuilder::new(5, 3).build();
+
+            for idx in 0..15
+            {
+                assert_eq!(index_of(&map, point_of(&map, idx)), idx);
+            }
+
+            // rows are `width` cells long.
+            assert_eq!(index_of(&map, (1, 0)), 5);
+            assert_eq!(point_of(&map, 14), (2, 4));
+        }
+
+        #[test]
+        fn filter_map_context_sees_the_neighbours()
+        {
+            // a 2x2 block, and a speckle on its own.
+            let map = MapBuilder::new(4, 3)
+                .rect((0, 0), (1, 1), 100)
+                .cell((1, 3), 100)
+                .build();
+
+            let cells = filter_map_context(&map, |value, context|
+            {
+                value > 3 && context.iter().filter(|&&v| v > 3).count() >= 3
+            });
+
+            assert_eq!(cells.len(), 4);
+            assert!(!cells.contains(&(1, 3)));
+
+            // off the edge of the map counts as unknown.
+            let corner = filter_map_context(&map, |_, context| context[0] == -1);
+            assert!(corner.contains(&(0, 0)) && !corner.contains(&(1, 1)));
+        }
+
+        #[test]
+        fn group_principal_axis_follows_the_group()
+        {
+            let map = MapBuilder::new(100, 100).build();
+
+            // going up and to the right; the rows count downwards.
+            let diagonal: Points = (0..30).flat_map(|i| vec![(60 - i, 40 + i), (60 - i, 41 + i)]).collect();
+            let angle = group_principal_axis(&map, &diagonal).to_degrees();
+            assert!((angle - 45.0).abs() < 3.0, "{}", angle);
+
+            // a vertical line is at a quarter-turn, not averaged out to zero.
+            let vertical: Points = (0..30).flat_map(|i| vec![(20 + i, 50), (20 + i, 51)]).collect();
+            let angle = group_principal_axis(&map, &vertical).to_degrees();
+            assert!((angle.abs() - 90.0).abs() < 1e-6, "{}", angle);
+        }
+
+        #[test]
+        fn group_anisotropy_tells_squares_from_rectangles()
+        {
+            let outline = |map: &Map| filter_map(map, occupied(50));
+
+            let square = MapBuilder::new(30, 30)
+                .rect((5, 5), (5, 15), 100).rect((15, 5), (15, 15), 100)
+                .rect((5, 5), (15, 5), 100).rect((5, 15), (15, 15), 100)
+                .build();
+
+            let oblong = MapBuilder::new(30, 30)
+                .rect((5, 5), (5, 20), 100).rect((12, 5), (12, 20), 100)
+                .rect((5, 5), (12, 5), 100).rect((5, 20), (12, 20), 100)
+                .build();
+
+            assert!((group_anisotropy(&square, &outline(&square)) - 1.0).abs() < 1e-6);
+            assert!(group_anisotropy(&oblong, &outline(&oblong)) > 1.5);
+
+            let line: Points = (0..10).map(|col| (3, col)).collect();
+            assert_eq!(group_anisotropy(&square, &line), INFINITY);
+        }
+
+        #[test]
+        fn filter_map_ignores_maps_whose_size_does_not_match()
+        {
+            let mut map = MapBuilder::new(4, 3).fill(100).build();
+            map.data.pop();
+
+            assert!(filter_map(&map, occupied(3)).is_empty());
+
+            // 65536 x 65537 cells wraps around to 65536 in a `u32`, which
+            // must not be mistaken for the number of cells there are.
+            map.info.width = 65536;
+            map.info.height = 65537;
+            map.data = vec![100; 65536];
+
+            assert!(filter_map(&map, occupied(3)).is_empty());
+        }
+
+        #[test]
+        fn validate_map_finds_each_problem()
+        {
+            let good = MapBuilder::new(3, 2).build();
+            assert_eq!(validate_map(&good), Ok(()));
+
+            let mut empty = good.clone();
+            empty.info.width = 0;
+            empty.data.clear();
+            assert_eq!(validate_map(&empty), Err(MapError::BadDimensions));
+
+            let mut short = good.clone();
+            short.data.pop();
+            assert_eq!(validate_map(&short), Err(MapError::DataLengthMismatch));
+
+            for &resolution in [0.0, -0.05, ::std::f32::NAN].iter()
+            {
+                let mut flat = good.clone();
+                flat.info.resolution = resolution;
+                assert_eq!(validate_map(&flat), Err(MapError::ZeroResolution));
+            }
+        }
+
+        #[test]
+        fn group_perimeter_cells_tells_filled_from_hollow()
+        {
+            let map = MapBuilder::new(10, 10).build();
+
+            let filled: Points = (2..7).flat_map(|row| (2..7).map(move |col| (row, col))).collect();
+            assert_eq!(group_perimeter_cells(&map, &filled).len(), 16);
+
+            let hollow = group_perimeter_cells(&map, &filled);
+            assert_eq!(group_perimeter_cells(&map, &hollow), hollow);
+
+            // the edge of the map counts as outside the group.
+            let corner: Points = [(0, 0), (0, 1), (1, 0), (1, 1)].iter().cloned().collect();
+            assert_eq!(group_perimeter_cells(&map, &corner).len(), 4);
+        }
+
+        #[test]
+        fn flood_fill_from_finds_just_the_seeded_region()
+        {
+            let map = MapBuilder::new(10, 10)
+                .rect((1, 1), (2, 2), 100)
+                .rect((6, 6), (8, 8), 100)
+                .build();
+
+            assert_eq!(flood_fill_from(&map, (7, 7), occupied(3), 2).len(), 9);
+            assert_eq!(flood_fill_from(&map, (1, 2), occupied(3), 2).len(), 4);
+
+            // a seed that doesn't match finds nothing.
+            assert!(flood_fill_from(&map, (0, 0), occupied(3), 2).is_empty());
+            assert!(flood_fill_from(&map, (50, 50), occupied(3), 2).is_empty());
+
+            assert_eq!(extract_groups(&map, occupied(3), 2).len(), 2);
+        }
+
+        #[cfg(feature = "image")]
+        #[test]
+        fn to_gray_image_follows_map_server()
+        {
+            let map = MapBuilder::new(7, 2).fill(-1).cell((0, 1), 0).cell((1, 2), 100).cell((1, 3), 50).build();
+
+            let image = to_gray_image(&map);
+
+            assert_eq!(image.dimensions(), (7, 2));
+            assert_eq!(image.get_pixel(1, 0).data[0], 255);
+            assert_eq!(image.get_pixel(2, 1).data[0], 0);
+            assert_eq!(image.get_pixel(3, 1).data[0], 128);
+            assert_eq!(image.get_pixel(0, 0).data[0], 127);
+        }
+
+        #[test]
+        fn transform_with_scales_each_axis_separately()
+        {
+            let map = MapBuilder::new(10, 10).build();
+
+            let xy = transform_with(&map, vec![(0, 0), (5, 5)], Resolution { x: 0.1, y: 0.2 });
+
+            assert!((xy[0].0 + 0.5).abs() < 1e-9 && (xy[0].1 - 1.0).abs() < 1e-9, "{:?}", xy);
+            assert_eq!(xy[1], (0.0, 0.0));
+
+            // square cells are the same as plain `transform`.
+            let square = Resolution::of(&map);
+            assert_eq!(square.x, square.y);
+            assert_eq!(transform_with(&map, vec![(2, 7)], square), transform::<Num, _>(&map, vec![(2, 7)]));
+        }
+
+        #[test]
+        fn group_works_out_the_metadata()
+        {
+            let map = MapBuilder::new(10, 10).resolution(0.1).rect((2, 2), (3, 4), 50).cell((2, 2), 100).build();
+
+            let groups = describe_groups(&map, extract_groups(&map, occupied(3), 2));
+            assert_eq!(groups.len(), 1);
+
+            let group = &groups[0];
+            assert_eq!(group.points.len(), 6);
+            assert!((group.mean_occupancy - (5.0 * 50.0 + 100.0) / 6.0).abs() < 1e-9);
+
+            // columns 2 to 4 are at x = -0.3 to -0.1, and rows 2 and 3 at
+            // y = 0.3 and 0.2.
+            assert!((group.centroid.0 + 0.2).abs() < 1e-6 && (group.centroid.1 - 0.25).abs() < 1e-6, "{:?}", group.centroid);
+            assert!((group.bbox.0 .0 + 0.3).abs() < 1e-6 && (group.bbox.0 .1 - 0.2).abs() < 1e-6, "{:?}", group.bbox);
+            assert!((group.bbox.1 .0 + 0.1).abs() < 1e-6 && (group.bbox.1 .1 - 0.3).abs() < 1e-6, "{:?}", group.bbox);
+        }
+
+        #[test]
+        fn subtract_removes_the_walls()
+        {
+            let background = MapBuilder::new(10, 10).rect((0, 0), (0, 9), 100).build();
+            let map = MapBuilder::new(10, 10).rect((0, 0), (0, 9), 100).rect((4, 4), (5, 5), 100).cell((9, 9), -1).build();
+
+            let result = subtract(&map, &background).unwrap();
+
+            // the wall is gone, and the obstacle is still there.
+            let obstacle = MapBuilder::new(10, 10).rect((4, 4), (5, 5), 100).build();
+            assert_eq!(filter_map(&result, occupied(3)), filter_map(&obstacle, occupied(3)));
+            assert!((0..10).all(|col| result.data[index_of(&result, (0, col))] == -1));
+            assert_eq!(result.data[index_of(&result, (9, 9))], -1);
+            assert_eq!(result.data[index_of(&result, (8, 8))], 0);
+
+            let mut short = background.clone();
+            short.data.pop();
+            assert_eq!(subtract(&map, &short), Err(MapError::DataLengthMismatch));
+        }
+
+        #[test]
+        fn for_each_neighbour_visits_each_neighbour_once()
+        {
+            // including cells on the edge of the map, where some of the
+            // neighbours would be off of it.
+            for &p in [(0, 0), (0, 3), (1, 5), (10, 10)].iter()
+            {
+                for kernel_size in 0..4
+                {
+                    let mut visited = Points::default();
+                    let mut calls = 0;
+
+                    for_each_neighbour(p, kernel_size, |n| { visited.insert(n); calls += 1; });
+
+                    assert_eq!(visited, neighbours(p, kernel_size), "{:?} with kernel {}", p, kernel_size);
+                    assert_eq!(calls, visited.len());
+                }
+            }
+        }
+
+        #[test]
+        fn transform_relative_to_subtracts_the_reference()
+        {
+            let map = MapBuilder::new(20, 10).resolution(0.1).build();
+            let cells = vec![(0, 0), (3, 7), (9, 19)];
+
+            let absolute: Vec<(Num, Num)> = transform(&map, cells.iter().cloned());
+            let relative = transform_relative_to(&map, cells.iter().cloned(), (0.5, -0.25));
+
+            for (a, r) in absolute.iter().zip(relative.iter())
+            {
+                assert!((a.0 - 0.5 - r.0).abs() < 1e-9 && (a.1 + 0.25 - r.1).abs() < 1e-9, "{:?} {:?}", a, r);
+            }
+
+            // a cell relative to itself is at the origin.
+            assert_eq!(transform_relative_to(&map, vec![(3, 7)], absolute[1]), vec![(0.0, 0.0)]);
+        }
+
+        // the groups in a table, without the group numbers, so that two ways
+        // of grouping the same map can be compared.
+        fn sorted_groups(groups: &GroupTable) -> Vec<Vec<Point>>
+        {
+            let mut sorted: Vec<Vec<Point>> = groups.values()
+            .map(|points|
+            {
+                let mut points: Vec<Point> = points.iter().cloned().collect();
+                points.sort();
+                points
+            })
+            .collect();
+
+            sorted.sort();
+            sorted
+        }
+
+        #[test]
+        fn extract_groups_tiled_matches_extract_groups()
+        {
+            let map = MapBuilder::new(20, 20)
+            // straddles the edge of the 8 cell tiles.
+            .rect((3, 5), (11, 13), 100)
+            // only touches diagonally, across the edge of the 16 cell tiles.
+            .cell((15, 15), 100)
+            .cell((16, 16), 100)
+            // runs down through every tile along the side.
+            .rect((0, 19), (19, 19), 100)
+            .build();
+
+            for kernel_size in 1..4
+            {
+                let whole = extract_groups(&map, occupied(3), kernel_size);
+
+                for &tile in [1, 3, 8, 16, 100].iter()
+                {
+                    let tiled = extract_groups_tiled(&map, occupied(3), kernel_size, Connectivity::Eight, tile);
+
+                    assert_eq!(sorted_groups(&whole), sorted_groups(&tiled), "kernel {}, tile {}", kernel_size, tile);
+                }
+            }
+        }
+
+        #[test]
+        fn encoding_decodes_unsigned_maps()
+        {
+            // 200 and 255 as unsigned bytes, i.e occupied and unknown.
+            let map = MapBuilder::new(6, 1).cell((0, 0), 200u8 as i8).cell((0, 1), -1).cell((0, 2), 100).cell((0, 5), 50).build();
+
+            let expected: Points = [(0, 0), (0, 2), (0, 5)].iter().cloned().collect();
+            assert_eq!(filter_map_encoded(&map, occupied(3), Encoding::Unsigned), expected);
+
+            // read as signed, the 200 is a negative, i.e unknown, value.
+            let expected: Points = [(0, 2), (0, 5)].iter().cloned().collect();
+            assert_eq!(filter_map_encoded(&map, occupied(3), Encoding::Signed), expected);
+
+            // the unknown cell still joins up the cells either side of it.
+            let groups = extract_groups_with(&map, occupied(3), 2, Connectivity::Eight, UnknownCells::Bridged, UnknownValues::default(), Encoding::Unsigned);
+            assert_eq!(sorted_groups(&groups), vec![vec![(0, 0), (0, 2)], vec![(0, 5)]]);
+            assert_eq!(extract_groups_encoded(&map, occupied(3), 2, Encoding::Unsigned).len(), 3);
+        }
+
+        #[test]
+        fn set_operations_give_the_right_points()
+        {
+            let points = |items: &[Point]| -> Points { items.iter().cloned().collect() };
+
+            let a = points(&[(0, 0), (0, 1), (1, 1)]);
+            let b = points(&[(1, 1), (2, 2)]);
+
+            assert_eq!(union(&a, &b), points(&[(0, 0), (0, 1), (1, 1), (2, 2)]));
+            assert_eq!(intersection(&a, &b), points(&[(1, 1)]));
+            assert_eq!(difference(&a, &b), points(&[(0, 0), (0, 1)]));
+            assert_eq!(symmetric_difference(&a, &b), points(&[(0, 0), (0, 1), (2, 2)]));
+        }
+
+        #[test]
+        fn unknown_cells_can_bridge_a_gap()
+        {
+            // a wall with one cell that hasn't been seen yet.
+            let gap = MapBuilder::new(20, 20).rect((5, 2), (5, 10), 100).cell((5, 6), -1).build();
+
+            assert_eq!(extract_groups_with(&gap, occupied(3), 2, Connectivity::Eight, UnknownCells::Blocking, UnknownValues::default(), Encoding::Signed).len(), 2);
+
+            // joined up, but the unknown cell isn't part of the group.
+            let bridged = extract_groups_with(&gap, occupied(3), 2, Connectivity::Eight, UnknownCells::Bridged, UnknownValues::default(), Encoding::Signed);
+            assert_eq!(bridged.len(), 1);
+            assert_eq!(bridged.values().next().unwrap().len(), 8);
+
+            // a cell that is known to be free is a real gap.
+            let free = MapBuilder::new(20, 20).rect((5, 2), (5, 10), 100).cell((5, 6), 0).build();
+
+            assert_eq!(extract_groups_with(&free, occupied(3), 2, Connectivity::Eight, UnknownCells::Bridged, UnknownValues::default(), Encoding::Signed).len(), 2);
+        }
+
+        #[test]
+        fn the_preset_predicates_split_up_the_cells()
+        {
+            let map = MapBuilder::new(5, 1).cell((0, 0), -1).cell((0, 2), 30).cell((0, 3), 80).cell((0, 4), 100).build();
+
+            // the columns of the cells, in order.
+            let cells = |points: Points|
+            {
+                let mut cols: Vec<usize> = points.into_iter().map(|p| p.1).collect();
+                cols.sort();
+                cols
+            };
+
+            assert_eq!(cells(filter_map(&map, occupied(50))), vec![3, 4]);
+            assert_eq!(cells(filter_map(&map, free(50))), vec![1, 2]);
+            assert_eq!(cells(filter_map(&map, unknown())), vec![0]);
+        }
+
+        #[test]
+        fn the_try_variants_report_bad_maps()
+        {
+            let mut map = MapBuilder::new(2, 2).cell((0, 1), 100).build();
+
+            assert_eq!(try_filter_map(&map, occupied(3)).unwrap().len(), 1);
+            assert_eq!(try_extract_groups(&map, occupied(3), 2).unwrap().len(), 1);
+            assert_eq!(try_transform(&map, vec![(0, 0)]).unwrap().len(), 1);
+
+            map.info.resolution = 0.0;
+            assert_eq!(try_transform(&map, vec![(0, 0)]), Err(MapError::ZeroResolution));
+
+            map.data.pop();
+            assert_eq!(try_filter_map(&map, occupied(3)), Err(MapError::DataLengthMismatch));
+
+            // an empty map, e.g while `gmapping` is starting up.
+            let empty = MapBuilder::new(0, 3).build();
+            assert_eq!(try_extract_groups(&empty, occupied(3), 2), Err(MapError::BadDimensions));
+        }
+
+        #[test]
+        fn raytrace_stops_at_the_first_hit()
+        {
+            // a wall down column 10.
+            let map = MapBuilder::new(20, 10).rect((0, 10), (9, 10), 100).build();
+
+            assert_eq!(raytrace(&map, (2, 2), (8, 8), occupied(50)), None);
+            assert_eq!(raytrace(&map, (5, 2), (5, 18), occupied(50)), Some((5, 10)));
+            assert_eq!(raytrace(&map, (5, 18), (5, 2), occupied(50)), Some((5, 10)));
+            assert_eq!(raytrace(&map, (0, 0), (9, 19), occupied(50)).map(|hit| hit.1), Some(10));
+
+            // a line of one cell only checks that cell.
+            assert_eq!(raytrace(&map, (3, 3), (3, 3), occupied(50)), None);
+            assert_eq!(raytrace(&map, (3, 10), (3, 10), occupied(50)), Some((3, 10)));
+        }
+
+        #[test]
+        fn extract_groups_numbers_the_groups_the_same_every_time()
+        {
+            let map = MapBuilder::new(80, 60).ring((20, 20), 6.0, 100).rect((2, 40), (10, 50), 100).noise(0.05, 100, 9).build();
+
+            let first = extract_groups(&map, occupied(3), 3);
+
+            // the same cells, under the same group numbers.
+            for _ in 0..5
+            {
+                assert_eq!(extract_groups(&map, occupied(3), 3), first);
+            }
+
+            // the first group is the one with the first cell in row-major order.
+            let first_cell = first.values().flat_map(|points| points.iter().cloned()).min().unwrap();
+            assert!(first[&0].contains(&first_cell));
+
+            // and every cell is in exactly one group.
+            let total: usize = first.values().map(|points| points.len()).sum();
+            assert_eq!(total, filter_map(&map, occupied(3)).len());
+        }
+
+        #[test]
+        fn hysteresis_grows_strong_groups_through_weak_cells()
+        {
+            let map = MapBuilder::new(30, 30)
+            // a faint outline around a solid core.
+            .rect((9, 9), (13, 13), 30)
+            .rect((10, 10), (12, 12), 100)
+            // some faint noise on its own.
+            .cell((25, 25), 30)
+            .build();
+
+            let groups = extract_groups_hysteresis(&map, occupied(50), occupied(20), 2, Connectivity::Eight);
+
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[&0].len(), 25);
+            assert!(!groups[&0].contains(&(25, 25)));
+
+            // with only the strong threshold, the outline is lost.
+            assert_eq!(extract_groups(&map, occupied(50), 2)[&0].len(), 9);
+        }
+
+        #[test]
+        fn transform_packed_matches_transform()
+        {
+            let map = MapBuilder::new(30, 20).ring((10, 10), 5.0, 100).build();
+            let cells: Vec<Point> = filter_map(&map, occupied(50)).into_iter().collect();
+
+            let packed = transform_packed(&map, cells.clone());
+            let transformed: Vec<(Num, Num)> = transform(&map, cells.clone());
+
+            // `x`, `y` and `z` for each cell, in the same order.
+            assert_eq!(packed.len(), 3 * cells.len());
+
+            for (i, &(x, y)) in transformed.iter().enumerate()
+            {
+                assert_eq!(&packed[3 * i..3 * i + 3], &[x as f32, y as f32, 0.0]);
+            }
+        }
+
+        #[test]
+        fn neighbour_ring_is_the_edge_of_the_square()
+        {
+            for radius in 1..4
+            {
+                let ring = neighbour_ring((5, 5), radius);
+
+                assert_eq!(ring.len(), 8 * radius);
+
+                // every cell is exactly `radius` steps away.
+                assert!(ring.iter().all(|p| (p.0 as isize - 5).abs().max((p.1 as isize - 5).abs()) == radius as isize), "{:?}", ring);
+            }
+
+            // cut short by the edge of the map.
+            assert_eq!(neighbour_ring((0, 0), 1).len(), 3);
+            assert_eq!(neighbour_ring((0, 5), 2).len(), 9);
+
+            assert_eq!(neighbour_ring((3, 3), 0), [(3, 3)].iter().cloned().collect());
+        }
+
+        #[test]
+        fn morphology_cleans_up_the_groups()
+        {
+            let square = filter_map(&MapBuilder::new(30, 30).rect((10, 10), (14, 14), 100).build(), occupied(50));
+
+            assert_eq!(erode(&square, 2).len(), 9);
+            assert_eq!(dilate(&square, 2).len(), 49);
+
+            // opening gets rid of a speck of noise, and leaves the square.
+            let mut noisy = square.clone();
+            noisy.insert((2, 20));
+            assert_eq!(open(&noisy, 2), square);
+
+            // closing fills in a hole.
+            let mut holed = square.clone();
+            holed.remove(&(12, 12));
+            assert_eq!(close(&holed, 2), square);
+        }
+
+        #[test]
+        fn the_conventions_put_cells_where_their_docs_say()
+        {
+            let mut map = MapBuilder::new(10, 10).resolution(0.5).build();
+            map.info.origin.position.x = -2.5;
+            map.info.origin.position.y = -2.5;
+
+            assert_eq!(transform_in(&map, vec![(2, 7)], Convention::RobotCentric), vec![(1.0, 1.5)]);
+            assert_eq!(transform_in(&map, vec![(2, 7)], Convention::RosMapFrame), vec![(1.25, -1.25)]);
+
+            // `transform` is the default convention.
+            let default: Vec<(Num, Num)> = transform(&map, vec![(2, 7)]);
+            assert_eq!(default, transform_in(&map, vec![(2, 7)], Convention::default()));
+            assert_eq!(Convention::default(), Convention::RobotCentric);
+        }
+
+        #[test]
+        fn maps_with_no_cells_have_nothing_in_them()
+        {
+            for &(width, height) in [(0, 0), (0, 5), (5, 0)].iter()
+            {
+                let mut map = MapBuilder::new(width, height).build();
+
+                // whatever the data says.
+                map.data = vec![100; 5];
+
+                assert_eq!(validate_map(&map), Err(MapError::BadDimensions));
+                assert_eq!(try_extract_groups(&map, occupied(3), 2), Err(MapError::BadDimensions));
+
+                assert!(filter_map_context(&map, |v, _| v > 50).is_empty());
+                assert!(extract_groups(&map, occupied(3), 2).is_empty());
+            }
+        }
+
+        #[test]
+        fn streaming_the_groups_gives_the_same_table()
+        {
+            let map = MapBuilder::new(30, 30)
+                .ring((8, 8), 4.0, 100)
+                .rect((20, 3), (24, 12), 100)
+                .rect((2, 20), (2, 28), 100)
+                .cell((27, 27), 100)
+                .build();
+
+            let table = extract_groups(&map, occupied(3), 3);
+            let streamed: GroupTable = extract_groups_stream(&map, occupied(3), 3).enumerate().collect();
+
+            assert_eq!(table.len(), 4);
+            assert_eq!(streamed, table);
+
+            // and the first group is there without finding the rest.
+            let first = extract_groups_stream(&map, occupied(3), 3).next().unwrap();
+            assert_eq!(&first, &table[&0]);
+        }
+
+        #[test]
+        fn points_round_trip_through_rle()
+        {
+            let map = MapBuilder::new(5, 4).build();
+
+            // the last two cells of row 0 and the first of row 1 are next to
+            // each other in `map.data`, so they make one run.
+            let points: Points = vec![(0, 3), (0, 4), (1, 0), (2, 2), (3, 1), (3, 2), (3, 3)].into_iter().collect();
+
+            let runs = points_to_rle(&map, &points);
+
+            assert_eq!(runs, vec![(3, 3), (12, 1), (16, 3)]);
+            assert_eq!(rle_to_points(&map, &runs), points);
+
+            // points off the edge of the map are left out.
+            let mut outside = points.clone();
+            outside.insert((4, 0));
+            outside.insert((0, 5));
+
+            assert_eq!(points_to_rle(&map, &outside), runs);
+
+            // and so does every group of a real map.
+            let map = MapBuilder::new(30, 30).ring((15, 15), 8.0, 100).rect((2, 2), (6, 9), 100).build();
+
+            for (_, group) in extract_groups(&map, occupied(3), 3)
+            {
+                assert_eq!(rle_to_points(&map, &points_to_rle(&map, &group)), group);
+            }
+
+            assert!(points_to_rle(&map, &Points::default()).is_empty());
+        }
+
+        #[test]
+        fn world_bounds_reach_the_outer_edges_of_the_map()
+        {
+            let mut map = MapBuilder::new(10, 10).resolution(0.5).build();
+            map.info.origin.position.x = -2.0;
+            map.info.origin.position.y = 1.0;
+
+            assert_eq!(world_bounds(&map), ((-2.0, 1.0), (3.0, 6.0)));
+
+            // the centres of the corner cells are half a cell inside them.
+            let corners = transform_in(&map, vec![(0, 0), (9, 9)], Convention::RosMapFrame);
+            assert_eq!(corners, vec![(-1.75, 1.25), (2.75, 5.75)]);
+
+            // and a map that isn't square.
+            let map = MapBuilder::new(4, 2).resolution(0.25).build();
+            assert_eq!(world_bounds(&map), ((0.0, 0.0), (1.0, 0.5)));
+        }
+
+        #[test]
+        fn the_unknown_values_can_be_chosen()
+        {
+            let map = MapBuilder::new(6, 1)
+                .cell((0, 0), -1)
+                .cell((0, 1), -2)
+                .cell((0, 3), 30)
+                .cell((0, 4), 80)
+                .cell((0, 5), 100)
+                .build();
+
+            let cols = |points: Points|
+            {
+                let mut cols: Vec<usize> = points.into_iter().map(|p| p.1).collect();
+                cols.sort();
+                cols
+            };
+
+            // `-2` is unknown along with `-1`, just like by default.
+            let both = UnknownValues::only(&[-1, -2]);
+
+            assert_eq!(cols(filter_map(&map, unknown_in(both))), vec![0, 1]);
+            assert_eq!(cols(filter_map(&map, free_in(50, both))), vec![2, 3]);
+            assert_eq!(cols(filter_map(&map, occupied_in(50, both))), vec![4, 5]);
+            assert_eq!(cols(filter_map(&map, free(50))), vec![2, 3]);
+
+            // if only `-1` is unknown, then `-2` is just a (strange) value.
+            let only = UnknownValues::only(&[-1]);
+
+            assert_eq!(cols(filter_map(&map, unknown_in(only))), vec![0]);
+            assert_eq!(cols(filter_map(&map, free_in(50, only))), vec![1, 2, 3]);
+
+            // and the unknown values can even be positive.
+            let high = UnknownValues::only(&[-1, 100]);
+
+            assert_eq!(cols(filter_map(&map, occupied_in(50, high))), vec![4]);
+
+            // an obstacle with a gap of `-2` in it is only joined up if `-2` is
+            // unknown.
+            let gap = MapBuilder::new(5, 1).cell((0, 0), 100).cell((0, 1), 100).cell((0, 2), -2).cell((0, 3), 100).cell((0, 4), 100).build();
+
+            let groups = |unknowns| extract_groups_with(&gap, occupied_in(3, unknowns), 2, Connectivity::Eight, UnknownCells::Bridged, unknowns, Encoding::Signed).len();
+
+            assert_eq!(groups(both), 1);
+            assert_eq!(groups(only), 2);
+
+            // nor do unknown cells count towards the mean occupancy of a group.
+            let group = Group::with_unknowns(&gap, 0, (0..5).map(|col| (0, col)).collect(), both);
+            assert_eq!(group.mean_occupancy, 100.0);
+        }
+
+        #[test]
+        fn kernels_can_reach_further_along_one_axis()
+        {
+            // cells twice as tall as they are wide, so 10cm is two columns, but
+            // only one row.
+            let kernel = Kernel::for_radius(0.1, Resolution { x: 0.05, y: 0.1 });
+            assert_eq!(kernel, Kernel { rows: 2, cols: 3 });
+
+            let mut count = 0;
+            for_each_neighbour_in((5, 5), kernel, |_| count += 1);
+            assert_eq!(count, 3 * 5);
+
+            // two cells two columns apart are joined, but two cells two rows
+            // apart aren't.
+            let across = MapBuilder::new(10, 10).cell((5, 3), 100).cell((5, 5), 100).build();
+            let down = MapBuilder::new(10, 10).cell((3, 5), 100).cell((5, 5), 100).build();
+
+            assert_eq!(extract_groups_kernel(&across, occupied(3), kernel).len(), 1);
+            assert_eq!(extract_groups_kernel(&down, occupied(3), kernel).len(), 2);
+
+            // which a square kernel can't tell apart.
+            assert_eq!(extract_groups_kernel(&down, occupied(3), Kernel::square(3)).len(), 1);
+            assert_eq!(Kernel::square(3), Kernel { rows: 3, cols: 3 });
+        }
+
+        #[test]
+        fn top_n_groups_keeps_the_largest()
+        {
+            // groups of 1, 3, 2 and 3 cells.
+            let mut groups = GroupTable::default();
+
+            for (id, size) in [1, 3, 2, 3].iter().enumerate()
+            {
+                groups.insert(id, (0..*size).map(|col| (id, col)).collect());
+            }
+
+            let ids = |groups: &GroupTable|
+            {
+                let mut ids: Vec<GroupNumber> = groups.keys().cloned().collect();
+                ids.sort();
+                ids
+            };
+
+            // the two groups of 3 tie for first.
+            assert_eq!(ids(&top_n_groups(groups.clone(), 2)), vec![1, 3]);
+
+            // a tie for the last place goes to the lower id.
+            assert_eq!(ids(&top_n_groups(groups.clone(), 1)), vec![1]);
+
+            assert_eq!(ids(&top_n_groups(groups.clone(), 3)), vec![1, 2, 3]);
+
+            // asking for more than there are keeps them all, and none keeps none.
+            assert_eq!(top_n_groups(groups.clone(), 10), groups);
+            assert!(top_n_groups(groups, 0).is_empty());
+        }
+
+        #[test]
+        fn subtract_lines_the_maps_up_by_world_coordinates()
+        {
+            // the background is 10x10, and the map has since grown by two cells
+            // on every side, so its origin has moved two cells down and left.
+            let background = MapBuilder::new(10, 10).resolution(0.5).rect((0, 0), (0, 9), 100).build();
+
+            let mut map = MapBuilder::new(14, 14).resolution(0.5)
+                .rect((2, 2), (2, 11), 100)
+                .rect((6, 6), (7, 7), 100)
+                .cell((0, 0), 100)
+                .build();
+
+            map.info.origin.position.x = -1.0;
+            map.info.origin.position.y = -1.0;
+
+            let result = subtract(&map, &background).unwrap();
+
+            // the wall is gone, wherever it is in the new map, and the obstacle
+            // and the cell outside of the background are still there.
+            let mut left = filter_map(&result, occupied(3)).into_iter().collect::<Vec<Point>>();
+            left.sort();
+
+            assert_eq!(left, vec![(0, 0), (6, 6), (6, 7), (7, 6), (7, 7)]);
+            assert!((2..12).all(|col| result.data[index_of(&result, (2, col))] == -1));
+
+            // the same goes for `changed_cells`: the wall hasn't changed, and
+            // the cells outside of the old map count as unknown before.
+            let changed = changed_cells(&background, &map).unwrap();
+
+            assert!(!changed.contains(&(2, 5)));
+            assert!(changed.contains(&(6, 6)) && changed.contains(&(0, 0)) && changed.contains(&(13, 13)));
+        }
+
+        #[test]
+        fn cells_outside_the_roi_are_left_out()
+        {
+            // one obstacle outside the region, and one that straddles its edge.
+            let map = MapBuilder::new(10, 10)
+            .rect((1, 1), (2, 2), 100)
+            .rect((4, 4), (7, 7), 100)
+            .build();
+
+            // hangs off the right of the map.
+            let roi = ((3, 3), (5, 20));
+
+            let cells = filter_map_roi(&map, occupied(50), roi);
+
+            let expected: Points = (4..6).flat_map(|row| (4..8).map(move |col| (row, col))).collect();
+            assert_eq!(cells, expected);
+
+            // the straddling obstacle is cut off at the edge of the region.
+            let groups = extract_groups_roi(&map, occupied(50), 3, roi);
+            assert_eq!(groups.len(), 1);
+
+            assert_eq!(groups.values().next(), Some(&expected));
+
+            // a region entirely off the map has nothing in it.
+            assert!(filter_map_roi(&map, occupied(50), ((10, 10), (12, 12))).is_empty());
+        }
+
+        #[test]
+        fn filter_map_range_includes_both_ends()
+        {
+            let map = MapBuilder::new(6, 1)
+            .cell((0, 0), -1)
+            .cell((0, 1), 0)
+            .cell((0, 2), 1)
+            .cell((0, 3), 50)
+            .cell((0, 4), 99)
+            .cell((0, 5), 100)
+            .build();
+
+            let cols = |lo, hi|
+            {
+                let mut cols: Vec<usize> = filter_map_range(&map, lo, hi).iter().map(|&(_, col)| col).collect();
+                cols.sort();
+                cols
+            };
+
+            // neither known to be free nor known to be occupied.
+            assert_eq!(cols(1, 99), vec![2, 3, 4]);
+
+            // unknown cells only come in with a negative `lo`.
+            assert_eq!(cols(0, 100), vec![1, 2, 3, 4, 5]);
+            assert_eq!(cols(-1, 0), vec![0, 1]);
+
+            assert_eq!(cols(50, 50), vec![3]);
+            assert!(cols(60, 40).is_empty());
+        }
+
+        #[test]
+        fn describe_gives_the_size_resolution_and_origin()
+        {
+            let mut map = MapBuilder::new(384, 200).resolution(0.05).build();
+            map.info.origin = Pose2D { x: -10.0, y: 2.5, theta: 0.5 }.to_ros();
+
+            assert_eq!(describe(&map), "384x200 @ 0.05 m/cell, origin (-10.00,2.50,0.50)");
+
+            // an empty map still gets described, e.g while gmapping starts up.
+            assert_eq!(describe(&Map::default()), "0x0 @ 0 m/cell, origin (0.00,0.00,0.00)");
+        }
+
+        #[test]
+        fn the_transform_cache_works_each_geometry_out_once()
+        {
+            let map = MapBuilder::new(20, 10).resolution(0.1).cell((3, 4), 100).build();
+            let cache = TransformCache::new(2);
+
+            // including a point off the edge of the map.
+            let points = vec![(3, 4), (9, 19), (0, 0), (50, 50)];
+            let direct: Vec<(Num, Num)> = transform(&map, points.iter().cloned());
+
+            // asked for the same map from many threads at once, it's still
+            // only worked out once.
+            let all: Vec<Vec<(Num, Num)>> = (0..64).into_par_iter().map(|_| cache.transform(&map, points.iter().cloned())).collect();
+
+            assert!(all.iter().all(|xy| *xy == direct));
+            assert_eq!(cache.computed(), 1);
+
+            // the cells changing doesn't matter, only the geometry.
+            let changed = MapBuilder::new(20, 10).resolution(0.1).cell((5, 5), 100).build();
+            assert_eq!(cache.transform(&changed, points.iter().cloned()), direct);
+            assert_eq!(cache.computed(), 1);
+
+            let coarser = MapBuilder::new(20, 10).resolution(0.2).build();
+            cache.transform(&coarser, points.iter().cloned());
+            assert_eq!(cache.computed(), 2);
+
+            cache.transform(&map, points.iter().cloned());
+            assert_eq!(cache.computed(), 2);
+
+            // only the last two are remembered.
+            let bigger = MapBuilder::new(30, 10).resolution(0.1).build();
+            cache.transform(&bigger, points.iter().cloned());
+            cache.transform(&coarser, points.iter().cloned());
+            assert_eq!(cache.computed(), 4);
+        }
+
+        #[test]
+        fn corners_only_join_groups_with_eight_connectivity()
+        {
+            // two blocks that only touch at a corner, which also lies on the
+            // edge between two tiles.
+            let map = MapBuilder::new(8, 8)
+            .rect((2, 2), (3, 3), 100)
+            .rect((4, 4), (5, 5), 100)
+            .build();
+
+            let unknowns = UnknownValues::default();
+
+            for &(connectivity, expected) in [(Connectivity::Four, 2), (Connectivity::Eight, 1)].iter()
+            {
+                assert_eq!(extract_groups_hysteresis(&map, occupied(50), occupied(20), 2, connectivity).len(), expected);
+                assert_eq!(extract_groups_tiled(&map, occupied(50), 2, connectivity, 4).len(), expected);
+
+                for &unknown in [UnknownCells::Blocking, UnknownCells::Bridged].iter()
+                {
+                    assert_eq!(extract_groups_with(&map, occupied(50), 2, connectivity, unknown, unknowns, Encoding::Signed).len(), expected);
+                }
+            }
+
+            // an unknown cell on the diagonal between two cells only bridges
+            // them if diagonals are connected.
+            let gap = MapBuilder::new(8, 8)
+            .fill(0)
+            .cell((2, 2), 100)
+            .cell((3, 3), -1)
+            .cell((4, 4), 100)
+            .build();
+
+            let bridged = |connectivity| extract_groups_with(&gap, occupied(50), 2, connectivity, UnknownCells::Bridged, unknowns, Encoding::Signed).len();
+
+            assert_eq!(bridged(Connectivity::Four), 2);
+            assert_eq!(bridged(Connectivity::Eight), 1);
+        }
+
+        #[test]
+        fn erosion_treats_every_edge_of_the_map_the_same()
+        {
+            let map = MapBuilder::new(30, 30)
+            .rect((0, 0), (4, 4), 100)
+            .rect((25, 25), (29, 29), 100)
+            .build();
+
+            let blobs = filter_map(&map, occupied(50));
+            let eroded = erode(&blobs, 2);
+
+            // both corners lose a cell along each side that touches the edge.
+            let top_left: Points = (1..4).flat_map(|row| (1..4).map(move |col| (row, col))).collect();
+            let bottom_right: Points = (26..29).flat_map(|row| (26..29).map(move |col| (row, col))).collect();
+
+            assert_eq!(eroded, union(&top_left, &bottom_right));
+
+            // so opening shrinks them both back the same way.
+            let opened = open(&blobs, 2);
+            assert_eq!(opened.iter().filter(|p| p.0 < 15).count(), opened.iter().filter(|p| p.0 >= 15).count());
+        }
     }
 }
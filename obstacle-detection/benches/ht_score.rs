@@ -0,0 +1,58 @@
+//! Benchmarks for scoring a fit against point clouds of a few sizes.
+//!
+//! Run with `cargo bench -p obstacle-detection`.
+
+#[macro_use] extern crate criterion;
+extern crate common;
+extern crate obstacle_detection;
+
+use criterion::Criterion;
+use common::prelude::*;
+
+use map_utils::MapBuilder;
+use obstacle_detection::model3;
+
+// the radii of the rings to benchmark, in cells.
+const RADII: [usize; 3] = [5, 20, 80];
+
+// the points on a ring of the given radius, in map coordinates, along with
+// the centre of the ring.
+fn ring(radius: usize) -> (Vec<(Num, Num)>, (Num, Num))
+{
+    let size = (2 * radius + 5) as u32;
+    let centre = (radius + 2, radius + 2);
+
+    let map = MapBuilder::new(size, size)
+        .ring(centre, radius as Num, 100)
+        .build();
+
+    let points = map_utils::transform::<Num, _>(&map, map_utils::filter_map(&map, |v| v > 3));
+    let centre = map_utils::transform::<Num, _>(&map, vec![centre])[0];
+
+    (points, centre)
+}
+
+fn ht_score(c: &mut Criterion)
+{
+    c.bench_function_over_inputs("ht_score", |b, &radius|
+    {
+        let (points, (p, q)) = ring(radius);
+        let r = radius as Num * 0.05;
+
+        b.iter(|| model3::ht_score(&points, r, r, p, q, 0.0, 1, true));
+    }, RADII.to_vec());
+}
+
+fn ht_score_not_robust(c: &mut Criterion)
+{
+    c.bench_function_over_inputs("ht_score (not robust)", |b, &radius|
+    {
+        let (points, (p, q)) = ring(radius);
+        let r = radius as Num * 0.05;
+
+        b.iter(|| model3::ht_score(&points, r, r, p, q, 0.0, 1, false));
+    }, RADII.to_vec());
+}
+
+criterion_group!(benches, ht_score, ht_score_not_robust);
+criterion_main!(benches);
@@ -0,0 +1,149 @@
+//! Debugging output, for seeing what the node is up to in rviz.
+
+use ::common::prelude::*;
+
+use ::common::map_utils::{GroupNumber, GroupTable, Map};
+
+use ::common::msg::sensor_msgs::{PointCloud2, PointField};
+
+/// The size, in bytes, of each point in `group_cloud`: `x`, `y`, `z` and `rgb`,
+/// each of them an `f32`.
+pub const POINT_STEP: u32 = 16;
+
+/// Packs the cells of each group into a point cloud, so that rviz can show
+/// which cells ended up in which group. Each group gets its own colour.
+///
+/// The points are in the same frame as the detections, i.e relative to the
+/// robot's starting position, and lie on the floor (`z = 0`). The colour is
+/// packed into the `rgb` field as a float, which is how rviz expects it.
+pub fn group_cloud(map: &Map, groups: &GroupTable, frame_id: &str, stamp: rosrust::Time) -> PointCloud2
+{
+    // sorted, so that the same groups always come out the same way.
+    let mut ids: Vec<&GroupNumber> = groups.keys().collect();
+    ids.sort();
+
+    let mut data = Vec::new();
+
+    for id in ids
+    {
+        let rgb = pack_rgb(group_colour(*id));
+
+        let points: Vec<(f32, f32)> = map_utils::transform(map, groups[id].iter().cloned());
+
+        for (x, y) in points
+        {
+            for value in [x, y, 0.0, rgb].iter()
+            {
+                // little-endian, as given in `is_bigendian`.
+                let bits = value.to_bits();
+                data.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+            }
+        }
+    }
+
+    let width = data.len() as u32 / POINT_STEP;
+
+    let mut cloud = PointCloud2::default();
+
+    cloud.header.frame_id = frame_id.to_string();
+    cloud.header.stamp = stamp;
+    cloud.height = 1;
+    cloud.width = width;
+    cloud.fields = ["x", "y", "z", "rgb"].iter().enumerate()
+    .map(|(i, name)| PointField
+    {
+        name: name.to_string(),
+        offset: 4 * i as u32,
+        datatype: PointField::FLOAT32,
+        count: 1,
+    })
+    .collect();
+    cloud.is_bigendian = false;
+    cloud.point_step = POINT_STEP;
+    cloud.row_step = POINT_STEP * width;
+    cloud.data = data;
+    cloud.is_dense = true;
+
+    cloud
+}
+
+/// The colour of a group, as `(r, g, b)`.
+///
+/// Each id is a golden-ratio step further around the colour wheel than the
+/// last, so that groups with nearby ids (which are usually next to each other
+/// in the map) get colours that are easy to tell apart.
+pub fn group_colour(id: GroupNumber) -> (u8, u8, u8)
+{
+    let hue = (id as Num * 0.618_033_988_749_895).fract() * 6.0;
+
+    // fully saturated and bright; see "HSV to RGB" on Wikipedia.
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+
+    let (r, g, b) = match hue as u32
+    {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+
+    let byte = |c: Num| (c * 255.0).round() as u8;
+
+    (byte(r), byte(g), byte(b))
+}
+
+/// Packs a colour into a float, the way that rviz expects for the `rgb` field
+/// of a point cloud.
+pub fn pack_rgb((r, g, b): (u8, u8, u8)) -> f32
+{
+    f32::from_bits((r as u32) << 16 | (g as u32) << 8 | b as u32)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use ::common::map_utils::MapBuilder;
+
+    // the `i`th `f32` in the data of the cloud.
+    fn float_at(cloud: &PointCloud2, i: usize) -> f32
+    {
+        let b = &cloud.data[4 * i..4 * i + 4];
+
+        f32::from_bits(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+    }
+
+    #[test]
+    fn colours_are_packed_the_way_rviz_reads_them()
+    {
+        assert_eq!(pack_rgb((0x12, 0x34, 0x56)).to_bits(), 0x0012_3456);
+        assert_eq!(pack_rgb((255, 0, 0)).to_bits(), 0x00ff_0000);
+
+        // one group of one cell, and one of two.
+        let map = MapBuilder::new(10, 10).build();
+
+        let mut groups = GroupTable::default();
+        groups.insert(0, vec![(2, 7)].into_iter().collect());
+        groups.insert(1, vec![(5, 5), (5, 6)].into_iter().collect());
+
+        let cloud = group_cloud(&map, &groups, "map", rosrust::Time { sec: 1, nsec: 0 });
+
+        assert_eq!(cloud.width, 3);
+        assert_eq!(cloud.data.len(), 3 * POINT_STEP as usize);
+        assert_eq!(cloud.row_step, 3 * POINT_STEP);
+
+        // the groups come out in order, so the first point is the cell of
+        // group 0.
+        let xy: Vec<(f32, f32)> = map_utils::transform(&map, vec![(2, 7)]);
+
+        assert_eq!((float_at(&cloud, 0), float_at(&cloud, 1), float_at(&cloud, 2)), (xy[0].0, xy[0].1, 0.0));
+        assert_eq!(float_at(&cloud, 3).to_bits(), pack_rgb(group_colour(0)).to_bits());
+        assert_eq!(float_at(&cloud, 7).to_bits(), pack_rgb(group_colour(1)).to_bits());
+
+        // and neighbouring groups get different colours.
+        assert!(group_colour(0) != group_colour(1));
+    }
+}
@@ -0,0 +1,620 @@
+//! Finding the obstacles in a map.
+//!
+//! This is everything the node does with a map, minus the ROS plumbing: the
+//! node only has to hand each map to `process_map` and publish whatever comes
+//! back. Keeping it apart means it can be run on hand-made maps, without a
+//! master (see `tests/fixture.rs`).
+
+use ::common::prelude::*;
+
+use ::common::map_utils::
+{
+    Connectivity,
+    Encoding,
+    Group,
+    GroupTable,
+    Map,
+    TransformCache,
+    UnknownCells,
+    UnknownValues,
+    extract_groups_with,
+};
+
+use model3::{self, SearchSteps, Shape};
+use superellipse::SuperellipseParams;
+
+/// Settings that control which groups are considered to be obstacles.
+#[derive(Debug, Clone)]
+pub struct DetectionParams
+{
+    /// Groups whose bounding box has a diagonal longer than this (in metres)
+    /// are too big to be obstacles.
+    pub max_obstacle_size: Num,
+
+    /// Groups and shapes with an area smaller than this (in square metres) are
+    /// assumed to be noise. Both are measured the same way; see
+    /// `SuperellipseParams::area`.
+    pub min_area: Num,
+
+    /// Fits with a score worse than this are rejected, since the group doesn't
+    /// really look like any shape.
+    pub reject_threshold: Num,
+
+    /// How much better a more complicated shape has to fit than a simpler one
+    /// to be chosen; see `model3::classify`.
+    pub classification_margin: Num,
+
+    /// How much weight each new detection gets when smoothing the position of
+    /// a tracked obstacle, between 0 and 1.
+    pub track_smoothing: Num,
+
+    /// How far (in metres) a detection can be from a tracked obstacle and
+    /// still be considered the same obstacle.
+    pub track_max_distance: Num,
+
+    /// How many maps in a row an obstacle can be missing from before it is
+    /// no longer tracked.
+    pub track_max_missed: usize,
+
+    /// How the map data was published; see `map_utils::Encoding`.
+    pub encoding: Encoding,
+
+    /// The cell values that mean "unknown"; see `map_utils::UnknownValues`.
+    /// These are never part of an obstacle, and are what `bridge_unknown`
+    /// bridges.
+    pub unknown_values: UnknownValues,
+
+    /// Whether unknown cells can join up the pieces of an obstacle that
+    /// hasn't been fully seen yet; see `map_utils::UnknownCells`.
+    pub bridge_unknown: bool,
+
+    /// Whether to fit the shapes to just the outline of each group, rather
+    /// than every cell in it. The model describes the outline of a shape, so
+    /// the cells inside a solid group pull the fit in towards the middle.
+    pub perimeter_only: bool,
+
+    /// Two shapes whose centres are closer than this (in metres), and that
+    /// overlap by more than `duplicate_iou`, are taken to be the same
+    /// obstacle; see `model3::suppress_duplicates`.
+    pub duplicate_distance: Num,
+
+    /// See `duplicate_distance`.
+    pub duplicate_iou: Num,
+
+    /// Only the largest this many groups are classified; the rest are most
+    /// likely noise. Zero classifies every group.
+    pub max_groups: usize,
+
+    /// The smallest and largest sizes (in metres) that an obstacle can be, as
+    /// a radius or half the side of a rectangle; see
+    /// `model3::classify_with_prior`. `None` allows any size.
+    pub size_prior: Option<(Num, Num)>,
+}
+
+impl Default for DetectionParams
+{
+    fn default() -> Self
+    {
+        DetectionParams
+        {
+            max_obstacle_size: 2.5,
+            min_area: 0.0081,
+            reject_threshold: 0.1,
+            classification_margin: model3::DEFAULT_MARGIN,
+            track_smoothing: 0.5,
+            track_max_distance: 0.3,
+            track_max_missed: 5,
+            encoding: Encoding::Signed,
+            unknown_values: UnknownValues::default(),
+            bridge_unknown: false,
+            perimeter_only: false,
+            duplicate_distance: 0.2,
+            duplicate_iou: 0.1,
+            max_groups: 0,
+            size_prior: None,
+        }
+    }
+}
+
+impl DetectionParams
+{
+    /// Reads the detection settings from the parameter server. Anything that
+    /// isn't set keeps its default.
+    pub fn from_params() -> Self
+    {
+        let defaults = DetectionParams::default();
+
+        DetectionParams
+        {
+            max_obstacle_size: params::get_or::<Num>("~max_obstacle_size_m", defaults.max_obstacle_size),
+
+            min_area: params::get_or::<Num>("~min_area_m2", defaults.min_area),
+
+            reject_threshold: params::get_or::<Num>("~reject_threshold", defaults.reject_threshold),
+
+            classification_margin: params::get_or::<Num>("~classification_margin", defaults.classification_margin),
+
+            track_smoothing: params::get_or::<Num>("~track_smoothing", defaults.track_smoothing),
+
+            track_max_distance: params::get_or::<Num>("~track_max_distance_m", defaults.track_max_distance),
+
+            track_max_missed: params::get_or::<i32>("~track_max_missed", defaults.track_max_missed as i32).max(0) as usize,
+
+            encoding: if params::get_or::<bool>("~unsigned_occupancy", defaults.encoding == Encoding::Unsigned)
+            {
+                Encoding::Unsigned
+            }
+            else { Encoding::Signed },
+
+            // values that don't fit in a cell could never turn up anyway.
+            unknown_values: match params::get_or::<Vec<i32>>("~unknown_values", Vec::new())
+            {
+                ref values if values.is_empty() => defaults.unknown_values,
+                values => UnknownValues::only(&values.into_iter()
+                    .filter(|&v| v >= i8::min_value() as i32 && v <= i8::max_value() as i32)
+                    .map(|v| v as i8)
+                    .collect::<Vec<i8>>()),
+            },
+
+            bridge_unknown: params::get_or::<bool>("~bridge_unknown", defaults.bridge_unknown),
+
+            perimeter_only: params::get_or::<bool>("~fit_perimeter_only", defaults.perimeter_only),
+
+            duplicate_distance: params::get_or::<Num>("~duplicate_distance_m", defaults.duplicate_distance),
+
+            duplicate_iou: params::get_or::<Num>("~duplicate_iou", defaults.duplicate_iou),
+
+            max_groups: params::get_or::<i32>("~max_groups", defaults.max_groups as i32).max(0) as usize,
+
+            size_prior: size_prior(&params::get_or::<Vec<Num>>("~size_prior_m", Vec::new()), defaults.size_prior),
+        }
+    }
+}
+
+// the size prior given as `[min, max]`, or `default` if it isn't one. This is
+// kept apart from `from_params` since reading a parameter needs a running node.
+fn size_prior(values: &[Num], default: Option<(Num, Num)>) -> Option<(Num, Num)>
+{
+    match *values
+    {
+        [] => default,
+
+        // with the ends the wrong way round, no size would be allowed at all.
+        [min, max] if min <= max => Some((min, max)),
+
+        _ =>
+        {
+            println!("WARNING! ~size_prior_m should be [min, max] with min <= max, not {:?}. Ignoring it", values);
+            default
+        }
+    }
+}
+
+/// Finds the obstacles in the map.
+///
+/// The map should already have been checked with `map_utils::validate_map`,
+/// and have had the background (if any) subtracted. A map with no cells has no
+/// obstacles in it.
+///
+/// This is `find_groups` followed by `classify_groups`.
+pub fn process_map(map: &Map, params: &DetectionParams) -> Vec<Shape>
+{
+    classify_groups(map, &find_groups(map, params), params)
+}
+
+/// Splits the occupied cells of the map up into groups, each of which might be
+/// an obstacle. If `params.max_groups` is set, only that many of the largest
+/// groups are kept.
+pub fn find_groups(map: &Map, params: &DetectionParams) -> GroupTable
+{
+    if map.info.width == 0 || map.info.height == 0
+    {
+        return GroupTable::default();
+    }
+
+    let unknown = if params.bridge_unknown { UnknownCells::Bridged } else { UnknownCells::Blocking };
+
+    let occupied = map_utils::occupied_in(3, params.unknown_values);
+
+    let groups = extract_groups_with(map, occupied, 3, Connectivity::Eight, unknown, params.unknown_values, params.encoding);
+
+    if params.max_groups == 0 { groups } else { map_utils::top_n_groups(groups, params.max_groups) }
+}
+
+/// A shape found in the map, along with how many cells the group that it was
+/// fitted to had in it.
+#[derive(Debug)]
+pub struct Detection
+{
+    pub shape: Shape,
+    pub group_size: usize,
+
+    /// The index (in the same list of detections) of the shape that this one
+    /// lies inside, e.g the outside wall of a hollow obstacle; see
+    /// `model3::find_nested`. If there are several, it's the smallest.
+    pub inside: Option<usize>,
+}
+
+// how much more spread out a group has to be along its principal axis than
+// across it before the axis is trusted as a rotation seed; see
+// `map_utils::group_anisotropy`.
+const MIN_SEED_ANISOTROPY: Num = 1.5;
+
+// how much smaller a shape has to be than the one around it to count as
+// nested inside it, rather than being the same obstacle; see
+// `model3::find_nested`.
+const NESTED_RADIUS_RATIO: Num = 0.9;
+
+/// Works out which of the groups from `find_groups` are obstacles, and what
+/// shape they are.
+pub fn classify_groups(map: &Map, group_table: &GroupTable, params: &DetectionParams) -> Vec<Shape>
+{
+    let cache = TransformCache::new(1);
+
+    detect(map, group_table, params, &cache).into_iter().map(|d| d.shape).collect()
+}
+
+/// Like `classify_groups`, but keeps the size of the group that each shape came
+/// from, e.g for logging. The coordinates of the cells are looked up in
+/// `cache`, which can be kept from one map to the next; see `classify_group`.
+pub fn detect(map: &Map, group_table: &GroupTable, params: &DetectionParams, cache: &TransformCache) -> Vec<Detection>
+{
+    // each group can be classified on its own, and that's where nearly all of
+    // the time goes, so the groups are shared out across threads. `ht_score`
+    // is itself parallel, but rayon just runs those inner jobs on whichever
+    // threads are free, so this doesn't oversubscribe the pool (set its size
+    // with `~threads`). The biggest groups still come first in the output,
+    // since those are the most likely to be real obstacles.
+    let groups: Vec<Group> = map_utils::groups_by_size_desc(group_table)
+    .into_par_iter()
+    .map(|(id, items)| Group::with_unknowns(map, id, items.clone(), params.unknown_values))
+    .collect();
+
+    // the walls of the arena surround everything else, which can't be seen
+    // from any one group on its own.
+    let border = map_utils::arena_border_groups(map, &groups);
+
+    let (shapes, sizes): (Vec<Shape>, Vec<usize>) = groups
+    .into_par_iter()
+    .filter(|group| !border.contains(&group.id))
+    .filter_map(|group| classify_group(map, &group, params, cache).map(|shape| (shape, group.points.len())))
+    .unzip();
+
+    // a hollow obstacle can show up as a ring inside a ring. The two overlap,
+    // so they would otherwise be taken for duplicates, and only one kept.
+    let nested = model3::find_nested(&shapes, NESTED_RADIUS_RATIO);
+
+    for nesting in nested.iter()
+    {
+        println!("Found a shape nested inside another: {} is inside {}", shapes[nesting.inner], shapes[nesting.outer]);
+    }
+
+    let keep = model3::non_duplicates_except(&shapes, params.duplicate_distance, params.duplicate_iou, &nested);
+
+    // where each of the shapes that are kept ends up in the output.
+    let mut index = vec![None; shapes.len()];
+    let mut kept = 0;
+
+    for (i, &keep) in keep.iter().enumerate()
+    {
+        if keep
+        {
+            index[i] = Some(kept);
+            kept += 1;
+        }
+    }
+
+    let inside = |inner: usize| nested.iter()
+    .filter(|n| n.inner == inner && keep[n.outer])
+    .min_by(|a, b| shapes[a.outer].enclosing_radius().partial_cmp(&shapes[b.outer].enclosing_radius()).unwrap_or(::std::cmp::Ordering::Equal))
+    .and_then(|n| index[n.outer]);
+
+    let inside: Vec<Option<usize>> = (0..shapes.len()).map(inside).collect();
+
+    shapes.into_iter().zip(sizes.into_iter()).zip(inside.into_iter()).zip(keep.into_iter())
+    .filter(|&(_, keep)| keep)
+    .map(|(((shape, group_size), inside), _)| Detection { shape, group_size, inside })
+    .collect()
+}
+
+/// Works out which shape (if any) a single group of cells makes up, or `None`
+/// if it isn't an obstacle, e.g because it's noise, or part of the walls.
+///
+/// This is what `process_map` does with each group. The coordinates of the
+/// cells are looked up in `cache`, since the geometry of the map rarely
+/// changes from one map to the next.
+pub fn classify_group(map: &Map, group: &Group, params: &DetectionParams, cache: &TransformCache) -> Option<Shape>
+{
+    let items = &group.points;
+
+    if items.len() == 0
+    {
+        println!("Skipped a group that contained zero elements! (This should never happen).");
+        return None;
+    }
+
+    // the walls of the arena can reach the edge of the map; those that don't
+    // are found by `detect`, which can see the other groups.
+    if map_utils::is_arena_border(items, map)
+    {
+        return None;
+    }
+
+    let ((xmin, ymin), (xmax, ymax)) = group.bbox;
+
+    let box_size = (xmax - xmin).hypot(ymax - ymin);
+
+    if box_size > params.max_obstacle_size
+    {
+        println!("Skipped a group that is too big to be an obstacle ({:.3}m across)", box_size);
+        return None;
+    }
+
+    // estimate the orientation of the group, so that the rectangle search
+    // doesn't have to check every rotation. A group that spreads out about as
+    // far one way as the other (e.g a square, however it's turned) doesn't
+    // have a meaningful principal axis, so that has to check them all anyway.
+    let rotation_seed = if map_utils::group_anisotropy(map, items) >= MIN_SEED_ANISOTROPY
+    {
+        Some(map_utils::group_principal_axis(map, items))
+    }
+    else { None };
+
+    let perimeter;
+
+    let items = if params.perimeter_only
+    {
+        perimeter = map_utils::group_perimeter_cells(map, items);
+        &perimeter
+    }
+    else { items };
+
+    // transform the items into xy, relative to the robot
+    // starting position.
+    let items = cache.transform(map, items.iter().cloned());
+
+    let (start, a, b) = model3::group_seed(group, &items, rotation_seed);
+
+    // `a` and `b` are half of the sides of the box. Every shape that could be
+    // fitted to it is at least as big as the ellipse inside it, so if that's
+    // too small, the shape would be thrown away below anyway.
+    let ellipse = SuperellipseParams { a, b, p: start.0, q: start.1, t: 0.0, s: 1.0 };
+
+    if ellipse.area() < params.min_area
+    {
+        // assuming it's noise and quietly continuing.
+        return None;
+    }
+
+    // search about as finely as the map is detailed.
+    let steps = SearchSteps::for_resolution(map.info.resolution as Num);
+
+    let classification = model3::classify_with_prior(
+        &items,
+        start,
+        a,
+        b,
+        rotation_seed,
+        params.reject_threshold,
+        params.classification_margin,
+        steps,
+        params.size_prior,
+    );
+
+    let shape = classification.best;
+
+    println!("{} (candidates: {:?})", shape, classification.candidates);
+
+    if let Shape::Unknown { score } = shape
+    {
+        println!("Skipped a group that doesn't look like any shape (best score: {:.4})", score);
+        return None;
+    }
+
+    if shape.area() < params.min_area
+    {
+        println!("Skipped a shape that is too small to be an obstacle ({:.4}m^2)", shape.area());
+        return None;
+    }
+
+    Some(shape)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use ::common::map_utils::MapBuilder;
+
+    // the outline of the cells between two corners, like `MapBuilder::rect`.
+    fn outline(map: MapBuilder, (top, left): (usize, usize), (bottom, right): (usize, usize)) -> MapBuilder
+    {
+        map.rect((top, left), (top, right), 100)
+        .rect((bottom, left), (bottom, right), 100)
+        .rect((top, left), (bottom, left), 100)
+        .rect((top, right), (bottom, right), 100)
+    }
+
+    #[test]
+    fn walls_are_rejected_but_big_obstacles_are_not()
+    {
+        // a 1.8m by 0.6m box, which is bigger across than the walls of the
+        // arena used to be allowed to be.
+        let obstacle = |map| outline(map, (44, 32), (55, 67));
+
+        // the walls are well inside the map, like in the maps from `gmapping`.
+        let walled = outline(obstacle(MapBuilder::new(100, 100)), (5, 5), (94, 94)).build();
+
+        let mut params = DetectionParams::default();
+
+        let shapes = process_map(&walled, &params);
+
+        assert_eq!(shapes.len(), 1, "{:?}", shapes);
+        assert!(match shapes[0] { Shape::Rectle(_) => true, _ => false }, "{:?}", shapes);
+
+        // the walls are thrown away for surrounding the obstacle, not for being
+        // too big.
+        params.max_obstacle_size = 100.0;
+
+        assert_eq!(process_map(&walled, &params).len(), 1);
+
+        // and an obstacle on its own doesn't surround anything.
+        let alone = obstacle(MapBuilder::new(100, 100)).build();
+
+        assert_eq!(process_map(&alone, &params).len(), 1);
+    }
+
+    #[test]
+    fn classifying_in_parallel_gives_the_same_shapes_as_one_at_a_time()
+    {
+        let circles = MapBuilder::new(60, 60)
+        .ring((15, 15), 5.0, 100)
+        .ring((45, 12), 4.0, 100);
+
+        let map = outline(circles, (36, 30), (43, 41)).build();
+
+        let params = DetectionParams::default();
+
+        let parallel = process_map(&map, &params);
+
+        let serial: Vec<Shape> = map_utils::groups_by_size_desc(&find_groups(&map, &params))
+        .into_iter()
+        .filter_map(|(id, items)| classify_group(&map, &Group::with_unknowns(&map, id, items.clone(), params.unknown_values), &params, &TransformCache::new(1)))
+        .collect();
+
+        assert_eq!(parallel.len(), 3, "{:?}", parallel);
+
+        // the scores are summed in the same order on any number of threads, so
+        // the shapes are exactly the same.
+        assert_eq!(format!("{:?}", parallel), format!("{:?}", serial));
+    }
+
+    #[test]
+    fn maps_with_no_cells_have_no_obstacles()
+    {
+        for &(width, height) in [(0, 0), (0, 5), (5, 0)].iter()
+        {
+            let map = MapBuilder::new(width, height).build();
+
+            assert!(find_groups(&map, &DetectionParams::default()).is_empty());
+            assert!(process_map(&map, &DetectionParams::default()).is_empty());
+        }
+    }
+
+    #[test]
+    fn solid_obstacles_fit_better_by_their_outline()
+    {
+        // a solid 60cm square; the centres of the cells along its edges are
+        // 55cm apart.
+        let map = MapBuilder::new(60, 60).rect((20, 20), (31, 31), 100).build();
+
+        let mut params = DetectionParams::default();
+
+        let fit = |params: &DetectionParams|
+        {
+            let shapes = process_map(&map, params);
+            assert_eq!(shapes.len(), 1, "{:?}", shapes);
+
+            match shapes[0]
+            {
+                Shape::Rectle(ref r) => ((2.0 * r.width, 2.0 * r.length), r.score),
+                // the solid square fits a circle about as badly as anything else.
+                Shape::Circle(ref c) => ((2.0 * c.radius, 2.0 * c.radius), c.score),
+                ref shape => panic!("not a rectangle or circle: {}", shape),
+            }
+        };
+
+        params.perimeter_only = true;
+        let (outline, outline_score) = fit(&params);
+
+        assert!((outline.0 - 0.55).abs() < 0.025 && (outline.1 - 0.55).abs() < 0.025, "{:?}", outline);
+
+        // the cells inside pull the fit in from the edges, so much so that
+        // it doesn't pass for any shape at all.
+        params.perimeter_only = false;
+        assert!(process_map(&map, &params).is_empty());
+
+        params.reject_threshold = 1.0;
+        let (solid, solid_score) = fit(&params);
+
+        let error = |(a, b): (Num, Num)| (a - 0.55).abs() + (b - 0.55).abs();
+
+        assert!(outline_score < solid_score, "{} vs {}", outline_score, solid_score);
+        assert!(error(outline) < error(solid), "{:?} vs {:?}", outline, solid);
+    }
+
+    #[test]
+    fn both_walls_of_a_hollow_obstacle_are_kept()
+    {
+        // a ring inside a ring, and another obstacle off to the side, so that
+        // the outer ring doesn't surround everything like the walls would.
+        let map = MapBuilder::new(60, 60)
+        .ring((20, 20), 9.0, 100)
+        .ring((20, 20), 4.0, 100)
+        .ring((45, 45), 6.0, 100)
+        .build();
+
+        let params = DetectionParams::default();
+
+        let found = detect(&map, &find_groups(&map, &params), &params, &TransformCache::new(1));
+        assert_eq!(found.len(), 3, "{:?}", found);
+
+        let radius = |detection: &Detection| match detection.shape
+        {
+            Shape::Circle(ref c) => c.radius,
+            ref shape => panic!("not a circle: {}", shape),
+        };
+
+        let outer = found.iter().position(|d| (radius(d) - 0.45).abs() < 0.03).expect("no outer ring");
+        let inner = found.iter().position(|d| (radius(d) - 0.2).abs() < 0.03).expect("no inner ring");
+
+        assert_eq!(found[inner].inside, Some(outer));
+        assert!(found.iter().enumerate().filter(|&(i, _)| i != inner).all(|(_, d)| d.inside.is_none()), "{:?}", found);
+
+        // they overlap enough that, nesting aside, one would be thrown away.
+        let shapes: Vec<Shape> = found.into_iter().map(|d| d.shape).collect();
+        let keep = model3::non_duplicates(&shapes, params.duplicate_distance, params.duplicate_iou);
+
+        assert_eq!(keep.iter().filter(|&&keep| keep).count(), 2);
+    }
+
+    #[test]
+    fn the_cell_coordinates_are_worked_out_once_per_geometry()
+    {
+        let circles = MapBuilder::new(60, 60)
+        .ring((15, 15), 5.0, 100)
+        .ring((45, 12), 4.0, 100);
+
+        let map = outline(circles, (36, 30), (43, 41)).build();
+
+        let params = DetectionParams::default();
+        let groups = find_groups(&map, &params);
+
+        // the groups are classified in parallel, but share the one table.
+        let cache = TransformCache::new(1);
+
+        let first = detect(&map, &groups, &params, &cache);
+        let second = detect(&map, &groups, &params, &cache);
+
+        assert_eq!(first.len(), 3);
+        assert_eq!(format!("{:?}", first), format!("{:?}", second));
+        assert_eq!(cache.computed(), 1);
+    }
+
+    #[test]
+    fn the_size_prior_has_to_be_min_then_max()
+    {
+        assert_eq!(size_prior(&[0.1, 0.3], None), Some((0.1, 0.3)));
+        assert_eq!(size_prior(&[0.2, 0.2], None), Some((0.2, 0.2)));
+
+        // anything else keeps the default.
+        let default = Some((0.05, 1.0));
+
+        assert_eq!(size_prior(&[], default), default);
+        assert_eq!(size_prior(&[0.3, 0.1], default), default);
+        assert_eq!(size_prior(&[0.1], None), None);
+        assert_eq!(size_prior(&[0.1, 0.2, 0.3], None), None);
+        assert_eq!(size_prior(&[0.1, ::std::f64::NAN], None), None);
+    }
+}
@@ -1,40 +1,78 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use rayon::prelude::*;
+use ::common::prelude::*;
 
-pub type Num = f64;
+use superellipse::SuperellipseParams;
 
 type Point  = (Num, Num);
 type Points = Vec<Point>;
 
 
-#[derive(Debug)]
-pub struct Model
+/// The result of a single step of gradient descent; see `Model::fit`.
+#[derive(Debug, Clone, Copy)]
+pub struct Step
 {
-    pub a: Num,
-    pub b: Num,
-    pub p: Num,
-    pub q: Num,
-    pub s: Num,
-    pub t: Num,
+    /// The loss before the step was taken.
+    pub loss_before: Num,
+
+    /// The loss after the step was taken.
+    pub loss_after: Num,
+
+    /// The size (L2 norm) of the gradient that the step was taken along.
+    pub gradient_norm: Num,
+
+    /// The learning rate that the line search settled on, or zero if no step
+    /// improved the fit.
+    pub step_size: Num,
 }
 
-impl Model
+/// How many times the line search halves the step before giving up.
+const MAX_HALVINGS: usize = 30;
+
+/// The biggest update that `Model::fit` will make to the parameters in one
+/// step.
+pub const DEFAULT_MAX_UPDATE: Num = 1.0;
+
+/// The model is just its parameters; the derivatives etc are added on here.
+pub type Model = SuperellipseParams;
+
+impl SuperellipseParams
 {
     pub fn M(&self, x: Num, y: Num) -> Num
     {
         self.X(x, y) + self.Y(x, y) - 1.0
     }
 
+    /// Evaluates the model at `(x, y)`, i.e `M`.
+    ///
+    /// This is negative inside the shape, zero on its boundary, and positive
+    /// outside it, so it can be used e.g as a cost function for keeping away
+    /// from an obstacle. Note that it is not the actual distance to the
+    /// boundary; it grows like `(distance/a)^(2*sharpness())`, so it gets
+    /// steep quickly outside of sharp-cornered shapes.
+    pub fn signed_field(&self, x: Num, y: Num) -> Num
+    {
+        self.M(x, y)
+    }
+
+    // the power that `A` and `B` are raised to. This is twice the rounded
+    // sharpness, like in `model3` and `model`, so that all of them agree on
+    // the shape when `s` isn't a whole number (rounding `2s` instead would
+    // give an odd power, and the field would go negative on one side).
+    fn power(&self) -> i32
+    {
+        2 * self.sharpness()
+    }
+
     fn X(&self, x: Num, y: Num) -> Num
     {
-        self.A(x, y).powi((2.0*self.s).round() as i32)
+        self.A(x, y).powi(self.power())
     }
 
     fn Y(&self, x: Num, y: Num) -> Num
     {
-        self.B(x, y).powi((2.0*self.s).round() as i32)
+        self.B(x, y).powi(self.power())
     }
 
     fn A(&self, x: Num, y: Num) -> Num
@@ -79,7 +117,7 @@ impl Model
 
     fn dXdA(&self, x: Num, y: Num) -> Num
     {
-        2.0 * self.s * self.A(x,y).powi((2.0 * self.s) as i32 - 1)
+        self.power() as Num * self.A(x,y).powi(self.power() - 1)
     }
 
     fn dAda(&self, x: Num, y: Num) -> Num
@@ -109,7 +147,7 @@ impl Model
 
     fn dYdB(&self, x: Num, y: Num) -> Num
     {
-        2.0 * self.s * self.B(x,y).powi((2.0 * self.s) as i32 - 1)
+        self.power() as Num * self.B(x,y).powi(self.power() - 1)
     }
 
     fn dBdb(&self, x: Num, y: Num) -> Num
@@ -159,7 +197,7 @@ impl Model
 
     fn dCdp(&self) -> Num
     {
-        self.dGdp() + self.dFdp()
+        self.dGdp() * self.t.cos() - self.dFdp() * self.t.sin()
     }
 
     pub fn dMdq(&self, x: Num, y: Num) -> Num
@@ -194,7 +232,7 @@ impl Model
 
     fn dYdq(&self, x: Num, y: Num) -> Num
     {
-        self.dYdb(x,y) * self.dBdq()
+        self.dYdB(x,y) * self.dBdq()
     }
 
     fn dBdq(&self) -> Num
@@ -212,14 +250,18 @@ impl Model
         self.dXds(x,y) + self.dYds(x,y)
     }
 
+    // `X` goes to zero faster than `ln(A^2)` blows up, so on the axis (where
+    // `A` is zero) the derivative is zero rather than `0 * -inf`.
     fn dXds(&self, x: Num, y: Num) -> Num
     {
-        self.A(x,y).powi(2).ln() * self.X(x,y)
+        let X = self.X(x,y);
+        if X == 0.0 { 0.0 } else { self.A(x,y).powi(2).ln() * X }
     }
 
     fn dYds(&self, x: Num, y: Num) -> Num
     {
-        self.B(x,y).powi(2).ln() * self.Y(x,y)
+        let Y = self.Y(x,y);
+        if Y == 0.0 { 0.0 } else { self.B(x,y).powi(2).ln() * Y }
     }
 
     pub fn dMdt(&self, x: Num, y: Num) -> Num
@@ -254,7 +296,7 @@ impl Model
 
     fn dCdt(&self, x: Num, y: Num) -> Num
     {
-        self.G(y) * -self.t.sin() - self.F(x) * -self.t.cos()
+        self.G(y) * -self.t.sin() - self.F(x) * self.t.cos()
     }
 
     // pub fn fit(&mut self, points: &Points, gamma: Num)
@@ -289,12 +331,73 @@ impl Model
     //     self.t -= gamma*dJdt;
     // }
 
-    pub fn fit(&mut self, points: &Points, gamma: Num)
+    /// Does a single step of gradient descent.
+    ///
+    /// A fixed learning rate is either too big (and the fit diverges) or too
+    /// small (and it crawls), so this does a backtracking line search instead:
+    /// `gamma` is the biggest step to try, and it is halved until the loss
+    /// actually decreases. If it never does, the model is left as-is and the
+    /// step size is reported as zero.
+    ///
+    /// The update is clipped to `DEFAULT_MAX_UPDATE`; see `fit_clipped`.
+    pub fn fit(&mut self, points: &Points, gamma: Num) -> Step
     {
-        let step = 0.001;
+        self.fit_clipped(points, gamma, DEFAULT_MAX_UPDATE)
+    }
 
+    /// Like `fit`, but the update to the parameters is clipped so that its
+    /// size (L2 norm) is at most `max_update`.
+    ///
+    /// A point near the centre of the shape can make the gradient enormous,
+    /// and without clipping, a single step can fling the parameters off to
+    /// somewhere meaningless.
+    pub fn fit_clipped(&mut self, points: &Points, gamma: Num, max_update: Num) -> Step
+    {
         let current_loss = self.loss(points);
 
+        let (dJda, dJdb, dJdp, dJdq, dJdt) = self.gradient(points, current_loss);
+        let gradient_norm = (dJda.powi(2) + dJdb.powi(2) + dJdp.powi(2) + dJdq.powi(2) + dJdt.powi(2)).sqrt();
+
+        let start = (self.a, self.b, self.p, self.q, self.t);
+        let mut gamma = gamma;
+
+        for _ in 0..MAX_HALVINGS
+        {
+            // shrink the update if it's too big, keeping its direction.
+            let size = gamma * gradient_norm;
+            let scale = if size > max_update { gamma * max_update / size } else { gamma };
+
+            self.a = start.0 - scale * dJda;
+            self.b = start.1 - scale * dJdb;
+            self.p = start.2 - scale * dJdp;
+            self.q = start.3 - scale * dJdq;
+            self.t = start.4 - scale * dJdt;
+
+            let loss = self.loss(points);
+
+            if loss < current_loss
+            {
+                return Step { loss_before: current_loss, loss_after: loss, gradient_norm, step_size: gamma };
+            }
+
+            gamma /= 2.0;
+        }
+
+        // couldn't find a step that helps, so put everything back.
+        self.a = start.0;
+        self.b = start.1;
+        self.p = start.2;
+        self.q = start.3;
+        self.t = start.4;
+
+        Step { loss_before: current_loss, loss_after: current_loss, gradient_norm, step_size: 0.0 }
+    }
+
+    // the gradient of the loss, by finite differences.
+    fn gradient(&mut self, points: &Points, current_loss: Num) -> (Num, Num, Num, Num, Num)
+    {
+        let step = 0.001;
+
         self.a += step;
         let dJda = (self.loss(points) - current_loss) / step;
         self.a -= step;
@@ -315,24 +418,116 @@ impl Model
         let dJdt = (self.loss(points) - current_loss) / step;
         self.t -= step;
 
-        println!("Changes: {:?}", (gamma*dJda, gamma*dJdb, gamma*dJdp, gamma*dJdq, gamma*dJdt));
+        (dJda, dJdb, dJdp, dJdq, dJdt)
+    }
+
+    /// Runs gradient descent until the gradient norm drops below `tol`, until
+    /// the line search can't find a step that improves the fit, or until
+    /// `max_iters` steps have been taken.
+    ///
+    /// Returns every step that was taken, so the caller can tell whether (and
+    /// how quickly) the fit converged.
+    pub fn fit_until(&mut self, points: &Points, gamma: Num, tol: Num, max_iters: usize) -> Vec<Step>
+    {
+        let mut steps = Vec::new();
 
-        self.a -= gamma * dJda;
-        self.b -= gamma * dJdb;
-        self.p -= gamma * dJdp;
-        self.q -= gamma * dJdq;
-        self.t -= gamma * dJdt;
+        while steps.len() < max_iters
+        {
+            let step = self.fit(points, gamma);
+            steps.push(step);
+
+            if step.gradient_norm < tol || step.step_size == 0.0 { break; }
+        }
+
+        steps
     }
 
 
     pub fn loss(&self, points: &Points) -> Num
     {
-        points.par_iter().map(|p|
+        points.par_iter().map(|p| 0.5 * self.M(p.0, p.1).powi(2)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn signed_field_is_negative_inside()
+    {
+        let model = Model { a: 1.0, b: 0.5, p: 2.0, q: 1.0, t: 0.0, s: 1.0 };
+
+        assert!(model.signed_field(2.0, 1.0) < 0.0);
+        assert!(model.signed_field(3.0, 1.0).abs() < 1e-12);
+        assert!(model.signed_field(2.0, 1.5).abs() < 1e-12);
+        assert!(model.signed_field(4.0, 1.0) > 0.0);
+    }
+
+    // points on a circle of radius 1 about the origin.
+    fn unit_circle(n: usize) -> Points
+    {
+        (0..n).map(|i|
         {
-            let ji = 0.5 * self.M(p.0, p.1).powi(2);
-            println!("point: {:?} loss: {}", p, ji);
-            ji
+            let angle = i as Num * 2.0 * ::std::f64::consts::PI / n as Num;
+            (angle.cos(), angle.sin())
         })
-        .sum()
+        .collect()
+    }
+
+    #[test]
+    fn fit_until_stops_when_it_converges()
+    {
+        let points = unit_circle(50);
+        let mut model = Model { a: 1.3, b: 0.8, p: 0.1, q: -0.1, t: 0.0, s: 1.0 };
+
+        let steps = model.fit_until(&points, 0.1, 1e-3, 1000);
+
+        assert!(steps.len() < 1000, "didn't converge");
+
+        // the loss never goes up, and each step starts where the last left off.
+        assert!(steps.iter().all(|step| step.loss_after <= step.loss_before));
+        assert!(steps.windows(2).all(|w| (w[1].loss_before - w[0].loss_after).abs() < 1e-12));
+
+        let last = steps.last().unwrap();
+        assert!(last.gradient_norm < 1e-3 || last.step_size == 0.0, "{:?}", last);
+        assert!(model.loss(&points) < 1e-3, "{:?}", model);
+
+        // and it stops after `max_iters` even if it hasn't converged.
+        let mut model = Model { a: 1.3, b: 0.8, p: 0.1, q: -0.1, t: 0.0, s: 1.0 };
+        assert_eq!(model.fit_until(&points, 0.001, 0.0, 5).len(), 5);
+    }
+
+    #[test]
+    fn the_line_search_never_makes_the_fit_worse()
+    {
+        let points = unit_circle(50);
+        let mut model = Model { a: 1.3, b: 0.8, p: 0.1, q: -0.1, t: 0.0, s: 1.0 };
+
+        let steps = model.fit_until(&points, 10.0, 1e-3, 200);
+
+        assert!(steps.iter().all(|step| step.loss_after <= step.loss_before && step.step_size <= 10.0));
+        assert!(steps.iter().any(|step| step.step_size < 10.0), "the step was never cut back");
+        assert!(model.loss(&points) < 1e-3, "{:?}", model);
+    }
+
+    #[test]
+    fn fit_clipped_limits_the_size_of_the_update()
+    {
+        // a point right by the centre makes the gradient enormous.
+        let mut points = unit_circle(50);
+        points.push((0.001, 0.0));
+
+        let mut model = Model { a: 0.2, b: 0.2, p: 0.0, q: 0.0, t: 0.0, s: 3.0 };
+        let before = model;
+
+        let step = model.fit_clipped(&points, 1000.0, 0.5);
+
+        let update = ((model.a - before.a).powi(2) + (model.b - before.b).powi(2) + (model.p - before.p).powi(2)
+            + (model.q - before.q).powi(2) + (model.t - before.t).powi(2)).sqrt();
+
+        assert!(update <= 0.5 + 1e-9, "{} {:?}", update, step);
+        assert!(step.loss_after <= step.loss_before);
     }
 }
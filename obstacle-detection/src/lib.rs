@@ -0,0 +1,37 @@
+//! The obstacle detection logic, kept apart from the node itself so that it
+//! can be benchmarked (see `benches/`) and reused.
+
+extern crate common;
+
+/// The model for finding shapes.
+pub mod model3;
+
+/// The parameters of the model, shared by `model3` and `diff2`.
+pub mod superellipse;
+
+/// The derivatives of the model, for refining a fit by gradient descent.
+pub mod diff2;
+
+/// Refining a fit by gradient descent, in either `f32` or `f64`.
+pub mod model;
+
+/// Watchdog for complaining when no map arrives.
+pub mod watchdog;
+
+/// Tracking of obstacles across maps.
+pub mod tracker;
+
+/// Finding the obstacles in a map.
+pub mod detection;
+
+/// Skipping maps that were already processed.
+pub mod recent;
+
+/// Classifying points on request, for the `classify_points` service.
+pub mod service;
+
+/// Debugging output for rviz.
+pub mod debug;
+
+/// Logging the detections to a file.
+pub mod logger;
@@ -0,0 +1,159 @@
+//! Recording the detections to a file, for looking over after a run.
+//!
+//! Each detection is one row of a CSV file, so the log can be loaded straight
+//! into a spreadsheet (or `pandas`) when tuning the parameters.
+
+use ::common::prelude::*;
+
+use std::f64::NAN;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use detection::Detection;
+use model3::Shape;
+
+/// The first line of the log, naming the columns of each row.
+pub const HEADER: &str = "stamp,topic,kind,x,y,width,length,rotation,sharpness,score,group_size,inside";
+
+/// Appends a row to a CSV file for each detection; see `HEADER` for the
+/// columns.
+///
+/// Rows are buffered until `flush`, so that logging each detection doesn't
+/// hold up the callback; the callback flushes once it has logged them all.
+pub struct DetectionLogger
+{
+    writer: BufWriter<File>,
+}
+
+impl DetectionLogger
+{
+    /// Opens the log at `path`, adding to the end of it if it already exists.
+    /// The header is only written if the file is empty, so the log of one run
+    /// can carry on from the last.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self>
+    {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        let empty = file.metadata()?.len() == 0;
+
+        let mut writer = BufWriter::new(file);
+
+        if empty
+        {
+            writeln!(writer, "{}", HEADER)?;
+        }
+
+        Ok(DetectionLogger { writer })
+    }
+
+    /// Adds a detection from the map made at `stamp`, which was published on
+    /// `topic`, to the log. It isn't written out to disk until `flush`.
+    pub fn log(&mut self, stamp: rosrust::Time, topic: &str, detection: &Detection) -> io::Result<()>
+    {
+        writeln!(self.writer, "{}", csv_row(stamp, topic, detection))
+    }
+
+    /// Writes everything logged so far out to disk. This also happens when the
+    /// logger is dropped.
+    pub fn flush(&mut self) -> io::Result<()>
+    {
+        self.writer.flush()
+    }
+}
+
+/// A single row of the log (without the newline).
+///
+/// For a circle, the width and length are both the radius; like a `Rectle`,
+/// the width and length of a rectangle are half of its sides. The stamp is in
+/// seconds. `inside` is empty unless the detection is nested inside another
+/// (see `Detection::inside`), which is counted among the detections logged
+/// for the same map, from zero.
+pub fn csv_row(stamp: rosrust::Time, topic: &str, detection: &Detection) -> String
+{
+    let (kind, centre, width, length, rotation, sharpness) = match detection.shape
+    {
+        Shape::Circle(ref c) => ("circle", c.centre, c.radius, c.radius, 0.0, 1),
+        Shape::Rectle(ref r) => ("rectangle", r.centre, r.width, r.length, r.rotation, r.sharpness),
+        Shape::Unknown { .. } => ("unknown", (NAN, NAN), NAN, NAN, NAN, 0),
+    };
+
+    let inside = detection.inside.map_or(String::new(), |i| i.to_string());
+
+    format!("{}.{:09},{},{},{},{},{},{},{},{},{},{},{}",
+        stamp.sec, stamp.nsec,
+        topic,
+        kind,
+        centre.0, centre.1,
+        width, length,
+        rotation,
+        sharpness,
+        detection.shape.score(),
+        detection.group_size,
+        inside)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use model3::{Circle, Rectle};
+
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn logged_detections_are_read_back_from_the_file()
+    {
+        let path = ::std::env::temp_dir().join(format!("detections-{}.csv", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let stamp = rosrust::Time { sec: 12, nsec: 5 };
+
+        let detections = vec!
+        [
+            Detection { shape: Shape::Circle(Circle { centre: (1.0, 2.0), radius: 0.25, score: 0.01 }), group_size: 30, inside: None },
+            Detection { shape: Shape::Rectle(Rectle { centre: (-1.0, 0.5), width: 0.3, length: 0.2, rotation: 0.1, score: 0.02, sharpness: 4 }), group_size: 50, inside: Some(0) },
+        ];
+
+        let read = ||
+        {
+            let mut contents = String::new();
+            File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        {
+            let mut logger = DetectionLogger::open(&path).unwrap();
+
+            for detection in detections.iter()
+            {
+                logger.log(stamp, "/obstacles", detection).unwrap();
+            }
+
+            logger.flush().unwrap();
+
+            // it's all there as soon as it is flushed, without waiting for the
+            // logger to be dropped.
+            let expected = format!("{}\n{}\n{}\n", HEADER, csv_row(stamp, "/obstacles", &detections[0]), csv_row(stamp, "/obstacles", &detections[1]));
+            assert_eq!(read(), expected);
+        }
+
+        assert_eq!(read().lines().nth(1), Some("12.000000005,/obstacles,circle,1,2,0.25,0.25,0,1,0.01,30,"));
+        assert!(read().lines().nth(2).unwrap().ends_with(",50,0"), "{}", read());
+
+        // opening it again carries on from the end, without another header.
+        {
+            let mut logger = DetectionLogger::open(&path).unwrap();
+            logger.log(stamp, "/obstacles", &detections[1]).unwrap();
+        }
+
+        let lines: Vec<String> = read().lines().map(|line| line.to_string()).collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines.iter().filter(|line| *line == HEADER).count(), 1);
+        assert_eq!(lines[3], lines[2]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
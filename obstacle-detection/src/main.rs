@@ -9,107 +9,795 @@
 extern crate common;
 use common::prelude::*;
 
-/// The model for finding shapes.
-pub mod model3;
+extern crate obstacle_detection;
+use obstacle_detection::{model3, watchdog, tracker, detection, recent, service, debug, logger};
+use detection::DetectionParams;
 
-use map_utils::
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use map_utils::{Map, MapError};
+
+use msg::geometry_msgs::
 {
-    Map,
-    extract_groups,
+    Pose,
+    PoseArray,
 };
 
+use msg::std_msgs::Float64;
+
+use msg::sensor_msgs::PointCloud2;
+
+use msg::nav_msgs::MapMetaData;
+
+use msg::tf2_msgs::TFMessage;
+
+use msg::od2rs_msgs::{ClassifyPoints, ClassifyPointsReq};
+use msg::geometry_msgs::TransformStamped;
+
+use model3::Shape;
+
+/// Settings for a single detection pipeline: one map in, one set of detections
+/// out. Several of these can run side-by-side in the same node, e.g for
+/// multiple robots.
+#[derive(Debug, Clone)]
+pub struct PipelineConfig
+{
+    /// The topic that the map is recieved on.
+    pub map_topic: String,
+
+    /// The topic that the detected obstacles are published on.
+    pub output_topic: String,
+
+    /// The frame that the detected obstacles are given in.
+    pub frame_id: String,
+}
+
+impl PipelineConfig
+{
+    /// Reads the pipeline settings from the parameter server.
+    ///
+    /// `~map_topics`, `~output_topics` and `~frame_ids` are lists, where the
+    /// n-th entry of each list belongs to the n-th pipeline. By default there is
+    /// a single pipeline, which listens on `~map_topic` (`/map` if not set),
+    /// publishes on `~output_topic` (`/obstacles`) and uses the frame
+    /// `~frame_id` (`map`).
+    pub fn from_params() -> Result<Vec<PipelineConfig>, String>
+    {
+        let map_topics    = list_param("~map_topics",    params::get_or("~map_topic",    "/map".to_string()));
+        let output_topics = list_param("~output_topics", params::get_or("~output_topic", "/obstacles".to_string()));
+        let frame_ids     = list_param("~frame_ids",     params::get_or("~frame_id",     "map".to_string()));
+
+        PipelineConfig::from_lists(map_topics, output_topics, frame_ids)
+    }
+
+    /// Pairs up the n-th entry of each list into the settings of the n-th
+    /// pipeline. The lists must all be the same length.
+    pub fn from_lists(map_topics: Vec<String>, output_topics: Vec<String>, frame_ids: Vec<String>) -> Result<Vec<PipelineConfig>, String>
+    {
+        if map_topics.len() != output_topics.len() || map_topics.len() != frame_ids.len()
+        {
+            return Err(format!(
+                "~map_topics, ~output_topics and ~frame_ids must all be the same length (got {}, {} and {})",
+                map_topics.len(), output_topics.len(), frame_ids.len()));
+        }
+
+        Ok(map_topics.into_iter()
+        .zip(output_topics.into_iter())
+        .zip(frame_ids.into_iter())
+        .map(|((map_topic, output_topic), frame_id)| PipelineConfig { map_topic, output_topic, frame_id })
+        .collect())
+    }
+}
+
+// reads a list of strings from the parameter server, with a single default.
+fn list_param(name: &str, default: String) -> Vec<String>
+{
+    params::get_or(name, vec![default])
+}
+
+/// The time at which a map was made; the stamp in its header, or if that isn't
+/// set, the time that the map was loaded.
+///
+/// This is carried through to the detections, so that whoever is listening can
+/// tell how stale they are.
+fn map_stamp(map: &Map) -> rosrust::Time
+{
+    let stamp = map.header.stamp;
+
+    if stamp.sec == 0 && stamp.nsec == 0 { map.info.map_load_time } else { stamp }
+}
+
+/// Builds the message containing the tracked obstacles, which are given in the
+/// frame `frame_id`, as of `stamp`. Tracked obstacles don't have an
+/// orientation.
+fn tracks_message(tracks: &[tracker::Track], frame_id: &str, stamp: rosrust::Time) -> PoseArray
+{
+    let mut message = PoseArray::default();
+
+    message.header.frame_id = frame_id.to_string();
+    message.header.stamp = stamp;
+    message.poses = tracks.iter()
+    .map(|track| Pose2D::new(track.position.0, track.position.1, 0.0).to_ros())
+    .collect();
+
+    message
+}
+
+/// The transform from `frame_id` to a tracked obstacle, as of `stamp`.
+///
+/// The child frame is named after the pipeline's output topic and the id of
+/// the track, e.g `obstacles_3`, so it stays the same for as long as the
+/// obstacle is tracked. Track ids are never reused, so when an obstacle is
+/// dropped its frame simply stops being broadcast, and goes stale in the TF
+/// tree, rather than jumping to some other obstacle.
+fn obstacle_transform(track: &tracker::Track, output_topic: &str, frame_id: &str, stamp: rosrust::Time) -> TransformStamped
+{
+    let mut transform = TransformStamped::default();
+
+    transform.header.frame_id = frame_id.to_string();
+    transform.header.stamp = stamp;
+    transform.child_frame_id = format!("{}_{}", output_topic.trim_matches('/').replace("/", "_"), track.id);
+
+    transform.transform.translation.x = track.position.0;
+    transform.transform.translation.y = track.position.1;
+    transform.transform.rotation.w = 1.0;
+
+    transform
+}
+
+/// Builds the message containing the detected obstacles, which are given in
+/// the frame `frame_id`, as of `stamp`.
+fn detections_message(shapes: &[Shape], frame_id: &str, stamp: rosrust::Time) -> PoseArray
+{
+    let mut message = PoseArray::default();
+
+    message.header.frame_id = frame_id.to_string();
+    message.header.stamp = stamp;
+    message.poses = shapes.iter().map(shape_pose).collect();
+
+    message
+}
+
+// the pose of a detected shape. Circles don't have an orientation.
+fn shape_pose(shape: &Shape) -> Pose
+{
+    let (centre, rotation) = match *shape
+    {
+        Shape::Circle(ref c) => (c.centre, 0.0),
+        Shape::Rectle(ref r) => (r.centre, r.rotation),
+
+        // these never get published.
+        Shape::Unknown { .. } => ((0.0, 0.0), 0.0),
+    };
+
+    Pose2D::new(centre.0, centre.1, rotation).to_ros()
+}
+
+/// Everything that a single pipeline needs while processing maps.
+struct Pipeline
+{
+    config: PipelineConfig,
+    params: DetectionParams,
+    publisher: Mutex<rosrust::Publisher<PoseArray>>,
+
+    /// Smooths the detections over time; the tracks are published on
+    /// `<output_topic>/tracked`.
+    tracker: Mutex<tracker::ObstacleTracker>,
+    tracks_publisher: Mutex<rosrust::Publisher<PoseArray>>,
+
+    /// A map of the empty arena, if there is one. Anything occupied in this
+    /// map is removed before looking for obstacles.
+    background: Arc<Mutex<Option<Map>>>,
+
+    /// How long (in seconds) each map took to process is published here. This
+    /// is shared by all pipelines.
+    timing_publisher: Arc<Mutex<rosrust::Publisher<Float64>>>,
+
+    /// If set, the cells of each group are published here, coloured by group,
+    /// for debugging in rviz; see `debug::group_cloud`. This is shared by all
+    /// pipelines.
+    group_debug_publisher: Option<Arc<Mutex<rosrust::Publisher<PointCloud2>>>>,
+
+    /// If set, each tracked obstacle is broadcast on `/tf`; see
+    /// `obstacle_transform`. This is shared by all pipelines.
+    tf_publisher: Option<Arc<Mutex<rosrust::Publisher<TFMessage>>>>,
+
+    /// If set, every detection is written to a CSV file; see
+    /// `logger::DetectionLogger`. This is shared by all pipelines.
+    logger: Option<Arc<Mutex<logger::DetectionLogger>>>,
+
+    /// The maps this pipeline processed recently; repeats are skipped.
+    recent: Mutex<recent::RecentMaps>,
+
+    /// The metadata of the last map that was processed, for keeping the
+    /// tracks still when the map's origin moves.
+    last_info: Mutex<Option<MapMetaData>>,
+
+    /// The coordinates of the cells of the last few maps, since the geometry
+    /// of the map rarely changes from one to the next.
+    transform_cache: map_utils::TransformCache,
+}
+
+/// The number of seconds (of wall time) since `start`.
+fn seconds_since(start: Instant) -> Num
+{
+    seconds(start.elapsed())
+}
+
+/// The length of `duration`, in seconds.
+fn seconds(duration: Duration) -> Num
+{
+    duration.as_secs() as Num + duration.subsec_nanos() as Num * 1e-9
+}
+
 /// The main callback that is passed to the subscriber object.
-fn callback(map: Map)
+fn callback(map: Map, pipeline: &Pipeline)
 {
-    println!("recieved map, info: {:.4?}", map.info);
+    println!("recieved map, {}", map_utils::describe(&map));
+
+    match map_utils::validate_map(&map)
+    {
+        Ok(()) => (),
+
+        // gmapping publishes these while it's starting up, so they're nothing
+        // to worry about.
+        Err(MapError::BadDimensions) =>
+        {
+            println!("Recieved an empty ({}x{}) map. Skipping it.", map.info.width, map.info.height);
+            return;
+        },
 
-    let group_table = extract_groups(&map, |value| value > 3, 3);
+        Err(e) =>
+        {
+            println!("ERROR! Recieved a malformed map: {:?}. Skipping it.", e);
+            return;
+        },
+    }
 
-    // we can now iterate over the groups of cells and try to determine whether
-    // each group makes up a circle or a rectangle.
-    for (_group, items) in group_table.into_iter()
+    if !pipeline.recent.lock().unwrap().should_process(&map)
     {
-        if items.len() == 0
+        println!("Map is the same as one processed recently. Skipping it.");
+        return;
+    }
+
+    let stamp = map_stamp(&map);
+
+    // get rid of the walls etc, if we know where they are.
+    let map = match *pipeline.background.lock().unwrap()
+    {
+        Some(ref background) => match map_utils::subtract(&map, background)
         {
-            println!("Skipped a group that contained zero elements! (This should never happen).");
-            continue;
+            Ok(m) => m,
+            Err(e) =>
+            {
+                println!("WARNING! Could not subtract the background map: {:?}. Using the map as-is.", e);
+                map
+            }
+        },
+
+        None => map,
+    };
+
+    // only the processing itself is timed, not the publishing.
+    let started = Instant::now();
+
+    let groups = detection::find_groups(&map, &pipeline.params);
+
+    let found = detection::detect(&map, &groups, &pipeline.params, &pipeline.transform_cache);
+
+    let processing_time = seconds_since(started);
+
+    println!("Processed map in {:.3}s", processing_time);
+
+    if let Some(ref logger) = pipeline.logger
+    {
+        let mut logger = logger.lock().unwrap();
+
+        for detection in found.iter()
+        {
+            if let Err(e) = logger.log(stamp, &pipeline.config.output_topic, detection)
+            {
+                println!("ERROR! Could not log a detection: {:?}", e);
+                break;
+            }
+        }
+
+        if let Err(e) = logger.flush()
+        {
+            println!("ERROR! Could not write out the log: {:?}", e);
         }
+    }
 
-        // transform the items into xy, relative to the robot
-        // starting position.
-        let items = map_utils::par_transform(&map, items);
+    let shapes: Vec<Shape> = found.into_iter().map(|d| d.shape).collect();
 
-        // find the bounds of the box:
-        let upper = items.par_iter().max_by(|a,b| a.0.partial_cmp(&b.0).unwrap()).unwrap();
-        let lower = items.par_iter().min_by(|a,b| a.0.partial_cmp(&b.0).unwrap()).unwrap();
-        let left  = items.par_iter().max_by(|a,b| a.1.partial_cmp(&b.1).unwrap()).unwrap();
-        let right = items.par_iter().min_by(|a,b| a.1.partial_cmp(&b.1).unwrap()).unwrap();
+    if let Some(ref group_debug_publisher) = pipeline.group_debug_publisher
+    {
+        let group_cloud = debug::group_cloud(&map, &groups, &pipeline.config.frame_id, stamp);
 
-        let box_size =
+        if let Err(e) = group_debug_publisher.lock().unwrap().send(group_cloud)
         {
-            let bh = upper.0 - lower.0;
-            let bw  = left.0 - right.0;
+            println!("ERROR! Could not publish the groups to /group_debug: {:?}", e);
+        }
+    }
 
-            bh.hypot(bw)
-        };
+    let detections = detections_message(&shapes, &pipeline.config.frame_id, stamp);
+
+    if let Err(e) = pipeline.publisher.lock().unwrap().send(detections)
+    {
+        println!("ERROR! Could not publish to {}: {:?}", pipeline.config.output_topic, e);
+    }
+
+    let centres: Vec<(Num, Num)> = shapes.iter().filter_map(|shape| shape.centre()).collect();
+
+    let mut tracker = pipeline.tracker.lock().unwrap();
+
+    // when gmapping moves the origin, or grows the map, everything in it
+    // appears to move.
+    if let Some(ref last_info) = *pipeline.last_info.lock().unwrap()
+    {
+        tracker.shift_frame(map_utils::coordinate_shift(last_info, &map.info));
+    }
 
-        let a0 = left.0  as Num - lower.0 as Num;
-        let a1 = left.1  as Num - lower.1 as Num;
-        let b0 = right.0 as Num - lower.0 as Num;
-        let b1 = right.1 as Num - lower.1 as Num;
+    *pipeline.last_info.lock().unwrap() = Some(map.info.clone());
 
-        let a = a0.hypot(a1);
-        let b = b0.hypot(b1);
+    tracker.update(&centres);
 
-        if a < 0.09 || b < 0.09 || box_size > 1.5
+    // the tracks are kept in the coordinates of the first map, but they're
+    // published with this one.
+    let tracked = tracker.current_tracks();
+
+    if let Some(ref tf_publisher) = pipeline.tf_publisher
+    {
+        let transforms = tracked.iter()
+        .map(|track| obstacle_transform(track, &pipeline.config.output_topic, &pipeline.config.frame_id, stamp))
+        .collect();
+
+        if let Err(e) = tf_publisher.lock().unwrap().send(TFMessage { transforms })
         {
-            // assuming it's noise and quietly continuing.
-            // this also filters for the borders
-            continue;
+            println!("ERROR! Could not broadcast the obstacle transforms: {:?}", e);
         }
+    }
 
-        println!("a0: {}", a0);
-        println!("a1: {}", a1);
-        println!("b0: {}", b0);
-        println!("b1: {}", b1);
-        println!("a:  {}", a);
-        println!("b:  {}", b);
-
-        println!("Bounding box:\nUpper: {:3.4}\t{:3.4}\nLower: {:3.4}\t{:3.4}\nLeft : {:3.4}\t{:3.4}\nRight: {:3.4}\t{:3.4}",
-            upper.0, upper.1,
-            lower.0, lower.1,
-             left.0,  left.1,
-            right.0, right.1);
-
-        let shape = model3::hough_transform(
-            &items,
-            (lower.0 + (a0+b0)/2.0, lower.1 + (a1+b1)/2.0),
-            a,
-            b,
-        );
+    let tracks = tracks_message(&tracked, &pipeline.config.frame_id, stamp);
+
+    if let Err(e) = pipeline.tracks_publisher.lock().unwrap().send(tracks)
+    {
+        println!("ERROR! Could not publish the tracked obstacles: {:?}", e);
+    }
 
-        println!("{:?}", shape);
+    if let Err(e) = pipeline.timing_publisher.lock().unwrap().send(Float64 { data: processing_time })
+    {
+        println!("ERROR! Could not publish the processing time: {:?}", e);
     }
 
     println!("Done processing map");
 }
 
+/// How often (in seconds) to check whether the master has been restarted; see
+/// `retry::RunIdWatcher`.
+const RUN_ID_CHECK_INTERVAL: u64 = 1;
+
+/// Sets up a topic (or service) with `setup`, retrying with `backoff` while
+/// the master can't be reached. `what` is e.g "publish to /tf", for the
+/// messages.
+fn set_up<T, F>(backoff: retry::Backoff, what: &str, setup: F) -> Result<T, String>
+where
+    F: FnMut() -> Result<T, rosrust::error::Error>
+{
+    retry::with_backoff(backoff, what, setup).map_err(|e| format!("Could not {}: {:?}", what, e))
+}
+
+/// The subscriptions (and the service) of the node. They're dropped and set
+/// up again when the master is restarted.
+struct Subscriptions
+{
+    _background: Option<rosrust::Subscriber>,
+    _maps: Vec<rosrust::Subscriber>,
+    _service: rosrust::Service,
+}
+
+/// Subscribes each pipeline to its map, and to the background map (if
+/// `background_topic` isn't empty), and advertises `~classify_points`.
+fn subscribe(
+    pipelines: &[Arc<Pipeline>],
+    background_topic: &str,
+    background: &Arc<Mutex<Option<Map>>>,
+    map_received: &Arc<AtomicBool>,
+    pool: &Arc<parallel::ThreadPool>,
+    reject_threshold: Num,
+    backoff: retry::Backoff) -> Result<Subscriptions, String>
+{
+    let background = if background_topic.is_empty() { None } else
+    {
+        Some(set_up(backoff, &format!("subscribe to {}", background_topic), ||
+        {
+            let background = background.clone();
+
+            rosrust::subscribe(background_topic, move |map: Map| *background.lock().unwrap() = Some(map))
+        })?)
+    };
+
+    let mut maps = Vec::new();
+
+    for pipeline in pipelines
+    {
+        let map_topic = &pipeline.config.map_topic;
+
+        maps.push(set_up(backoff, &format!("subscribe to {}", map_topic), ||
+        {
+            let pipeline = pipeline.clone();
+            let map_received = map_received.clone();
+            let pool = pool.clone();
+
+            rosrust::subscribe(map_topic, move |map: Map|
+            {
+                map_received.store(true, Ordering::SeqCst);
+                parallel::with_pool(&pool, || callback(map, &pipeline));
+            })
+        })?);
+    }
+
+    // other nodes can ask for a set of points to be classified, rather than
+    // waiting for them to turn up in a map.
+    let service = set_up(backoff, "advertise ~classify_points", ||
+    {
+        let pool = pool.clone();
+
+        rosrust::service::<ClassifyPoints, _>("~classify_points", move |request: ClassifyPointsReq|
+        {
+            parallel::with_pool(&pool, || service::classify_points(&request, reject_threshold))
+        })
+    })?;
+
+    Ok(Subscriptions { _background: background, _maps: maps, _service: service })
+}
+
+/// Sets up all of the pipelines' publishers again, e.g with a new master. The
+/// shared publishers are set up once, through the first pipeline.
+fn republish(pipelines: &[Arc<Pipeline>], backoff: retry::Backoff) -> Result<(), String>
+{
+    for pipeline in pipelines
+    {
+        let output_topic = &pipeline.config.output_topic;
+        let tracks_topic = format!("{}/tracked", output_topic);
+
+        *pipeline.publisher.lock().unwrap() = set_up(backoff, &format!("publish to {}", output_topic), || rosrust::publish(output_topic))?;
+        *pipeline.tracks_publisher.lock().unwrap() = set_up(backoff, &format!("publish to {}", tracks_topic), || rosrust::publish(&tracks_topic))?;
+    }
+
+    let shared = match pipelines.first()
+    {
+        Some(pipeline) => pipeline,
+        None => return Ok(()),
+    };
+
+    *shared.timing_publisher.lock().unwrap() = set_up(backoff, "publish to /od2rs/processing_time", || rosrust::publish("/od2rs/processing_time"))?;
+
+    if let Some(ref group_debug_publisher) = shared.group_debug_publisher
+    {
+        *group_debug_publisher.lock().unwrap() = set_up(backoff, "publish to /group_debug", || rosrust::publish("/group_debug"))?;
+    }
+
+    if let Some(ref tf_publisher) = shared.tf_publisher
+    {
+        *tf_publisher.lock().unwrap() = set_up(backoff, "publish to /tf", || rosrust::publish("/tf"))?;
+    }
+
+    Ok(())
+}
+
 fn main()
 {
     rosrust::init("od2rs");
 
-    let _subscriber = match rosrust::subscribe("/map", callback)
+    // how long to wait for a map before complaining, and (optionally) before
+    // giving up entirely. A hard timeout of zero means "wait forever".
+    let warn_after = params::get_or::<Num>("~map_timeout", 10.0);
+
+    let exit_after = params::get_or::<Num>("~map_hard_timeout", 0.0);
+
+    let timeouts = watchdog::Timeouts
+    {
+        warn_after: watchdog::seconds(warn_after),
+        exit_after: if exit_after > 0.0 { Some(watchdog::seconds(exit_after)) } else { None },
+    };
+
+    let configs = match PipelineConfig::from_params()
+    {
+        Ok(c) => c,
+        Err(e) =>
+        {
+            println!("ERROR! Bad pipeline configuration: {}. Node is shutting down", e);
+            return;
+        }
+    };
+
+    let params = DetectionParams::from_params();
+
+    // how many maps each pipeline remembers, so that repeats of them can be
+    // skipped. Zero processes every map.
+    let recent_maps = params::get_or::<i32>("~recent_maps", 4).max(0) as usize;
+
+    // the number of threads to use for processing the map. Zero means "use
+    // every core".
+    let threads = params::get_or::<i32>("~threads", 0);
+
+    let pool = match parallel::thread_pool(threads.max(0) as usize)
+    {
+        Ok(p) => Arc::new(p),
+        Err(e) =>
+        {
+            println!("ERROR! Could not create the thread pool: {:?}. Node is shutting down", e);
+            return;
+        }
+    };
+
+    let map_received = Arc::new(AtomicBool::new(false));
+    let _watchdog = watchdog::spawn(timeouts, map_received.clone());
+
+    // the master can be unreachable for a little while, e.g if it's being
+    // restarted, so setting up the topics is retried for a bit before giving
+    // up on them.
+    let backoff = retry::Backoff::default();
+
+    // optionally, a map of the empty arena, which is shared by all pipelines.
+    let background = Arc::new(Mutex::new(None));
+
+    let background_topic = params::get_or::<String>("~background_topic", String::new());
+
+    let timing_publisher = match retry::with_backoff(backoff, "publish to /od2rs/processing_time", || rosrust::publish("/od2rs/processing_time"))
+    {
+        Ok(p) => Arc::new(Mutex::new(p)),
+        Err(e) =>
+        {
+            println!("ERROR! Could not publish to /od2rs/processing_time: {:?}. Node is shutting down", e);
+            return;
+        }
+    };
+
+    // packing every cell of every group into a point cloud isn't free, so the
+    // groups are only published when someone wants to look at them.
+    let group_debug_publisher = if !params::get_or("~publish_group_debug", false) { None } else
+    {
+        match retry::with_backoff(backoff, "publish to /group_debug", || rosrust::publish("/group_debug"))
+        {
+            Ok(p) => Some(Arc::new(Mutex::new(p))),
+            Err(e) =>
+            {
+                println!("ERROR! Could not publish to /group_debug: {:?}. Node is shutting down", e);
+                return;
+            }
+        }
+    };
+
+    // logging the detections is optional, for when the parameters are being
+    // tuned.
+    let log_path = params::get_or::<String>("~log_path", String::new());
+
+    let logger = if log_path.is_empty() { None } else
+    {
+        match logger::DetectionLogger::open(&log_path)
+        {
+            Ok(l) => Some(Arc::new(Mutex::new(l))),
+            Err(e) =>
+            {
+                println!("ERROR! Could not open the detection log {}: {:?}. Node is shutting down", log_path, e);
+                return;
+            }
+        }
+    };
+
+    // broadcasting the obstacles on /tf is optional, since not everyone wants
+    // them cluttering up the TF tree.
+    let tf_publisher = if !params::get_or("~broadcast_tf", false) { None } else
+    {
+        match retry::with_backoff(backoff, "publish to /tf", || rosrust::publish("/tf"))
+        {
+            Ok(p) => Some(Arc::new(Mutex::new(p))),
+            Err(e) =>
+            {
+                println!("ERROR! Could not publish to /tf: {:?}. Node is shutting down", e);
+                return;
+            }
+        }
+    };
+
+    // each pipeline gets its own publisher and subscriber, but they all share
+    // the same callback.
+    let mut pipelines = Vec::new();
+
+    for config in configs
+    {
+        let publisher = match retry::with_backoff(backoff, &format!("publish to {}", config.output_topic), || rosrust::publish(&config.output_topic))
+        {
+            Ok(p) => Mutex::new(p),
+            Err(e) =>
+            {
+                println!("ERROR! Could not publish to {}: {:?}. Node is shutting down", config.output_topic, e);
+                return;
+            }
+        };
+
+        let tracks_topic = format!("{}/tracked", config.output_topic);
+
+        let tracks_publisher = match retry::with_backoff(backoff, &format!("publish to {}", tracks_topic), || rosrust::publish(&tracks_topic))
+        {
+            Ok(p) => Mutex::new(p),
+            Err(e) =>
+            {
+                println!("ERROR! Could not publish to {}: {:?}. Node is shutting down", tracks_topic, e);
+                return;
+            }
+        };
+
+        let tracker = tracker::ObstacleTracker::new(
+            params.track_smoothing,
+            params.track_max_distance,
+            params.track_max_missed,
+        );
+
+        pipelines.push(Arc::new(Pipeline
+        {
+            config,
+            params: params.clone(),
+            publisher,
+            tracker: Mutex::new(tracker),
+            tracks_publisher,
+            background: background.clone(),
+            timing_publisher: timing_publisher.clone(),
+            group_debug_publisher: group_debug_publisher.clone(),
+            tf_publisher: tf_publisher.clone(),
+            logger: logger.clone(),
+            recent: Mutex::new(recent::RecentMaps::new(recent_maps)),
+            last_info: Mutex::new(None),
+            transform_cache: map_utils::TransformCache::new(map_utils::TRANSFORM_CACHE_SIZE),
+        }));
+    }
+
+    // setting everything up again with a new master would do no good without
+    // the subscriptions (and the service), so they're set up again together.
+    let subscribe_all = ||
+    {
+        subscribe(
+            &pipelines,
+            &background_topic,
+            &background,
+            &map_received,
+            &pool,
+            params.reject_threshold,
+            backoff)
+    };
+
+    let mut _subscriptions = match subscribe_all()
     {
         Ok(s) => s,
         Err(e) =>
         {
-            println!("ERROR! Could not subscribe to /map: {:?}. Node is shutting down", e);
+            println!("ERROR! {}. Node is shutting down", e);
             return;
         }
     };
 
     println!("od2rs node successfully initialised");
-    rosrust::spin();
+
+    // the node carries on if the master is restarted, but everything has to be
+    // set up again with the new one.
+    let mut run_id = retry::RunIdWatcher::new();
+
+    while rosrust::is_ok()
+    {
+        thread::sleep(Duration::from_secs(RUN_ID_CHECK_INTERVAL));
+
+        if !run_id.restarted() { continue; }
+
+        println!("WARNING! The master has restarted. Setting up the topics again");
+
+        // the old subscriptions went with the old master, and shouldn't be
+        // unregistered from the new one once it has the new ones.
+        drop(_subscriptions);
+
+        match republish(&pipelines, backoff).and_then(|()| subscribe_all())
+        {
+            Ok(s) => _subscriptions = s,
+            Err(e) =>
+            {
+                println!("ERROR! {}. Node is shutting down", e);
+                return;
+            }
+        }
+    }
 
     println!("od2rs shutting down");
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use obstacle_detection::model3::Circle;
+
+    // a list of strings.
+    fn strings(items: &[&str]) -> Vec<String>
+    {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn each_pipeline_gets_its_own_topics_and_frame()
+    {
+        let configs = PipelineConfig::from_lists(
+            strings(&["/robot1/map", "/robot2/map"]),
+            strings(&["/robot1/obstacles", "/robot2/obstacles"]),
+            strings(&["robot1/map", "robot2/map"]),
+        ).unwrap();
+
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[1].map_topic, "/robot2/map");
+        assert_eq!(configs[1].output_topic, "/robot2/obstacles");
+        assert_eq!(configs[1].frame_id, "robot2/map");
+
+        assert!(PipelineConfig::from_lists(strings(&["/a", "/b"]), strings(&["/c"]), strings(&["d", "e"])).is_err());
+    }
+
+    #[test]
+    fn detections_are_given_in_the_pipelines_frame()
+    {
+        let shapes = vec![Shape::Circle(Circle { centre: (1.0, -2.0), radius: 0.25, score: 0.0 })];
+
+        let message = detections_message(&shapes, "robot2/map", rosrust::Time { sec: 0, nsec: 0 });
+
+        assert_eq!(message.header.frame_id, "robot2/map");
+        assert_eq!(message.poses.len(), 1);
+        assert_eq!((message.poses[0].position.x, message.poses[0].position.y), (1.0, -2.0));
+    }
+
+    #[test]
+    fn messages_are_stamped_with_the_time_of_the_map()
+    {
+        let mut map = Map::default();
+        map.info.map_load_time = rosrust::Time { sec: 5, nsec: 6 };
+
+        // `gmapping` doesn't always stamp the header.
+        assert_eq!(map_stamp(&map), rosrust::Time { sec: 5, nsec: 6 });
+
+        map.header.stamp = rosrust::Time { sec: 7, nsec: 0 };
+        assert_eq!(map_stamp(&map), rosrust::Time { sec: 7, nsec: 0 });
+
+        let track = tracker::Track { id: 0, position: (0.5, 0.5), missed: 0 };
+
+        assert_eq!(tracks_message(&[track], "map", map_stamp(&map)).header.stamp, rosrust::Time { sec: 7, nsec: 0 });
+        assert_eq!(detections_message(&[], "map", map_stamp(&map)).header.stamp, rosrust::Time { sec: 7, nsec: 0 });
+    }
+
+    #[test]
+    fn durations_are_given_in_seconds()
+    {
+        assert_eq!(seconds(Duration::new(2, 500_000_000)), 2.5);
+        assert_eq!(seconds(Duration::from_millis(1)), 0.001);
+
+        let elapsed = seconds_since(Instant::now());
+        assert!(elapsed >= 0.0 && elapsed < 1.0, "{}", elapsed);
+    }
+
+    #[test]
+    fn each_tracked_obstacle_gets_its_own_frame()
+    {
+        let track = tracker::Track { id: 3, position: (1.5, -0.5), missed: 0 };
+
+        let transform = obstacle_transform(&track, "/robot1/obstacles", "robot1/map", rosrust::Time { sec: 7, nsec: 0 });
+
+        assert_eq!(transform.header.frame_id, "robot1/map");
+        assert_eq!(transform.header.stamp, rosrust::Time { sec: 7, nsec: 0 });
+        assert_eq!(transform.child_frame_id, "robot1_obstacles_3");
+
+        let translation = &transform.transform.translation;
+        assert_eq!((translation.x, translation.y, translation.z), (1.5, -0.5, 0.0));
+
+        let rotation = &transform.transform.rotation;
+        assert_eq!((rotation.x, rotation.y, rotation.z, rotation.w), (0.0, 0.0, 0.0, 1.0));
+    }
+}
@@ -1,50 +1,150 @@
-type Point  = (f32, f32);
-type Points = Vec<Point>;
+#![allow(non_snake_case)]
 
+use ::common::num_traits::Float;
+
+use std::fmt::Debug;
+
+use superellipse::SuperellipseParams;
+
+/// The model, in either `f32` or `f64`; the points have to be in the same one.
 #[derive(Debug)]
-pub struct Model
+pub struct Model<T: Float>
 {
-    pub a: f64,
-    pub b: f64,
-    pub p: f64,
-    pub q: f64,
-    pub theta: f64,
-    pub s: f64,
+    pub a: T,
+    pub b: T,
+    pub p: T,
+    pub q: T,
+    pub theta: T,
+    pub s: T,
 }
 
-impl Model
+// a literal, in whichever float type the model is using.
+fn lit<T: Float>(x: f64) -> T
 {
-    pub fn fit(
-        points: &Points, 
-        gamma: f64,
-        a:     f64,
-        b:     f64,
-        p:     f64,
-        q:     f64,
-        theta: f64) -> Self
+    T::from(x).unwrap()
+}
+
+impl<T: Float + Debug> Model<T>
+{
+    /// The model with the given parameters.
+    pub fn from_params(params: SuperellipseParams) -> Self
     {
-        let mut this = Model
+        Model
         {
-            a,
-            b,
-            p,
-            q,
-            theta,
-            s: 1.0,
-        };
+            a:     lit(params.a),
+            b:     lit(params.b),
+            p:     lit(params.p),
+            q:     lit(params.q),
+            theta: lit(params.t),
+            s:     lit(params.s),
+        }
+    }
+
+    /// The parameters of the model.
+    pub fn params(&self) -> SuperellipseParams
+    {
+        let num = |x: T| x.to_f64().unwrap();
+
+        SuperellipseParams
+        {
+            a: num(self.a),
+            b: num(self.b),
+            p: num(self.p),
+            q: num(self.q),
+            t: num(self.theta),
+            s: num(self.s),
+        }
+    }
+
+    /// Fits the model to the points with gradient descent, starting from the
+    /// given parameters, as an ellipse (`s = 1`).
+    pub fn fit(
+        points: &[(T, T)],
+        gamma: T,
+        max_update: T,
+        a:     T,
+        b:     T,
+        p:     T,
+        q:     T,
+        theta: T) -> Self
+    {
+        let num = |x: T| x.to_f64().unwrap();
+
+        let start = SuperellipseParams { a: num(a), b: num(b), p: num(p), q: num(q), t: num(theta), s: 1.0 };
+
+        Model::fit_from(points, gamma, max_update, start)
+    }
 
-        ros_info!("Fitting model starting from {:?}", this);
+    /// Fits the model to the points with gradient descent, starting from
+    /// `start`, e.g the result of the Hough search in `model3`.
+    ///
+    /// Each update to the parameters is clipped so that its size (L2 norm) is
+    /// at most `max_update`, since a point near the centre of the shape can
+    /// make the gradient enormous.
+    pub fn fit_from(
+        points: &[(T, T)],
+        gamma: T,
+        max_update: T,
+        start: SuperellipseParams) -> Self
+    {
+        let mut this = Model::from_params(start);
+
+        println!("Fitting model starting from {:?}", this);
 
         loop
         {
             let (dJda, dJdb, dJdp, dJdq, dJdt, dJds) = this.gradients(&points);
 
-            this.a     = this.a     - gamma*dJda;
-            this.b     = this.b     - gamma*dJdb;
-            this.p     = this.p     - gamma*dJdp;
-            this.q     = this.q     - gamma*dJdq;
-            this.theta = this.theta - gamma*dJdt;
-            this.s     = this.s     - gamma*dJds;
+            // backtracking line search; halve the step until the loss
+            // actually goes down.
+            let current_loss = this.total_loss(&points);
+            let start = (this.a, this.b, this.p, this.q, this.theta, this.s);
+            let mut step = gamma;
+            let mut improved = false;
+
+            let norm =
+            (
+                dJda.powi(2) +
+                dJdb.powi(2) +
+                dJdp.powi(2) +
+                dJdq.powi(2) +
+                dJdt.powi(2) +
+                dJds.powi(2)
+            ).sqrt();
+
+            for _ in 0..30
+            {
+                // clip the update, keeping its direction.
+                let scale = if step*norm > max_update { max_update / norm } else { step };
+
+                this.a     = start.0 - scale*dJda;
+                this.b     = start.1 - scale*dJdb;
+                this.p     = start.2 - scale*dJdp;
+                this.q     = start.3 - scale*dJdq;
+                this.theta = start.4 - scale*dJdt;
+                this.s     = start.5 - scale*dJds;
+
+                if this.total_loss(&points) < current_loss
+                {
+                    improved = true;
+                    break;
+                }
+
+                step = step / lit(2.0);
+            }
+
+            if !improved
+            {
+                // no step helps, so we're as close as we're going to get.
+                this.a     = start.0;
+                this.b     = start.1;
+                this.p     = start.2;
+                this.q     = start.3;
+                this.theta = start.4;
+                this.s     = start.5;
+
+                break;
+            }
 
             let change = (dJda, dJdb, dJdp, dJdq, dJdt, dJds);
 
@@ -63,67 +163,72 @@ impl Model
 
             // ros_info!("change: {}", change);
 
-            if change < 0.005 { break; }
+            if change < lit(0.005) { break; }
         }
 
         this
     }
 
-    fn model(&self, p: &Point) -> f64
+    fn model(&self, p: &(T, T)) -> T
     {
         let (st, ct) = self.theta.sin_cos();
 
-        let x = p.0 as f64 - self.p;
-        let y = p.1 as f64 - self.q;
+        let x = p.0 - self.p;
+        let y = p.1 - self.q;
 
         let R = (x * ct + y * st) / self.a;
         let C = (y * ct - x * st) / self.b;
 
-        let s = 2.0 * self.s.round();
+        let s = lit::<T>(2.0) * self.s.round();
 
         return R.powf(s) + C.powf(s);
     }
 
-    fn loss(&self, p: &Point) -> f64
+    fn loss(&self, p: &(T, T)) -> T
     {
-        (1.0 - self.model(p)).powi(2) / 2.0
+        (T::one() - self.model(p)).powi(2) / lit(2.0)
     }
 
-    fn gradients(&mut self, points: &Points) -> (f64, f64, f64, f64, f64, f64)
+    fn total_loss(&self, points: &[(T, T)]) -> T
+    {
+        points.iter().map(|p| self.loss(p)).fold(T::zero(), |acc, x| acc + x)
+    }
+
+    fn gradients(&mut self, points: &[(T, T)]) -> (T, T, T, T, T, T)
     {
         points.iter()
         .map(|p|
         {
-            let step = 0.001;
+            let step = lit::<T>(0.001);
             let current_val = self.loss(p);
 
-            self.a += step;
+            self.a = self.a + step;
             let dJda = (self.loss(p) - current_val) / step;
-            self.a -= step;
+            self.a = self.a - step;
 
-            self.b += step;
+            self.b = self.b + step;
             let dJdb = (self.loss(p) - current_val) / step;
-            self.b -= step;
+            self.b = self.b - step;
 
-            self.p += step;
+            self.p = self.p + step;
             let dJdp = (self.loss(p) - current_val) / step;
-            self.p -= step;
+            self.p = self.p - step;
 
-            self.q += step;
+            self.q = self.q + step;
             let dJdq = (self.loss(p) - current_val) / step;
-            self.q -= step;
+            self.q = self.q - step;
 
-            self.theta += step;
+            self.theta = self.theta + step;
             let dJdt = (self.loss(p) - current_val) / step;
-            self.theta -= step;
+            self.theta = self.theta - step;
 
-            self.s += step;
+            self.s = self.s + step;
             let dJds = (self.loss(p) - current_val) / step;
-            self.s -= step;
+            self.s = self.s - step;
 
             (dJda, dJdb, dJdp, dJdq, dJdt, dJds)
         })
-        .fold((0.0, 0.0, 0.0, 0.0, 0.0, 0.0), |acc, x|
+        .fold((T::zero(), T::zero(), T::zero(), T::zero(), T::zero(), T::zero()), |acc, x|
         {
             (
                 acc.0 + x.0, 
@@ -191,3 +296,94 @@ impl Model
     //     .reduce(|| (0.0, 0.0, 0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2, a.3 + b.3))
     // }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // points on a circle of radius 1 about the origin.
+    fn unit_circle<T: Float>(n: usize) -> Vec<(T, T)>
+    {
+        (0..n).map(|i|
+        {
+            let angle = i as f64 * 2.0 * ::std::f64::consts::PI / n as f64;
+            (lit(angle.cos()), lit(angle.sin()))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn the_line_search_copes_with_a_big_learning_rate()
+    {
+        let points = unit_circle::<f64>(50);
+        let start = Model::from_params(SuperellipseParams { a: 1.3, b: 0.8, p: 0.1, q: -0.1, t: 0.0, s: 1.0 });
+
+        // a step of 10 would throw a plain gradient descent right off.
+        let fitted = Model::fit(&points, 10.0, 1.0, 1.3, 0.8, 0.1, -0.1, 0.0);
+
+        assert!(fitted.total_loss(&points) < start.total_loss(&points) / 100.0, "{:?}", fitted);
+        assert!((fitted.a - 1.0).abs() < 0.05 && (fitted.b - 1.0).abs() < 0.05, "{:?}", fitted);
+        assert!(fitted.p.abs() < 0.05 && fitted.q.abs() < 0.05, "{:?}", fitted);
+    }
+
+    #[test]
+    fn a_point_by_the_centre_doesnt_throw_the_fit_off()
+    {
+        let mut points = unit_circle::<f32>(50);
+        points.push((0.001, 0.0));
+
+        let start = SuperellipseParams { a: 0.9, b: 0.9, p: 0.05, q: 0.0, t: 0.0, s: 1.0 };
+        let fitted = Model::fit_from(&points, 1000.0, 0.1, start);
+
+        let fitted = fitted.params();
+
+        assert!(fitted.a.is_finite() && fitted.b.is_finite() && fitted.p.is_finite() && fitted.q.is_finite(), "{:?}", fitted);
+        assert!(fitted.p.abs() < 0.5 && fitted.q.abs() < 0.5 && fitted.a.abs() < 2.0 && fitted.b.abs() < 2.0, "{:?}", fitted);
+    }
+
+    #[test]
+    fn the_params_mean_the_same_to_every_model()
+    {
+        use model3::{self, Rectle};
+
+        // including sharpnesses that aren't whole numbers, which all of them
+        // round the same way.
+        for &s in [3.0, 1.5, 2.3].iter()
+        {
+            let params = SuperellipseParams { a: 0.3, b: 0.2, p: 1.0, q: -0.5, t: 0.4, s };
+
+            // the conversions round trip, exactly in f64, and to f32's
+            // precision. A `Rectle` only keeps the rounded sharpness.
+            assert_eq!(Model::<f64>::from_params(params).params(), params);
+
+            let single = Model::<f32>::from_params(params).params();
+            for &(x, y) in [(single.a, params.a), (single.b, params.b), (single.p, params.p), (single.q, params.q), (single.t, params.t), (single.s, params.s)].iter()
+            {
+                assert!((x - y).abs() < 1e-6, "{:?} vs {:?}", single, params);
+            }
+
+            let rounded = SuperellipseParams { s: params.sharpness() as f64, ..params };
+            assert_eq!(Rectle::with_params(params, 0.0).params(), rounded);
+
+            // so they agree on where the boundary is, and on the score.
+            let points: Vec<(f64, f64)> = vec![(1.1, -0.4), (0.7, -0.5), (1.3, -0.2), (1.0, -0.3)];
+            let model = Model::<f64>::from_params(params);
+
+            for point in points.iter()
+            {
+                let field = params.M(point.0, point.1);
+                assert!((model.model(point) - 1.0 - field).abs() < 1e-9, "s = {}, {:?}: {} vs {}", s, point, model.model(point) - 1.0, field);
+            }
+
+            // well outside the shape on either side, the field is positive.
+            assert!(params.signed_field(params.p - 2.0 * params.a, params.q) > 0.0, "s = {}", s);
+            assert!(params.signed_field(params.p + 2.0 * params.a, params.q) > 0.0, "s = {}", s);
+
+            for &robust in [false, true].iter()
+            {
+                assert_eq!(params.score(&points, robust), model3::ht_score(&points, 0.3, 0.2, 1.0, -0.5, 0.4, params.sharpness(), robust));
+            }
+        }
+    }
+}
@@ -86,16 +86,132 @@ type Point = (Num, Num);
 type Points = Vec<Point>;
 type Range  = Vec<Num>;
 
+use ::common::map_utils::{self, Group, Map};
+type MapPoint = map_utils::Point;
+
+use superellipse::SuperellipseParams;
+
 use std::f64::INFINITY;
 
+use ::common::num_traits::Float;
+use std::f64::consts::PI;
+use std::cmp::Ordering;
+use std::fmt;
+
 /// The shape.
 #[derive(Debug)]
 pub enum Shape
 {
     Circle(Circle),
     Rectle(Rectle),
+
+    /// Neither model fits the points well, e.g because the group is actually
+    /// two obstacles that have merged together. `score` is the best score that
+    /// either model managed.
+    Unknown { score: Num },
+}
+
+
+impl Shape
+{
+    /// The centre of the shape, if it has one.
+    pub fn centre(&self) -> Option<Point>
+    {
+        match *self
+        {
+            Shape::Circle(ref c) => Some(c.centre),
+            Shape::Rectle(ref r) => Some(r.centre),
+            Shape::Unknown { .. } => None,
+        }
+    }
+
+    /// How well the shape fit the points it came from; lower is better.
+    ///
+    /// This is the root-mean-square distance of the points from the outline,
+    /// as a fraction of the distance from the centre to the outline (see
+    /// `radial_error`), so that it means the same for every kind of shape; e.g
+    /// `0.1` is about 10% out either way.
+    pub fn score(&self) -> Num
+    {
+        match *self
+        {
+            Shape::Circle(ref c) => c.score,
+            Shape::Rectle(ref r) => r.score,
+            Shape::Unknown { score } => score,
+        }
+    }
+
+    /// The area of the shape, in square metres.
+    pub fn area(&self) -> Num
+    {
+        match *self
+        {
+            Shape::Circle(ref c) => c.area(),
+            Shape::Rectle(ref r) => r.area(),
+            Shape::Unknown { .. } => 0.0,
+        }
+    }
+
+    /// The radius of the smallest circle about the centre that the whole shape
+    /// fits inside, in metres: the radius of a circle, or half the diagonal of
+    /// a rectangle, whichever way round it is. A point further than this from
+    /// the centre can't be inside the shape, which is much cheaper to check
+    /// than `contains`.
+    pub fn enclosing_radius(&self) -> Num
+    {
+        match *self
+        {
+            Shape::Circle(ref c) => c.radius,
+            Shape::Rectle(ref r) => r.width.hypot(r.length),
+            Shape::Unknown { .. } => 0.0,
+        }
+    }
+
+    /// Whether the point lies inside (or on the edge of) the shape.
+    pub fn contains(&self, point: Point) -> bool
+    {
+        match *self
+        {
+            Shape::Circle(ref c) => (point.0 - c.centre.0).hypot(point.1 - c.centre.1) <= c.radius,
+
+            Shape::Rectle(ref r) =>
+            {
+                let f = point.0 - r.centre.0;
+                let g = point.1 - r.centre.1;
+
+                let R = f*r.rotation.cos() + g*r.rotation.sin();
+                let C = g*r.rotation.cos() - f*r.rotation.sin();
+
+                (R / r.width).powi(2*r.sharpness) + (C / r.length).powi(2*r.sharpness) <= 1.0
+            },
+
+            Shape::Unknown { .. } => false,
+        }
+    }
+
+    // a box (min x, min y, max x, max y) that the shape fits inside, though
+    // not necessarily snugly.
+    fn bounds(&self) -> Option<(Num, Num, Num, Num)>
+    {
+        let centre = self.centre()?;
+        let reach = self.enclosing_radius();
+
+        Some((centre.0 - reach, centre.1 - reach, centre.0 + reach, centre.1 + reach))
+    }
 }
 
+impl fmt::Display for Shape
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Shape::Circle(ref c) => write!(f, "{}", c),
+            Shape::Rectle(ref r) => write!(f, "{}", r),
+            Shape::Unknown { score } => write!(f, "Unknown shape, best score={:.4}", score),
+        }
+    }
+}
 
 /// A circle.
 #[derive(Debug)]
@@ -117,6 +233,44 @@ impl Circle
             score:  INFINITY,
         }
     }
+
+    /// The area of the circle, in square metres.
+    pub fn area(&self) -> Num
+    {
+        PI * self.radius.powi(2)
+    }
+
+    /// The parameters of the model that describe this circle.
+    pub fn params(&self) -> SuperellipseParams
+    {
+        SuperellipseParams::circle(self.centre.0, self.centre.1, self.radius)
+    }
+
+    /// The circle with the given parameters and score. A circle is round, so
+    /// only `a` is used for the radius; `b`, `t` and `s` are ignored.
+    pub fn with_params(params: SuperellipseParams, score: Num) -> Self
+    {
+        Circle { centre: (params.p, params.q), radius: params.a, score }
+    }
+
+    /// Checks whether two circles are the same, to within `tol`. The scores
+    /// are not compared.
+    pub fn approx_eq(&self, other: &Circle, tol: Num) -> bool
+    {
+        (self.centre.0 - other.centre.0).abs() <= tol &&
+        (self.centre.1 - other.centre.1).abs() <= tol &&
+        (self.radius   - other.radius  ).abs() <= tol
+    }
+}
+
+/// e.g `Circle r=0.25m at (1.20, -0.30) score=0.0010`
+impl fmt::Display for Circle
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Circle r={:.2}m at ({:.2}, {:.2}) score={:.4}",
+            self.radius, self.centre.0, self.centre.1, self.score)
+    }
 }
 
 /// A Rectangle
@@ -127,141 +281,2044 @@ pub struct Rectle
     pub width: Num,
     pub length: Num,
     pub rotation: Num,
-    pub score: Num
+    pub score: Num,
+
+    /// How sharp the corners are; `s` in the model equation. `1` is an
+    /// ellipse, and the corners get sharper as it goes up. See `fit_rectle`.
+    pub sharpness: i32,
 }
 
 impl Rectle
 {
-    fn from(points: &Points, a: Num, b: Num, p: Num, q: Num, t: Num) -> Self
+    /// Note that the sides are put in canonical order, so that `width` is never
+    /// more than `length`; see `canonical_sides`.
+    fn scored(a: Num, b: Num, p: Num, q: Num, t: Num, score: Num) -> Self
     {
+        let (a, b, t) = canonical_sides(a, b, t);
+
         Rectle
         {
             centre: (p, q),
             width: a,
             length: b,
             rotation: t,
-            score: ht_score(points, a, b, p, q, t, 6),
+            score,
+            sharpness: RECTLE_SHARPNESS,
+        }
+    }
+
+    /// The area of the rectangle, in square metres, with the corners rounded
+    /// off by however much the sharpness rounds them; see
+    /// `SuperellipseParams::area`.
+    ///
+    /// In the model equation, `a` and `b` (i.e `width` and `length`) are the
+    /// distances from the centre to the sides, so the sides are actually twice
+    /// as long.
+    pub fn area(&self) -> Num
+    {
+        self.params().area()
+    }
+
+    /// The parameters of the model that describe this rectangle.
+    pub fn params(&self) -> SuperellipseParams
+    {
+        SuperellipseParams
+        {
+            a: self.width,
+            b: self.length,
+            p: self.centre.0,
+            q: self.centre.1,
+            t: self.rotation,
+            s: self.sharpness as Num,
+        }
+    }
+
+    /// The rectangle with the given parameters and score. The sides are kept
+    /// as they are, rather than being put in canonical order.
+    pub fn with_params(params: SuperellipseParams, score: Num) -> Self
+    {
+        Rectle
+        {
+            centre: (params.p, params.q),
+            width: params.a,
+            length: params.b,
+            rotation: params.t,
+            score,
+            sharpness: params.sharpness(),
         }
     }
+
+    /// Checks whether two rectangles are the same, to within `tol`. The scores
+    /// are not compared.
+    ///
+    /// A rectangle turned by half a turn is the same rectangle, so rotations
+    /// are compared modulo `pi`; e.g `0`, `pi`, `-pi` and `100*pi` are all
+    /// considered equal.
+    pub fn approx_eq(&self, other: &Rectle, tol: Num) -> bool
+    {
+        // doubling the angles turns half a turn into a full one, which
+        // `angle_diff` already wraps.
+        let dt = (angle_diff(2.0 * self.rotation, 2.0 * other.rotation) / 2.0).abs();
+
+        (self.centre.0 - other.centre.0).abs() <= tol &&
+        (self.centre.1 - other.centre.1).abs() <= tol &&
+        (self.width    - other.width   ).abs() <= tol &&
+        (self.length   - other.length  ).abs() <= tol &&
+        dt <= tol
+    }
 }
 
 
-/// Hough-transform inspired parameter search.
-pub fn hough_transform(points: &Points, start: Point, a: Num, b: Num) -> Shape
+/// e.g `Rectangle 0.60m x 0.40m at (1.20, -0.30) rotated 30.0° s=6 score=0.0020`
+///
+/// The sizes given are the full lengths of the sides, i.e twice `width` and
+/// `length`.
+impl fmt::Display for Rectle
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Rectangle {:.2}m x {:.2}m at ({:.2}, {:.2}) rotated {:.1}° s={} score={:.4}",
+            2.0 * self.width, 2.0 * self.length, self.centre.0, self.centre.1,
+            self.rotation.to_degrees(), self.sharpness, self.score)
+    }
+}
+
+
+/// The cells of the map that the shape covers, along with how much of each
+/// cell is covered, as a cost from `0` to `100` like in an `OccupancyGrid`.
+/// This is handy for stamping the detected obstacles into a costmap.
+///
+/// Each cell is checked at its centre and at its four corners with
+/// `Shape::contains`, and the cost is the fraction of those that are inside
+/// the shape; cells that the shape doesn't touch at all are left out. So the
+/// cells inside the shape get `100`, and the cells along its outline get
+/// something less.
+pub fn rasterize(shape: &Shape, map: &Map) -> Vec<(MapPoint, i8)>
 {
-    println!("HT starting from position: {:?}, a: {}, b: {}", start, a, b);
+    let (x0, y0, x1, y1) = match shape.bounds()
+    {
+        Some(b) => b,
+        None => return Vec::new(),
+    };
+
+    let res = map_utils::Resolution::of(map);
+
+    let last = (map.info.height as usize, map.info.width as usize);
+
+    if last.0 == 0 || last.1 == 0 || !(res.x > 0.0) || !(res.y > 0.0)
+    {
+        return Vec::new();
+    }
+
+    // the corners of the bounding box, clipped to the map. The centres of the
+    // cells around the edge of the map are as far out as the box can reach, so
+    // that `cell_at` always finds a cell.
+    let edges: Vec<Point> = map_utils::transform(map, vec![(0, 0), (last.0 - 1, last.1 - 1)]);
+
+    let (xmin, ymax) = (edges[0].0 + res.x / 2.0, edges[0].1 - res.y / 2.0);
+    let (xmax, ymin) = (edges[1].0 + res.x / 2.0, edges[1].1 - res.y / 2.0);
+
+    let clip = |v: Num, min: Num, max: Num| v.max(min).min(max);
 
-    // circles add the constraint that a == b, which restricts the size of the
-    // parameter space. This makes the parameter search a lot easier, so we
-    // do this one first.
-    let circle = fit_circle(points, start, a+b / 2.0);
+    let top_left     = map_utils::cell_at(map, (clip(x0, xmin, xmax), clip(y1, ymin, ymax)));
+    let bottom_right = map_utils::cell_at(map, (clip(x1, xmin, xmax), clip(y0, ymin, ymax)));
 
-    // early return if it looks like a circle
-    if circle.score < 0.002 { return Shape::Circle(circle) }
+    let ((top, left), (bottom, right)) = match (top_left, bottom_right)
+    {
+        (Some(top_left), Some(bottom_right)) => (top_left, bottom_right),
+        _ => return Vec::new(),
+    };
+
+    let covered: Vec<MapPoint> = (top..bottom + 1)
+    .flat_map(|row| (left..right + 1).map(move |col| (row, col)))
+    .collect();
 
-    // otherwise, check for rectangle
-    let rectle = fit_rectle(points, start, a, b);
+    // the top-left corner of each cell.
+    let corners: Vec<Point> = map_utils::transform(map, covered.iter().cloned());
 
-    // we want the min of the scores
-    if rectle.score < circle.score
+    let mut cells = Vec::new();
+
+    for (&cell, &(x, y)) in covered.iter().zip(corners.iter())
     {
-        return Shape::Rectle(rectle);
+        let samples = [
+            (x + res.x / 2.0, y - res.y / 2.0),
+            (x,         y),
+            (x + res.x, y),
+            (x,         y - res.y),
+            (x + res.x, y - res.y),
+        ];
+
+        let inside = samples.iter().filter(|&&p| shape.contains(p)).count();
+
+        if inside > 0
+        {
+            cells.push((cell, (100 * inside / samples.len()) as i8));
+        }
     }
 
-    return Shape::Circle(circle);
+    cells
 }
 
-fn fit_rectle(points: &Points, start: Point, a: Num, b: Num) -> Rectle
+/// Folds the rotation of a rectangle into `[0, pi/2)`.
+///
+/// Turning a rectangle by a quarter-turn is the same as swapping its width and
+/// length, so every rectangle can be described with a rotation in that range.
+/// Without this, the same rectangle could come back with any of several
+/// different rotations.
+pub fn fold_rotation(a: Num, b: Num, t: Num) -> (Num, Num, Num)
 {
-    println!("fit rectle");
+    let quarter = PI / 2.0;
 
-    let p = start.0;
-    let q = start.1;
+    let turns = (t / quarter).floor();
+    let mut t = t - turns * quarter;
+
+    // an odd number of quarter-turns swaps the sides.
+    let mut swap = turns % 2.0 != 0.0;
+
+    // rounding can leave us sitting right on the upper bound.
+    if t >= quarter
+    {
+        t -= quarter;
+        swap = !swap;
+    }
+
+    if swap { (b, a, t) } else { (a, b, t) }
+}
 
-    let pq_width = 0.020;
-    let ab_width = 0.020;
+/// Puts the sides of a rectangle in order, so that `a <= b`, with the rotation
+/// in `[0, pi)`.
+///
+/// `fold_rotation` alone still leaves two ways of describing each rectangle
+/// (e.g `0.3 x 0.5` or `0.5 x 0.3` turned a quarter-turn), depending on which
+/// side the search happened to settle on. This picks the one where the first
+/// side is the shorter, which is turned an extra quarter-turn if need be.
+pub fn canonical_sides(a: Num, b: Num, t: Num) -> (Num, Num, Num)
+{
+    let (a, b, t) = fold_rotation(a, b, t);
 
-    // generate the parameter sets in parallel.
-    let min: Rectle            = range(a - ab_width, a + ab_width, 0.010).into_par_iter()
-    .flat_map(|aa              | range(b - ab_width, b + ab_width, 0.010).into_par_iter().map(|bb| (aa, bb)             ).collect::<Vec<_>>())
-    .flat_map(|(aa, bb)        | range(p - pq_width, p + pq_width, 0.010).into_par_iter().map(|pp| (aa, bb, pp)         ).collect::<Vec<_>>())
-    .flat_map(|(aa, bb, pp)    | range(q - pq_width, q + pq_width, 0.010).into_par_iter().map(|qq| (aa, bb, pp, qq)     ).collect::<Vec<_>>())
-    .flat_map(|(aa, bb, pp, qq)| range(         0.0,        1.574, 0.010).into_par_iter().map(|tt| (aa, bb, pp, qq, tt) ).collect::<Vec<_>>())
-    .map(|(a, b, p, q, t)| Rectle::from(points, a, b, p, q, t))
-    .min_by(|a,b| a.score.partial_cmp(&b.score).unwrap()).unwrap();
+    if a <= b { (a, b, t) } else { (b, a, t + PI / 2.0) }
+}
 
-    println!("min rectle: {:?} (rot: {})", min, min.rotation.to_degrees());
+/// How far either side of the rotation seed to search, in radians.
+const ROTATION_WINDOW: Num = 0.1;
 
-    min
+/// Hough-transform inspired parameter search.
+///
+/// If an estimate of the orientation is known (e.g from
+/// `map_utils::group_principal_axis`), then it can be given as
+/// `rotation_seed`, and the rectangle search only looks at rotations near it.
+/// Only give one if the points are clearly longer one way than the other (see
+/// `map_utils::group_anisotropy`); the principal axis of a square could be
+/// any which way, and the right rotation is then likely to be outside the
+/// window.
+///
+/// If the best score is worse (higher) than `reject_threshold`, then the
+/// points don't really look like either shape, and `Shape::Unknown` is
+/// returned instead.
+///
+/// This is `classify` with a margin of `DEFAULT_MARGIN`, keeping only the
+/// chosen shape.
+pub fn hough_transform(points: &Points, start: Point, a: Num, b: Num, rotation_seed: Option<Num>, reject_threshold: Num) -> Shape
+{
+    classify(points, start, a, b, rotation_seed, reject_threshold, DEFAULT_MARGIN).best
 }
 
-fn fit_circle(points: &Points, start: Point, r: Num) -> Circle
+/// Like `hough_transform`, but for a group of cells straight from the map (see
+/// `map_utils::describe_groups`), with the search started from `group_seed`.
+/// The search steps are matched to the resolution of the map.
+pub fn hough_transform_group(map: &Map, group: &Group, rotation_seed: Option<Num>, reject_threshold: Num) -> Shape
 {
-    println!("fit circle");
+    let points: Points = map_utils::transform(map, group.points.iter().cloned());
+    let (start, a, b) = group_seed(group, &points, rotation_seed);
 
-    let mut min = Circle::new();
+    let steps = SearchSteps::for_resolution(map.info.resolution as Num);
+
+    classify_with(&points, start, a, b, rotation_seed, reject_threshold, DEFAULT_MARGIN, steps).best
+}
+
+/// How finely `group_seed` turns the box around a group, looking for the
+/// snuggest one, in radians.
+const SEED_BOX_STEP: Num = 0.0175;
 
-    for rr in range(r - 0.1, r + 0.1, 0.01)
+/// Where to start the search for the shape of a group: its centroid, along
+/// with the half-sides `a` and `b` of the box around `points` (the group's
+/// cells, in map coordinates).
+///
+/// The box is lined up with `rotation_seed` if there is one, since that's the
+/// rotation that the rectangle search will be looking at. Otherwise it's the
+/// box with the smallest area, at whichever rotation that turns out to be; a
+/// box that is square to the map would be far too big for a square obstacle
+/// turned by 45°.
+pub fn group_seed(group: &Group, points: &Points, rotation_seed: Option<Num>) -> (Point, Num, Num)
+{
+    let t = match rotation_seed
     {
-        for pp in range(start.0 - 0.3, start.0 + 0.3, 0.02)
-        {
-            for qq in range(start.1 - 0.3, start.1 + 0.3, 0.02)
+        Some(t) => t,
+        None => range_iter(0.0, PI / 2.0, SEED_BOX_STEP)
+            .min_by(|&t1, &t2|
             {
-                let score = ht_score(points, rr, rr, pp, qq, 0.0, 1);
+                let area = |t: Num| { let (a, b) = box_along(points, t); a * b };
+                cmp_score(area(t1), area(t2))
+            })
+            .unwrap_or(0.0),
+    };
 
-                if score < min.score
-                {
-                    min.centre = (pp, qq);
-                    min.radius = rr;
-                    min.score  = score;
-                }
-            }
+    let (a, b) = box_along(points, t);
+
+    (group.centroid, a, b)
+}
+
+// the half-sides of the box around the points, with its first side turned by
+// `t`.
+fn box_along(points: &Points, t: Num) -> (Num, Num)
+{
+    let (sin, cos) = t.sin_cos();
+
+    let (umin, umax, vmin, vmax) = points.iter()
+    .fold((INFINITY, -INFINITY, INFINITY, -INFINITY), |(umin, umax, vmin, vmax), &(x, y)|
+    {
+        let u = x*cos + y*sin;
+        let v = y*cos - x*sin;
+
+        (umin.min(u), umax.max(u), vmin.min(v), vmax.max(v))
+    });
+
+    if umin > umax { return (0.0, 0.0); }
+
+    ((umax - umin) / 2.0, (vmax - vmin) / 2.0)
+}
+
+/// The kinds of shape that `classify` tries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShapeKind
+{
+    Circle,
+
+    /// An ellipse is given as a `Rectle` with a sharpness of `1`.
+    Ellipse,
+
+    Rectle,
+}
+
+/// The result of `classify`: the chosen shape, along with the score of every
+/// kind of shape that was tried, for diagnostics.
+#[derive(Debug)]
+pub struct Classification
+{
+    /// The chosen shape, or `Shape::Unknown` if nothing fit well enough.
+    pub best: Shape,
+
+    /// The best score for each kind of shape, simplest shape first.
+    pub candidates: Vec<(ShapeKind, Num)>,
+
+    /// How many shapes were scored against the points along the way, which is
+    /// most of the work; e.g a rotation seed cuts this right down.
+    pub evaluations: usize,
+}
+
+/// The default margin for `classify`.
+pub const DEFAULT_MARGIN: Num = 0.01;
+
+/// How finely the parameter search steps through positions and sizes.
+///
+/// There's no point stepping much more finely than the cells of the map, since
+/// the points only come at the centres of cells anyway, and stepping much more
+/// coarsely throws away detail that the map does have. On a very fine map, the
+/// step is widened for any parameter that would otherwise be tried more than
+/// `MAX_STEPS_PER_PARAMETER` times.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchSteps
+{
+    /// The step, in metres, for the centre of a rectangle and the sides of a
+    /// rectangle or radius of a circle. The centre of a circle is searched over
+    /// a wider area, so it uses twice this.
+    pub step: Num,
+}
+
+/// How many search steps fit in a cell of the map, for `SearchSteps::for_resolution`.
+const STEPS_PER_CELL: Num = 5.0;
+
+impl SearchSteps
+{
+    /// Steps that suit a map with cells `resolution` metres across. A
+    /// resolution that isn't a positive number gets the default steps, since a
+    /// step of zero would never finish.
+    pub fn for_resolution(resolution: Num) -> Self
+    {
+        if !(resolution > 0.0) || !resolution.is_finite()
+        {
+            return SearchSteps::default();
         }
+
+        SearchSteps { step: resolution / STEPS_PER_CELL }
     }
+}
 
-    println!("min circle: {:?}", min);
+impl Default for SearchSteps
+{
+    /// The steps for the usual 5cm cells from `gmapping`, i.e 1cm.
+    fn default() -> Self
+    {
+        SearchSteps { step: 0.05 / STEPS_PER_CELL }
+    }
+}
 
-    min
+/// Fits a circle, an ellipse and a rectangle to the points, and picks one.
+///
+/// A more complicated shape can always fit the points at least about as well
+/// as a simpler one, so rather than just taking the lowest score, this takes
+/// the simplest shape (circle, then ellipse, then rectangle) whose score is
+/// within `margin` of the best. See `hough_transform` for the rest of the
+/// parameters.
+///
+/// This uses the default `SearchSteps`; see `classify_with` for maps with a
+/// different resolution.
+pub fn classify(points: &Points, start: Point, a: Num, b: Num, rotation_seed: Option<Num>, reject_threshold: Num, margin: Num) -> Classification
+{
+    classify_with(points, start, a, b, rotation_seed, reject_threshold, margin, SearchSteps::default())
 }
 
-/// Evaluates the score of the model against the points, given the parameters.
-/// Lower is better.
-fn ht_score(points: &Points, a: Num, b: Num, p: Num, q: Num, t: Num, s: i32) -> Num
+/// Like `classify`, but searches with the given steps.
+pub fn classify_with(points: &Points, start: Point, a: Num, b: Num, rotation_seed: Option<Num>, reject_threshold: Num, margin: Num, steps: SearchSteps) -> Classification
+{
+    classify_with_prior(points, start, a, b, rotation_seed, reject_threshold, margin, steps, None)
+}
+
+/// Like `classify_with`, but only considers shapes whose size is within
+/// `size_prior`, given as `(min, max)` in metres, e.g when the obstacles are
+/// known to be 20-40cm across. The bounds apply to the radius of a circle and
+/// to both `width` and `length` of a rectangle, which are also measured from
+/// the centre, so they're half of the size across.
+///
+/// Sizes outside of the bounds aren't searched, so a group that would be best
+/// fit by a shape that is too big or too small gets the best shape within the
+/// bounds instead, or `Shape::Unknown` if there isn't one that fits well
+/// enough. With `None`, any size goes.
+pub fn classify_with_prior(points: &Points, start: Point, a: Num, b: Num, rotation_seed: Option<Num>, reject_threshold: Num, margin: Num, steps: SearchSteps, size_prior: Option<(Num, Num)>) -> Classification
 {
-    let f = |x: Num| x - p;
-    let g = |y: Num| y - q;
+    println!("HT starting from position: {:?}, a: {}, b: {}, rotation seed: {:?}", start, a, b, rotation_seed);
 
-    let R = |x: Num, y: Num| f(x)*t.cos() + g(y)*t.sin();
-    let C = |x: Num, y: Num| g(y)*t.cos() - f(x)*t.sin();
+    let (circle, circle_evaluations) = fit_circle(points, start, (a + b) / 2.0, steps, size_prior);
+    let (rectle, rectle_evaluations) = fit_rectle(points, start, a, b, rotation_seed, steps, size_prior);
 
-    let A = |x: Num, y: Num| R(x,y) / a;
-    let B = |x: Num, y: Num| C(x,y) / b;
+    // and one more for the ellipse.
+    let evaluations = circle_evaluations + rectle_evaluations + 1;
 
-    let X = |x: Num, y: Num| A(x,y).powi(2*s);
-    let Y = |x: Num, y: Num| B(x,y).powi(2*s);
+    // an ellipse is a rectangle with the sharpest corners rounded right off,
+    // so try that with the size and orientation of the best rectangle.
+    let ellipse = Rectle
+    {
+        centre: rectle.centre,
+        width: rectle.width,
+        length: rectle.length,
+        rotation: rectle.rotation,
+        score: radial_error(points, &SuperellipseParams { s: 1.0, ..rectle.params() }),
+        sharpness: 1,
+    };
 
-    let M = |p: &(Num, Num)| (X(p.0, p.1) + Y(p.0, p.1) - 1.0).powi(2) / (X(p.0, p.1) + Y(p.0, p.1));
+    let candidates = vec![
+        (ShapeKind::Circle,  circle.score),
+        (ShapeKind::Ellipse, ellipse.score),
+        (ShapeKind::Rectle,  rectle.score),
+    ];
 
-    let len = points.len() as Num;
+    let best_score = candidates.iter().map(|c| c.1).min_by(|a, b| cmp_score(*a, *b)).unwrap();
+
+    // written this way around so that a NaN score is rejected too.
+    if !(best_score <= reject_threshold)
+    {
+        return Classification { best: Shape::Unknown { score: best_score }, candidates, evaluations };
+    }
+
+    let kind = candidates.iter()
+    .find(|c| c.1 <= best_score + margin)
+    .map(|c| c.0)
+    .unwrap_or(ShapeKind::Rectle);
 
-    let T = |p: &(Num, Num)| (M(p) / s as Num).tanh() / len;
+    let best = match kind
+    {
+        ShapeKind::Circle  => Shape::Circle(circle),
+        ShapeKind::Ellipse => Shape::Rectle(ellipse),
+        ShapeKind::Rectle  => Shape::Rectle(rectle),
+    };
 
-    return points.par_iter().map(T).sum();
+    Classification { best, candidates, evaluations }
 }
 
+/// The estimated variance of each parameter of a fitted shape, e.g for feeding
+/// into a tracker.
+#[derive(Debug)]
+pub struct Variance
+{
+    /// The variance of the centre, along x and y.
+    pub centre: (Num, Num),
 
-// generates a range.
-fn range(start: Num, stop: Num, step: Num) -> Range
+    /// The variance of the width and length. For a circle, both of these are
+    /// the variance of the radius.
+    pub size: (Num, Num),
+
+    /// The variance of the rotation. Circles don't have a rotation.
+    pub rotation: Option<Num>,
+}
+
+/// Like `hough_transform`, but also estimates how certain the fit is.
+pub fn hough_transform_with_variance(points: &Points, start: Point, a: Num, b: Num, rotation_seed: Option<Num>, reject_threshold: Num) -> (Shape, Variance)
 {
-    let mut vec = Vec::new();
-    let mut acc = start;
+    let shape = hough_transform(points, start, a, b, rotation_seed, reject_threshold);
+    let variance = variance(points, &shape);
 
-    while acc < stop
+    (shape, variance)
+}
+
+/// The step used for the finite differences in `variance`.
+const CURVATURE_STEP: Num = 0.005;
+
+/// Estimates the variance of the parameters of a fitted shape, from the
+/// curvature of the score around the fit.
+///
+/// If the score rises sharply as a parameter moves away from the fitted value,
+/// then that parameter is well-determined by the points; if the score is flat,
+/// it isn't. Treating the summed score like a chi-squared value, the variance
+/// of each parameter is approximately `2 / (n * d2S/dx2)`, where `S` is the
+/// score and `n` the number of points. A parameter along which the score is
+/// not curved upwards gets an infinite variance.
+pub fn variance(points: &Points, shape: &Shape) -> Variance
+{
+    let n = points.len() as Num;
+
+    match *shape
     {
-        vec.push(acc);
-        acc += step;
+        Shape::Circle(ref c) =>
+        {
+            let (p, q) = c.centre;
+            let r = c.radius;
+
+            let score = |r: Num, p: Num, q: Num| ht_score(points, r, r, p, q, 0.0, 1, true);
+
+            let vr = variance_along(|x| score(x, p, q), r, n);
+
+            Variance
+            {
+                centre: (variance_along(|x| score(r, x, q), p, n), variance_along(|x| score(r, p, x), q, n)),
+                size: (vr, vr),
+                rotation: None,
+            }
+        },
+
+        Shape::Rectle(ref rect) =>
+        {
+            let (p, q) = rect.centre;
+            let (a, b, t) = (rect.width, rect.length, rect.rotation);
+
+            let score = |a: Num, b: Num, p: Num, q: Num, t: Num| ht_score(points, a, b, p, q, t, rect.sharpness, true);
+
+            Variance
+            {
+                centre: (variance_along(|x| score(a, b, x, q, t), p, n), variance_along(|x| score(a, b, p, x, t), q, n)),
+                size: (variance_along(|x| score(x, b, p, q, t), a, n), variance_along(|x| score(a, x, p, q, t), b, n)),
+                rotation: Some(variance_along(|x| score(a, b, p, q, x), t, n)),
+            }
+        },
+
+        Shape::Unknown { .. } => Variance
+        {
+            centre: (INFINITY, INFINITY),
+            size: (INFINITY, INFINITY),
+            rotation: None,
+        },
     }
+}
+
+// estimates the variance of a single parameter from the curvature of the score
+// `f` around the fitted value `x`.
+fn variance_along<F: Fn(Num) -> Num>(f: F, x: Num, n: Num) -> Num
+{
+    let h = CURVATURE_STEP;
+
+    let curvature = (f(x + h) - 2.0 * f(x) + f(x - h)) / (h * h);
+
+    if curvature > 0.0 { 2.0 / (n * curvature) } else { INFINITY }
+}
+
+/// The sharpness that rectangles are searched for (and scored) with.
+const RECTLE_SHARPNESS: i32 = 6;
+
+/// The sharpnesses that are tried once the rectangle has been found.
+const SHARPNESSES: [i32; 4] = [1, 2, 4, 8];
+
+/// How far either side of `start` `fit_rectle` searches for the centre, in
+/// metres.
+const CENTRE_WINDOW: Num = 0.02;
+
+/// How far either side of each seeded side `fit_rectle` searches, as a
+/// fraction of that side. A bigger obstacle's sides are seeded less accurately
+/// (in metres), so it gets a wider window; see `side_window`.
+const SIDE_WINDOW_FRACTION: Num = 0.1;
+
+/// The narrowest window that `fit_rectle` searches either side of each side,
+/// in metres.
+const MIN_SIDE_WINDOW: Num = 0.02;
+
+// how far either side of the seeded side `side` to search.
+fn side_window(side: Num) -> Num
+{
+    (SIDE_WINDOW_FRACTION * side).max(MIN_SIDE_WINDOW)
+}
+
+/// Searches for the rectangle that best fits the points, and says how many
+/// rectangles it scored along the way.
+///
+/// The search itself is done with a fixed sharpness, so that the scores of all
+/// rectangles are comparable. Once the best rectangle is found, each of
+/// `SHARPNESSES` is tried against it, and the one that fits the points best is
+/// stored in the result, along with its `radial_error` at that sharpness; a
+/// rectangle with rounded corners ends up with a lower sharpness than one with
+/// sharp corners.
+///
+/// Only sides within `size_prior` are tried; see `classify_with_prior`. If
+/// there are none, the result has an infinite score.
+fn fit_rectle(points: &Points, start: Point, a: Num, b: Num, rotation_seed: Option<Num>, steps: SearchSteps, size_prior: Option<(Num, Num)>) -> (Rectle, usize)
+{
+    println!("fit rectle");
+
+    let p = start.0;
+    let q = start.1;
+
+    // search around the nearest size that is allowed.
+    let a = clamp_to_prior(a, size_prior);
+    let b = clamp_to_prior(b, size_prior);
+
+    // without a seed we have to check the whole quarter-turn.
+    let (t_start, t_stop) = match rotation_seed
+    {
+        Some(t) => (t - ROTATION_WINDOW, t + ROTATION_WINDOW),
+        None    => (0.0, 1.574),
+    };
+
+    let step = steps.step;
+
+    let aa = search_range(a - side_window(a), a + side_window(a), step);
+    let bb = search_range(b - side_window(b), b + side_window(b), step);
+    let pp = search_range(p - CENTRE_WINDOW, p + CENTRE_WINDOW, step);
+    let qq = search_range(q - CENTRE_WINDOW, q + CENTRE_WINDOW, step);
+
+    // the rotation axis is the biggest, so that's the one that gets split
+    // across threads. The rest of the grid is scored in one batch for each
+    // rotation.
+    let per_rotation: Vec<(Option<Rectle>, usize)> = range(t_start, t_stop, 0.010).into_par_iter()
+    .map(|t|
+    {
+        let candidates: Vec<SuperellipseParams> = grid(aa.clone(), bb.clone(), pp.clone(), qq.clone())
+        .filter(|&(a, b, _, _)| in_prior(a, size_prior) && in_prior(b, size_prior))
+        .map(|(a, b, p, q)| SuperellipseParams { a, b, p, q, t, s: RECTLE_SHARPNESS as Num })
+        .collect();
+
+        let best = candidates.iter().zip(ht_score_batch(points, &candidates, true))
+        .map(|(c, score)| Rectle::scored(c.a, c.b, c.p, c.q, c.t, score))
+        .min_by(|a,b| cmp_score(a.score, b.score));
+
+        (best, candidates.len())
+    })
+    .collect();
 
-    vec
+    let evaluations = per_rotation.iter().map(|r| r.1).sum::<usize>();
+
+    let best = per_rotation.into_iter()
+    .filter_map(|r| r.0)
+    .min_by(|a,b| cmp_score(a.score, b.score));
+
+    let mut min = match best
+    {
+        Some(best) => best,
+        None => return (Rectle::scored(a, b, p, q, t_start, INFINITY), evaluations),
+    };
+
+    let params = min.params();
+    let with_sharpness = |s: i32| SuperellipseParams { s: s as Num, ..params };
+
+    min.sharpness = SHARPNESSES.iter().cloned()
+    .min_by(|s1, s2| cmp_score(radial_error(points, &with_sharpness(*s1)), radial_error(points, &with_sharpness(*s2))))
+    .unwrap();
+
+    // the search scored it with `ht_score`, which can't be compared with the
+    // other shapes, or even between sharpnesses.
+    min.score = radial_error(points, &min.params());
+
+    println!("min rectle: {:?} (rot: {})", min, min.rotation.to_degrees());
+
+    (min, evaluations + SHARPNESSES.len())
+}
+
+/// How far either side of `start` `fit_circle` searches for the centre, when
+/// there is no better seed.
+const CIRCLE_WINDOW: Num = 0.3;
+
+/// How far either side of the seed from `least_squares_circle` `fit_circle`
+/// searches for the centre. The seed is usually within a cell or two of the
+/// answer, so this can be much smaller than `CIRCLE_WINDOW`.
+const CIRCLE_SEED_WINDOW: Num = 0.1;
+
+// whether a size is allowed by the prior; see `classify_with_prior`.
+fn in_prior(size: Num, size_prior: Option<(Num, Num)>) -> bool
+{
+    size_prior.map_or(true, |(min, max)| size >= min && size <= max)
 }
 
+// the allowed size nearest to `size`.
+fn clamp_to_prior(size: Num, size_prior: Option<(Num, Num)>) -> Num
+{
+    size_prior.map_or(size, |(min, max)| size.max(min).min(max))
+}
+
+/// Searches for the circle that best fits the points, and says how many circles
+/// it scored along the way.
+///
+/// The search starts from the circle that fits the points by least squares
+/// (see `least_squares_circle`), if there is one near `start`; otherwise it
+/// searches a wider box around `start`, with a radius of about `r`. Only radii
+/// within `size_prior` are tried; see `classify_with_prior`. Like `fit_rectle`,
+/// the circle that it finds is scored with `radial_error`.
+fn fit_circle(points: &Points, start: Point, r: Num, steps: SearchSteps, size_prior: Option<(Num, Num)>) -> (Circle, usize)
+{
+    println!("fit circle");
+
+    let seed = least_squares_circle(points)
+    .filter(|&(centre, _)| (centre.0 - start.0).abs() <= CIRCLE_WINDOW && (centre.1 - start.1).abs() <= CIRCLE_WINDOW);
+
+    let (start, r, window) = match seed
+    {
+        Some((centre, radius)) => (centre, radius, CIRCLE_SEED_WINDOW),
+        None => (start, r, CIRCLE_WINDOW),
+    };
+
+    let r = clamp_to_prior(r, size_prior);
+
+    let mut min = Circle::new();
+    let mut evaluations = 0;
+
+    for rr in search_range(r - 0.1, r + 0.1, steps.step).filter(|&rr| in_prior(rr, size_prior))
+    {
+        for pp in search_range(start.0 - window, start.0 + window, 2.0 * steps.step)
+        {
+            for qq in search_range(start.1 - window, start.1 + window, 2.0 * steps.step)
+            {
+                let score = ht_score(points, rr, rr, pp, qq, 0.0, 1, true);
+                evaluations += 1;
+
+                if score < min.score
+                {
+                    min.centre = (pp, qq);
+                    min.radius = rr;
+                    min.score  = score;
+                }
+            }
+        }
+    }
+
+    if min.score.is_finite()
+    {
+        min.score = radial_error(points, &min.params());
+    }
+
+    println!("min circle: {:?}", min);
+
+    (min, evaluations)
+}
+
+/// How many times the median distance from the first least-squares circle a
+/// point can be before `least_squares_circle` leaves it out of the second.
+const CIRCLE_SEED_TRIM: Num = 3.0;
+
+/// The circle that best fits the points of the group by least squares, as
+/// `(centre, radius)`, for seeding `fit_circle`.
+///
+/// A single stray cell can pull a least-squares fit well off, so the circle is
+/// fitted twice: the second time without the points that are more than
+/// `CIRCLE_SEED_TRIM` times the median distance from the first. Returns `None`
+/// if there are fewer than three points, or they all lie on a line.
+pub fn least_squares_circle(points: &Points) -> Option<(Point, Num)>
+{
+    let first = match fit_least_squares_circle(points.iter())
+    {
+        Some(circle) => circle,
+        None => return None,
+    };
+
+    let distance = |p: &Point| ((p.0 - (first.0).0).hypot(p.1 - (first.0).1) - first.1).abs();
+
+    let mut distances: Vec<Num> = points.iter().map(&distance).collect();
+    distances.sort_by(|a, b| cmp_score(*a, *b));
+    let median = distances[distances.len() / 2];
+
+    let kept = points.iter().filter(|p| distance(p) <= CIRCLE_SEED_TRIM * median);
+
+    fit_least_squares_circle(kept).or(Some(first))
+}
+
+// the algebraic (Kasa) least-squares circle through the points, worked out
+// about their centroid so that the sums stay small.
+fn fit_least_squares_circle<'a, I: Iterator<Item = &'a Point> + Clone>(points: I) -> Option<(Point, Num)>
+{
+    let n = points.clone().count();
+    if n < 3 { return None; }
+
+    let n = n as Num;
+    let centroid = (points.clone().map(|p| p.0).sum::<Num>() / n, points.clone().map(|p| p.1).sum::<Num>() / n);
+
+    let (mut suu, mut svv, mut suv, mut su, mut sv) = (0.0, 0.0, 0.0, 0.0, 0.0);
+
+    for p in points
+    {
+        let (u, v) = (p.0 - centroid.0, p.1 - centroid.1);
+
+        suu += u * u;
+        svv += v * v;
+        suv += u * v;
+        su  += u * (u * u + v * v);
+        sv  += v * (u * u + v * v);
+    }
+
+    let det = suu * svv - suv * suv;
+    if det.abs() < 1e-12 { return None; }
+
+    let uc = (su * svv - sv * suv) / (2.0 * det);
+    let vc = (sv * suu - su * suv) / (2.0 * det);
+
+    let centre = (uc + centroid.0, vc + centroid.1);
+    let radius = (uc * uc + vc * vc + (suu + svv) / n).sqrt();
+
+    Some((centre, radius))
+}
+
+/// Evaluates the score of the model against the points, given the parameters.
+/// Lower is better.
+///
+/// If `robust` is set, each point's contribution is squashed with `tanh`, so
+/// that outliers can't dominate the score. This is good for a coarse search,
+/// but it also flattens the score near the optimum; without it, the score is
+/// the plain mean of `M`, which has a much sharper minimum and is better for
+/// refining a fit that is already close.
+///
+/// This works in either `f32` or `f64`, e.g `ht_score::<f32>` for speed, or
+/// `ht_score::<f64>` for accuracy; usually it's just the same as the points.
+///
+/// The sharpness `s` must be at least `MIN_SHARPNESS`; anything lower is
+/// treated as `MIN_SHARPNESS`. With `s = 0`, every point would get the same
+/// score however well it fit, so the search would just pick its first guess.
+pub fn ht_score<N: Float + Send + Sync>(points: &[(N, N)], a: N, b: N, p: N, q: N, t: N, s: i32, robust: bool) -> N
+{
+    let T = ht_term(a, b, p, q, t, s, robust);
+
+    let len = N::from(points.len()).unwrap();
+
+    return par_sum(points, |p| T(p.0, p.1) / len);
+}
+
+/// How many points each thread sums at a time in `par_sum`.
+const SUM_CHUNK: usize = 256;
+
+// sums `f` over the items in parallel, always adding them up in the same order.
+//
+// A plain parallel `sum` splits the work up however the threads happen to be
+// scheduled, and since floating-point addition isn't associative the result
+// can change in the last few bits from one run to the next. That's enough to
+// flip which of two nearly-equal fits wins the search, so the same map could
+// give different shapes.
+fn par_sum<T: Sync, N: Float + Send, F: Fn(&T) -> N + Sync>(items: &[T], f: F) -> N
+{
+    let partial: Vec<N> = items.par_chunks(SUM_CHUNK)
+    .map(|chunk| chunk.iter().map(&f).fold(N::zero(), |acc, x| acc + x))
+    .collect();
+
+    partial.into_iter().fold(N::zero(), |acc, x| acc + x)
+}
+
+/// Scores each of the candidates against the points, like calling `ht_score`
+/// on each in turn (the scores are exactly the same), but in a single pass over
+/// the points.
+///
+/// Each point is checked against every candidate while it's at hand, rather
+/// than going through all of the points again for each candidate, which is
+/// much kinder to the cache when there are many points and lets the compiler
+/// vectorise the inner loop.
+pub fn ht_score_batch(points: &[(Num, Num)], candidates: &[SuperellipseParams], robust: bool) -> Vec<Num>
+{
+    let terms: Vec<_> = candidates.iter()
+    .map(|c| ht_term(c.a, c.b, c.p, c.q, c.t, c.sharpness(), robust))
+    .collect();
+
+    let len = points.len() as Num;
+
+    // summed in the same chunks and order as `par_sum`, so that the scores
+    // match `ht_score` to the last bit.
+    let partial: Vec<Vec<Num>> = points.par_chunks(SUM_CHUNK)
+    .map(|chunk|
+    {
+        let mut sums = vec![0.0; terms.len()];
+
+        for &(x, y) in chunk
+        {
+            for (sum, T) in sums.iter_mut().zip(terms.iter())
+            {
+                *sum = *sum + T(x, y) / len;
+            }
+        }
+
+        sums
+    })
+    .collect();
+
+    partial.into_iter().fold(vec![0.0; terms.len()], |mut total, sums|
+    {
+        for (t, s) in total.iter_mut().zip(sums) { *t = *t + s; }
+
+        total
+    })
+}
+
+/// Like `ht_score`, but each point carries a weight as its third element (e.g
+/// the occupancy value of the cell it came from), so that more trustworthy
+/// points count for more. The score is normalised by the total weight.
+/// Lower is better.
+pub fn ht_score_weighted(points_with_weight: &[(Num, Num, Num)], a: Num, b: Num, p: Num, q: Num, t: Num, s: i32) -> Num
+{
+    let T = ht_term(a, b, p, q, t, s, true);
+
+    // summed with `par_sum`, so that the score is the same on any number of
+    // threads.
+    let total = par_sum(points_with_weight, |&(x, y, w)| w * T(x, y));
+    let weight = par_sum(points_with_weight, |&(_, _, w)| w);
+
+    if weight == 0.0 { return INFINITY; }
+
+    return total / weight;
+}
+
+/// Like `ht_score`, but also gives the gradient of the score with respect to
+/// `(a, b, p, q, t, s)`, in that order, so that a fit from the Hough
+/// transform can be polished with a few steps of gradient descent.
+///
+/// The parameters are taken from `model`. The score rounds `s` to a whole
+/// number, like `ht_score` does, and the gradient is taken at that rounded
+/// `s` too, so that it's the gradient of the score that comes back. The
+/// derivative along `s` is that of the smooth model, so it still says which
+/// way to move `s`.
+pub fn ht_score_and_grad(points: &[(Num, Num)], model: &SuperellipseParams, robust: bool) -> (Num, [Num; 6])
+{
+    // the derivatives have to be of the model that is actually scored.
+    let mut model = *model;
+    model.s = model.sharpness().max(MIN_SHARPNESS) as Num;
+
+    let s = model.sharpness();
+    let T = ht_term(model.a, model.b, model.p, model.q, model.t, s, robust);
+    let s = s as Num;
+
+    let len = points.len() as Num;
+
+    // the score and the gradient of a single point, already divided by the
+    // number of points (like in `ht_score`, so the score comes out the same);
+    // see `ht_term`.
+    let term = |&(x, y): &(Num, Num)| -> [Num; 7]
+    {
+        let score = T(x, y);
+
+        let D = model.M(x, y) + 1.0;
+        if D == 0.0 || !D.is_finite() { return [score / len, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]; }
+
+        let M = (D - 1.0).powi(2) / D;
+        let dMdD = 1.0 - 1.0 / D.powi(2);
+
+        let dD = [model.dMda(x, y), model.dMdb(x, y), model.dMdp(x, y), model.dMdq(x, y), model.dMdt(x, y), model.dMds(x, y)];
+
+        let mut out = [score, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+
+        for (i, d) in dD.iter().enumerate()
+        {
+            out[i + 1] = if robust { (1.0 - score.powi(2)) * dMdD * d / s } else { dMdD * d };
+        }
+
+        // with `robust`, `s` also scales the term before it is squashed.
+        if robust { out[6] -= (1.0 - score.powi(2)) * M / s.powi(2); }
+
+        for value in out.iter_mut() { *value /= len; }
+
+        out
+    };
+
+    // summed in a fixed order, for the same reason as `par_sum`.
+    let add = |mut acc: [Num; 7], x: [Num; 7]| { for i in 0..7 { acc[i] += x[i]; } acc };
+
+    let partial: Vec<[Num; 7]> = points.par_chunks(SUM_CHUNK)
+    .map(|chunk| chunk.iter().map(&term).fold([0.0; 7], &add))
+    .collect();
+
+    let total = partial.into_iter().fold([0.0; 7], &add);
+
+    if len == 0.0 { return (0.0, [0.0; 6]); }
+
+    let mut grad = [0.0; 6];
+    grad.copy_from_slice(&total[1..]);
+
+    (total[0], grad)
+}
+
+/// The lowest sharpness that the score accepts; see `ht_score`.
+pub const MIN_SHARPNESS: i32 = 1;
+
+// the contribution of a single point to the score, before normalisation.
+fn ht_term<N: Float + Sync>(a: N, b: N, p: N, q: N, t: N, s: i32, robust: bool) -> impl Fn(N, N) -> N + Sync
+{
+    let s = s.max(MIN_SHARPNESS);
+
+    move |x: N, y: N|
+    {
+        let f = x - p;
+        let g = y - q;
+
+        let R = f*t.cos() + g*t.sin();
+        let C = g*t.cos() - f*t.sin();
+
+        let A = R / a;
+        let B = C / b;
+
+        let X = A.powi(2*s);
+        let Y = B.powi(2*s);
+
+        let D = X + Y;
+
+        // a point right at the centre gives `D == 0`, and a point far enough
+        // away (or a big enough `s`) can overflow `D` to infinity. Either way
+        // the point fits as badly as it possibly can, so rather than let an
+        // infinity or NaN poison the score, give it the worst possible value.
+        if D == N::zero() || !D.is_finite()
+        {
+            return if robust { N::one() } else { N::infinity() };
+        }
+
+        let M = (D - N::one()).powi(2) / D;
+
+        if robust { (M / N::from(s).unwrap()).tanh() } else { M }
+    }
+}
+
+// how far the points are from the outline of the shape, as the root mean square
+// over the points.
+//
+// Unlike `ht_score`, this can be compared between sharpnesses, and so between
+// kinds of shape: `D^(1/2s)` is how far out from the centre the point is, as a
+// multiple of the distance to the outline in the same direction, so it is `1`
+// on the outline for any `s`.
+fn radial_error(points: &Points, model: &SuperellipseParams) -> Num
+{
+    let SuperellipseParams { a, b, p, q, t, .. } = *model;
+    let s = model.sharpness();
+
+    let len = points.len() as Num;
+
+    par_sum(points, |&(x, y)|
+    {
+        let f = x - p;
+        let g = y - q;
+
+        let R = f*t.cos() + g*t.sin();
+        let C = g*t.cos() - f*t.sin();
+
+        let D = (R / a).powi(2*s) + (C / b).powi(2*s);
+
+        (D.powf(1.0 / (2*s) as Num) - 1.0).powi(2) / len
+    })
+    .sqrt()
+}
+
+// compares two scores, treating NaN as worse than any other score.
+fn cmp_score(a: Num, b: Num) -> Ordering
+{
+    match (a.is_nan(), b.is_nan())
+    {
+        (true,  true ) => Ordering::Equal,
+        (true,  false) => Ordering::Greater,
+        (false, true ) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap(),
+    }
+}
+
+
+// generates a range.
+fn range(start: Num, stop: Num, step: Num) -> Range
+{
+    range_iter(start, stop, step).collect()
+}
+
+// generates a range lazily. Yields exactly the same values as `range`.
+fn range_iter(start: Num, stop: Num, step: Num) -> impl Iterator<Item=Num> + Clone
+{
+    ::std::iter::repeat(())
+    .scan(start, move |acc, _|
+    {
+        let current = *acc;
+        *acc += step;
+
+        Some(current)
+    })
+    .take_while(move |x| *x < stop)
+}
+
+/// The most values that the search tries for any one parameter, however fine
+/// the steps are. The searches are over four parameters at once, so a map with
+/// millimetre cells would otherwise take forever; the default steps stay under
+/// this anyway.
+const MAX_STEPS_PER_PARAMETER: Num = 32.0;
+
+// `range_iter`, but with the step widened if need be, so that there are no
+// more than `MAX_STEPS_PER_PARAMETER` values.
+fn search_range(start: Num, stop: Num, step: Num) -> impl Iterator<Item=Num> + Clone
+{
+    range_iter(start, stop, step.max((stop - start) / (MAX_STEPS_PER_PARAMETER - 1.0)))
+}
+
+// lazily walks every combination of the values of four ranges.
+fn grid<I>(aa: I, bb: I, pp: I, qq: I) -> impl Iterator<Item=(Num, Num, Num, Num)>
+where
+    I: Iterator<Item=Num> + Clone
+{
+    aa.flat_map(move |a|
+    {
+        let (pp, qq) = (pp.clone(), qq.clone());
+
+        bb.clone().flat_map(move |b|
+        {
+            let qq = qq.clone();
+
+            pp.clone().flat_map(move |p| qq.clone().map(move |q| (a, b, p, q)))
+        })
+    })
+}
+
+/// The intersection-over-union of two shapes, e.g for comparing a detected
+/// shape against the real one. `1` means that they are identical, and `0`
+/// means that they don't overlap at all.
+///
+/// This is estimated by checking a grid of `samples` by `samples` points over
+/// the box that both shapes fit inside, so more samples gives a more accurate
+/// answer. If neither shape covers any of the points (e.g both are
+/// `Shape::Unknown`), the result is `0`.
+pub fn iou(a: &Shape, b: &Shape, samples: usize) -> Num
+{
+    let (xmin, ymin, xmax, ymax) = match (a.bounds(), b.bounds())
+    {
+        (Some(a), Some(b)) => (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)),
+        (Some(a), None) | (None, Some(a)) => a,
+        (None, None) => return 0.0,
+    };
+
+    let step = |lo: Num, hi: Num, i: usize| lo + (hi - lo) * (i as Num + 0.5) / samples as Num;
+
+    let (intersection, union) = (0..samples).into_par_iter()
+    .map(|i|
+    {
+        let x = step(xmin, xmax, i);
+
+        (0..samples).fold((0, 0), |(intersection, union), j|
+        {
+            let y = step(ymin, ymax, j);
+
+            match (a.contains((x, y)), b.contains((x, y)))
+            {
+                (true, true)  => (intersection + 1, union + 1),
+                (false, false) => (intersection, union),
+                _ => (intersection, union + 1),
+            }
+        })
+    })
+    .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+
+    if union == 0 { return 0.0; }
+
+    intersection as Num / union as Num
+}
+
+/// How many samples `suppress_duplicates` uses along each side for `iou`.
+const SUPPRESS_IOU_SAMPLES: usize = 50;
+
+/// Removes duplicate detections of the same obstacle, e.g when an obstacle was
+/// split into two groups that each fit a shape around the whole thing.
+///
+/// Two shapes are duplicates if their centres are no more than `max_distance`
+/// apart and their `iou` is more than `min_iou`. Of each set of duplicates,
+/// only the one with the best score is kept. Scores mean the same for every
+/// kind of shape (see `Shape::score`), so a circle and a rectangle fitted to
+/// the same obstacle are ranked fairly. The shapes that are kept stay in the
+/// same order as they were given.
+pub fn suppress_duplicates(shapes: Vec<Shape>, max_distance: Num, min_iou: Num) -> Vec<Shape>
+{
+    let keep = non_duplicates(&shapes, max_distance, min_iou);
+
+    shapes.into_iter().zip(keep.into_iter()).filter(|s| s.1).map(|s| s.0).collect()
+}
+
+/// Like `suppress_duplicates`, but only says which of the shapes it would
+/// keep, so that the caller can hang on to whatever else goes with each shape.
+pub fn non_duplicates(shapes: &[Shape], max_distance: Num, min_iou: Num) -> Vec<bool>
+{
+    non_duplicates_except(shapes, max_distance, min_iou, &[])
+}
+
+/// Like `non_duplicates`, but the two shapes of each of the `nested` pairs
+/// (see `find_nested`) are never taken to be duplicates of each other, so
+/// that e.g both walls of a hollow obstacle are kept.
+pub fn non_duplicates_except(shapes: &[Shape], max_distance: Num, min_iou: Num, nested: &[Nesting]) -> Vec<bool>
+{
+    let is_nested = |i: usize, j: usize| nested.iter().any(|n| (n.outer == i && n.inner == j) || (n.outer == j && n.inner == i));
+
+    let mut by_score: Vec<usize> = (0..shapes.len()).collect();
+    by_score.sort_by(|&i, &j| cmp_score(shapes[i].score(), shapes[j].score()));
+
+    let mut keep = vec![false; shapes.len()];
+
+    for i in by_score
+    {
+        let duplicate = (0..shapes.len()).filter(|&j| keep[j] && !is_nested(i, j)).any(|j|
+        {
+            let close = match (shapes[i].centre(), shapes[j].centre())
+            {
+                (Some(a), Some(b)) => (a.0 - b.0).hypot(a.1 - b.1) <= max_distance,
+                _ => false,
+            };
+
+            close && iou(&shapes[i], &shapes[j], SUPPRESS_IOU_SAMPLES) > min_iou
+        });
+
+        keep[i] = !duplicate;
+    }
+
+    keep
+}
+
+/// One shape lying inside another, e.g the inside and outside walls of a hollow
+/// obstacle that was scanned from both sides. `outer` and `inner` are indices
+/// into the shapes given to `find_nested`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nesting
+{
+    pub outer: usize,
+    pub inner: usize,
+}
+
+/// Finds the shapes that are nested inside another one, i.e whose centre lies
+/// inside the other shape, and that reach no more than `max_radius_ratio`
+/// times as far from their centre as it does (the radius, for a circle).
+///
+/// The ratio should be less than `1`, so that two copies of the same shape
+/// aren't taken to be nested in each other. If a shape is nested inside
+/// several others (e.g three concentric rings), each pair is given.
+pub fn find_nested(shapes: &[Shape], max_radius_ratio: Num) -> Vec<Nesting>
+{
+    let mut nested = Vec::new();
+
+    for (outer, o) in shapes.iter().enumerate()
+    {
+        if o.centre().is_none() { continue; }
+
+        let outer_reach = o.enclosing_radius();
+
+        for (inner, i) in shapes.iter().enumerate()
+        {
+            if inner == outer { continue; }
+
+            let inside = match i.centre()
+            {
+                Some(centre) => o.contains(centre) && i.enclosing_radius() <= max_radius_ratio * outer_reach,
+                None => false,
+            };
+
+            if inside
+            {
+                nested.push(Nesting { outer, inner });
+            }
+        }
+    }
+
+    nested
+}
+
+/// Draws the points and the outline of the fitted shape as ASCII art, in a grid
+/// `cols` characters wide and `rows` characters high, for debugging without
+/// rviz (e.g over SSH).
+///
+/// Points are drawn as `.`, the outline of the shape as `#`, and places where
+/// the two overlap as `@`. The grid is stretched to fit both the points and the
+/// shape, with `x` increasing to the right and `y` increasing upwards. Bear in
+/// mind that characters are about twice as tall as they are wide, so a circle
+/// only looks round if there are about twice as many columns as rows.
+pub fn ascii_preview(points: &Points, shape: &Shape, cols: usize, rows: usize) -> String
+{
+    if cols == 0 || rows == 0 { return String::new(); }
+
+    let outline = outline(shape, 4 * (cols + rows));
+
+    let (mut xmin, mut xmax, mut ymin, mut ymax) = (INFINITY, -INFINITY, INFINITY, -INFINITY);
+
+    for &(x, y) in points.iter().chain(outline.iter())
+    {
+        xmin = xmin.min(x);
+        xmax = xmax.max(x);
+        ymin = ymin.min(y);
+        ymax = ymax.max(y);
+    }
+
+    let mut grid = vec![vec![' '; cols]; rows];
+
+    // nothing to draw.
+    if xmin > xmax
+    {
+        return grid.into_iter().map(|row| row.into_iter().collect::<String>() + "\n").collect();
+    }
+
+    // the grid cell that a point falls in. `y` is flipped so that up is up.
+    let cell = |x: Num, y: Num|
+    {
+        let fx = if xmax > xmin { (x - xmin) / (xmax - xmin) } else { 0.5 };
+        let fy = if ymax > ymin { (ymax - y) / (ymax - ymin) } else { 0.5 };
+
+        let col = ((fx * (cols - 1) as Num).round() as usize).min(cols - 1);
+        let row = ((fy * (rows - 1) as Num).round() as usize).min(rows - 1);
+
+        (row, col)
+    };
+
+    for &(x, y) in points.iter()
+    {
+        let (row, col) = cell(x, y);
+        grid[row][col] = '.';
+    }
+
+    // the samples around the outline can be a long way apart (e.g along the
+    // sides of a rectangle), so join each one to the next with a line.
+    for i in 0..outline.len()
+    {
+        let (x0, y0) = outline[i];
+        let (x1, y1) = outline[(i + 1) % outline.len()];
+
+        let (r0, c0) = cell(x0, y0);
+        let (r1, c1) = cell(x1, y1);
+
+        let steps = (r0 as isize - r1 as isize).abs().max((c0 as isize - c1 as isize).abs()).max(1);
+
+        for k in 0..steps + 1
+        {
+            let f = k as Num / steps as Num;
+            let (row, col) = cell(x0 + f * (x1 - x0), y0 + f * (y1 - y0));
+
+            grid[row][col] = match grid[row][col]
+            {
+                '.' | '@' => '@',
+                _ => '#',
+            };
+        }
+    }
+
+    grid.into_iter().map(|row| row.into_iter().collect::<String>() + "\n").collect()
+}
+
+// samples `n` points evenly (by angle) around the outline of the shape.
+fn outline(shape: &Shape, n: usize) -> Points
+{
+    let (centre, a, b, t, s) = match *shape
+    {
+        Shape::Circle(ref c) => (c.centre, c.radius, c.radius, 0.0, 1),
+        Shape::Rectle(ref r) => (r.centre, r.width, r.length, r.rotation, r.sharpness),
+        Shape::Unknown { .. } => return Vec::new(),
+    };
+
+    (0..n).map(|i|
+    {
+        let theta = 2.0 * PI * i as Num / n as Num;
+
+        // a point on the superellipse `(R/a)^(2s) + (C/b)^(2s) = 1`, before
+        // rotating and translating it.
+        let R = a * theta.cos().signum() * theta.cos().abs().powf(1.0 / s as Num);
+        let C = b * theta.sin().signum() * theta.sin().abs().powf(1.0 / s as Num);
+
+        (centre.0 + R*t.cos() - C*t.sin(), centre.1 + R*t.sin() + C*t.cos())
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // points every `spacing` metres along the outline of a rectangle whose
+    // sides are `2a` and `2b`, centred on `centre` and turned by `t`.
+    fn rectangle_outline(centre: Point, a: Num, b: Num, t: Num, spacing: Num) -> Points
+    {
+        let mut corners = Vec::new();
+
+        for &(u, v) in [(-a, -b), (a, -b), (a, b), (-a, b)].iter()
+        {
+            corners.push((centre.0 + u*t.cos() - v*t.sin(), centre.1 + u*t.sin() + v*t.cos()));
+        }
+
+        let mut points = Vec::new();
+
+        for i in 0..4
+        {
+            let (from, to) = (corners[i], corners[(i + 1) % 4]);
+            let n = ((to.0 - from.0).hypot(to.1 - from.1) / spacing).round() as usize;
+
+            for k in 0..n
+            {
+                let f = k as Num / n as Num;
+                points.push((from.0 + f * (to.0 - from.0), from.1 + f * (to.1 - from.1)));
+            }
+        }
+
+        points
+    }
+
+    #[test]
+    fn rotation_seed_cuts_down_the_search()
+    {
+        let points = rectangle_outline((0.5, -0.2), 0.3, 0.2, 0.3, 0.02);
+
+        let seeded   = classify(&points, (0.5, -0.2), 0.3, 0.2, Some(0.3), 0.2, DEFAULT_MARGIN);
+        let unseeded = classify(&points, (0.5, -0.2), 0.3, 0.2, None,      0.2, DEFAULT_MARGIN);
+
+        assert!(seeded.evaluations * 4 < unseeded.evaluations, "{} vs {}", seeded.evaluations, unseeded.evaluations);
+
+        match (seeded.best, unseeded.best)
+        {
+            (Shape::Rectle(ref s), Shape::Rectle(ref u)) => assert!(s.approx_eq(u, 0.011), "{} vs {}", s, u),
+            (s, u) => panic!("expected two rectangles, got {} and {}", s, u),
+        }
+    }
+
+    #[test]
+    fn range_iter_matches_range()
+    {
+        let lazy: Vec<Num> = range_iter(-0.02, 0.02, 0.01).collect();
+
+        assert_eq!(lazy, range(-0.02, 0.02, 0.01));
+        assert_eq!(lazy.len(), 4);
+
+        // it can be walked more than once.
+        let values = range_iter(0.0, 1.0, 0.25);
+        assert_eq!(values.clone().count(), 4);
+        assert_eq!(values.sum::<Num>(), 1.5);
+
+        assert_eq!(range_iter(1.0, 0.0, 0.1).count(), 0);
+    }
+
+    #[test]
+    fn ht_score_weighted_trusts_heavier_points_more()
+    {
+        let points = rectangle_outline((0.0, 0.0), 0.3, 0.2, 0.0, 0.05);
+
+        let weighted: Vec<(Num, Num, Num)> = points.iter().map(|&(x, y)| (x, y, 100.0)).collect();
+
+        let plain = ht_score(&points, 0.3, 0.2, 0.0, 0.0, 0.0, 6, true);
+        let even = ht_score_weighted(&weighted, 0.3, 0.2, 0.0, 0.0, 0.0, 6);
+        assert!((plain - even).abs() < 1e-12, "{} vs {}", plain, even);
+
+        // a stray point that hardly anyone believes in barely moves the score.
+        let mut doubtful = weighted.clone();
+        doubtful.push((1.0, 1.0, 1.0));
+        let mut certain = weighted.clone();
+        certain.push((1.0, 1.0, 100.0));
+
+        let doubtful = ht_score_weighted(&doubtful, 0.3, 0.2, 0.0, 0.0, 0.0, 6);
+        let certain = ht_score_weighted(&certain, 0.3, 0.2, 0.0, 0.0, 0.0, 6);
+        assert!(doubtful < certain);
+
+        assert_eq!(ht_score_weighted(&[(0.3, 0.0, 0.0)], 0.3, 0.2, 0.0, 0.0, 0.0, 6), INFINITY);
+    }
+
+    #[test]
+    fn ht_score_weighted_is_the_same_on_any_number_of_threads()
+    {
+        use ::common::parallel::{thread_pool, with_pool};
+
+        let weighted: Vec<(Num, Num, Num)> = rectangle_outline((0.1, -0.2), 0.5, 0.3, 0.4, 0.002)
+        .iter().enumerate()
+        .map(|(i, &(x, y))| (x, y, 1.0 + (i % 7) as Num * 13.1))
+        .collect();
+        assert!(weighted.len() > 4 * SUM_CHUNK);
+
+        let score = |threads|
+        {
+            let pool = thread_pool(threads).unwrap();
+            with_pool(&pool, || ht_score_weighted(&weighted, 0.3, 0.2, 0.1, -0.2, 0.3, 6))
+        };
+
+        let one = score(1);
+        assert_eq!(one, score(4));
+        assert_eq!(one, score(7));
+    }
+
+    // a sharp-cornered rectangle with the given rotation.
+    fn rectle(centre: Point, width: Num, length: Num, rotation: Num) -> Rectle
+    {
+        Rectle { centre, width, length, rotation, score: 0.0, sharpness: RECTLE_SHARPNESS }
+    }
+
+    #[test]
+    fn approx_eq_compares_rotations_modulo_half_a_turn()
+    {
+        let r = rectle((1.0, 2.0), 0.2, 0.3, 0.1);
+
+        for &turns in [1.0, -1.0, 2.0, 100.0, -101.0].iter()
+        {
+            assert!(r.approx_eq(&rectle((1.0, 2.0), 0.2, 0.3, 0.1 + turns * PI), 1e-6), "{} half-turns", turns);
+        }
+
+        // either side of the wrap.
+        assert!(rectle((0.0, 0.0), 0.2, 0.3, PI - 0.001).approx_eq(&rectle((0.0, 0.0), 0.2, 0.3, 0.001), 0.003));
+
+        assert!(!r.approx_eq(&rectle((1.0, 2.0), 0.2, 0.3, 0.1 + PI / 2.0), 1e-6));
+        assert!(!r.approx_eq(&rectle((1.0, 2.0), 0.2, 0.3, 0.2), 0.05));
+        assert!(!r.approx_eq(&rectle((1.1, 2.0), 0.2, 0.3, 0.1), 0.05));
+
+        let c = Circle { centre: (1.0, 2.0), radius: 0.25, score: 0.0 };
+        assert!(c.approx_eq(&Circle { centre: (1.01, 2.0), radius: 0.25, score: 9.0 }, 0.02));
+        assert!(!c.approx_eq(&Circle { centre: (1.0, 2.0), radius: 0.3, score: 0.0 }, 0.02));
+    }
+
+    #[test]
+    fn ht_score_without_tanh_is_the_plain_mean()
+    {
+        // on a unit circle, `M` is zero; at twice the radius it's
+        // `(4 - 1)^2 / 4`.
+        let on = vec![(1.0, 0.0), (0.0, 1.0), (-1.0, 0.0), (0.0, -1.0)];
+        assert!(ht_score(&on, 1.0, 1.0, 0.0, 0.0, 0.0, 1, false).abs() < 1e-12);
+
+        let mut off = on.clone();
+        off.push((2.0, 0.0));
+        let plain = ht_score(&off, 1.0, 1.0, 0.0, 0.0, 0.0, 1, false);
+        assert!((plain - 2.25 / 5.0).abs() < 1e-12, "{}", plain);
+
+        // squashed, a far-off point can count for at most one.
+        let far = vec![(100.0, 0.0)];
+        assert!(ht_score(&far, 1.0, 1.0, 0.0, 0.0, 0.0, 1, true) <= 1.0);
+        assert!(ht_score(&far, 1.0, 1.0, 0.0, 0.0, 0.0, 1, false) > 1000.0);
+    }
+
+    #[test]
+    fn scores_never_come_out_nan()
+    {
+        use std::f64::NAN;
+
+        // right at the centre, and far enough away to overflow.
+        let points = vec![(0.0, 0.0), (1e200, 1e200), (NAN, 0.0)];
+
+        for &robust in [true, false].iter()
+        {
+            let score = ht_score(&points, 0.3, 0.2, 0.0, 0.0, 0.0, 8, robust);
+            assert!(!score.is_nan());
+        }
+
+        assert_eq!(ht_score(&points, 0.3, 0.2, 0.0, 0.0, 0.0, 8, true), 1.0);
+
+        assert_eq!(cmp_score(NAN, 1e300), Ordering::Greater);
+        assert_eq!(cmp_score(0.0, NAN), Ordering::Less);
+        assert_eq!(cmp_score(NAN, NAN), Ordering::Equal);
+    }
+
+    #[test]
+    fn fold_rotation_lands_in_a_quarter_turn()
+    {
+        let quarter = PI / 2.0;
+
+        for &t in [0.0, 0.3, quarter, 1.9, PI, -0.3, -2.0, 7.5, quarter * 4.0 - 1e-12, -1e3].iter()
+        {
+            let (a, b, folded) = fold_rotation(0.2, 0.3, t);
+
+            assert!(folded >= 0.0 && folded < quarter, "{} folded to {}", t, folded);
+
+            // the same rectangle either way.
+            assert!(rectle((0.0, 0.0), 0.2, 0.3, t).approx_eq(&rectle((0.0, 0.0), a, b, folded), 1e-9)
+                || rectle((0.0, 0.0), 0.2, 0.3, t).approx_eq(&rectle((0.0, 0.0), b, a, folded + quarter), 1e-9), "{}", t);
+        }
+
+        assert_eq!(fold_rotation(0.2, 0.3, 0.1), (0.2, 0.3, 0.1));
+
+        // an odd number of quarter-turns swaps the sides.
+        let (a, b, t) = fold_rotation(0.2, 0.3, 0.1 + quarter);
+        assert_eq!((a, b), (0.3, 0.2));
+        assert!((t - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn hough_transform_group_starts_from_the_group()
+    {
+        // a 60cm x 40cm rectangle, turned by 30 degrees, at 5cm per cell.
+        let outline = rectangle_outline((0.5, 0.25), 0.3, 0.2, 0.5236, 0.025);
+
+        let map = map_utils::MapBuilder::new(60, 60).build();
+
+        let cells = outline.iter().filter_map(|&p| map_utils::cell_at(&map, p)).collect();
+        let group = Group::new(&map, 0, cells);
+
+        let points: Points = map_utils::transform(&map, group.points.iter().cloned());
+        let (start, a, b) = group_seed(&group, &points, None);
+        assert!((start.0 - 0.5).abs() < 0.05 && (start.1 - 0.25).abs() < 0.05, "{:?}", start);
+        assert!((a.min(b) - 0.2).abs() < 0.04 && (a.max(b) - 0.3).abs() < 0.04, "{} {}", a, b);
+
+        match hough_transform_group(&map, &group, None, 0.2)
+        {
+            Shape::Rectle(ref r) =>
+            {
+                assert!(r.approx_eq(&rectle((0.5, 0.25), 0.2, 0.3, 0.5236 + PI / 2.0), 0.05), "{}", r);
+            },
+
+            ref shape => panic!("expected a rectangle, got {}", shape),
+        }
+    }
+
+    // `n` points evenly spaced around a circle.
+    fn circle_points(centre: Point, radius: Num, n: usize) -> Points
+    {
+        (0..n).map(|i|
+        {
+            let theta = 2.0 * PI * i as Num / n as Num;
+            (centre.0 + radius * theta.cos(), centre.1 + radius * theta.sin())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn variance_shrinks_with_more_points()
+    {
+        let circle = Shape::Circle(Circle { centre: (1.0, 1.0), radius: 0.25, score: 0.0 });
+
+        let few  = variance(&circle_points((1.0, 1.0), 0.25, 20), &circle);
+        let many = variance(&circle_points((1.0, 1.0), 0.25, 200), &circle);
+
+        for &(f, m) in [(few.centre.0, many.centre.0), (few.centre.1, many.centre.1), (few.size.0, many.size.0)].iter()
+        {
+            assert!(f.is_finite() && f > 0.0, "{}", f);
+            assert!(m < f, "{} vs {}", m, f);
+        }
+
+        assert_eq!(few.size.0, few.size.1);
+        assert_eq!(few.rotation, None);
+
+        let rectangle = Shape::Rectle(rectle((0.0, 0.0), 0.2, 0.3, 0.2));
+        let points = rectangle_outline((0.0, 0.0), 0.2, 0.3, 0.2, 0.02);
+        assert!(variance(&points, &rectangle).rotation.unwrap().is_finite());
+
+        let unknown = variance(&points, &Shape::Unknown { score: 1.0 });
+        assert_eq!(unknown.centre, (INFINITY, INFINITY));
+    }
+
+    #[test]
+    fn fits_worse_than_the_threshold_are_unknown()
+    {
+        let circle = circle_points((0.2, 0.1), 0.15, 60);
+
+        match hough_transform(&circle, (0.2, 0.1), 0.15, 0.15, None, 0.2)
+        {
+            Shape::Circle(_) => (),
+            shape => panic!("expected a circle, got {}", shape),
+        }
+
+        // the best score is still given back when it isn't good enough.
+        match hough_transform(&circle, (0.2, 0.1), 0.15, 0.15, None, -1.0)
+        {
+            Shape::Unknown { score } => assert!(score >= 0.0 && score < 0.2, "{}", score),
+            shape => panic!("expected an unknown shape, got {}", shape),
+        }
+    }
+
+    #[test]
+    fn ascii_preview_draws_the_points_over_the_outline()
+    {
+        // one point in the middle, and one on the right hand side.
+        let points = vec![(0.0, 0.0), (1.0, 0.0)];
+        let shape = Shape::Rectle(rectle((0.0, 0.0), 1.0, 0.5, 0.0));
+
+        let preview = ascii_preview(&points, &shape, 21, 11);
+        let lines: Vec<Vec<char>> = preview.lines().map(|line| line.chars().collect()).collect();
+
+        assert_eq!(lines.len(), 11);
+        assert!(lines.iter().all(|line| line.len() == 21), "{}", preview);
+
+        assert_eq!(lines[5][10], '.', "{}", preview);
+        assert_eq!(lines[5][20], '@', "{}", preview);
+        assert_eq!(lines[0][10], '#', "{}", preview);
+        assert_eq!(lines[10][10], '#', "{}", preview);
+
+        // with nothing to draw, it's just blank.
+        assert_eq!(ascii_preview(&vec![], &Shape::Unknown { score: 1.0 }, 3, 2), "   \n   \n");
+        assert_eq!(ascii_preview(&points, &shape, 0, 5), "");
+    }
+
+    #[test]
+    fn rectangles_are_scored_at_their_own_sharpness()
+    {
+        let corners = rectangle_outline((0.5, -0.2), 0.3, 0.2, 0.3, 0.01);
+        let (sharp, _) = fit_rectle(&corners, (0.5, -0.2), 0.3, 0.2, Some(0.3), SearchSteps::default(), None);
+
+        let ellipse: Points = (0..100).map(|i|
+        {
+            let angle = i as Num * 2.0 * PI / 100.0;
+            (0.5 + 0.3 * angle.cos(), -0.2 + 0.2 * angle.sin())
+        })
+        .collect();
+
+        let (round, _) = fit_rectle(&ellipse, (0.5, -0.2), 0.3, 0.2, Some(0.0), SearchSteps::default(), None);
+
+        assert!(sharp.sharpness > round.sharpness, "{} vs {}", sharp, round);
+
+        for &(r, points) in [(&sharp, &corners), (&round, &ellipse)].iter()
+        {
+            assert_eq!(r.score, radial_error(points, &r.params()), "{}", r);
+        }
+    }
+
+    #[test]
+    fn iou_measures_the_overlap()
+    {
+        let circle = |x| Shape::Circle(Circle { centre: (x, 2.0), radius: 0.5, score: 0.0 });
+        let rectangle = Shape::Rectle(rectle((0.0, 0.0), 1.0, 0.5, 0.5));
+
+        assert!((iou(&circle(1.0), &circle(1.0), 200) - 1.0).abs() < 1e-9);
+        assert!((iou(&rectangle, &rectangle, 200) - 1.0).abs() < 1e-9);
+        assert_eq!(iou(&circle(1.0), &circle(5.0), 200), 0.0);
+
+        // two circles a radius apart overlap by `2/3 PI - sqrt(3)/2` radii
+        // squared.
+        let overlap = 2.0 * PI / 3.0 - 3.0f64.sqrt() / 2.0;
+        let expected = overlap / (2.0 * PI - overlap);
+
+        assert!((iou(&circle(1.0), &circle(1.5), 400) - expected).abs() < 0.01, "{} vs {}", iou(&circle(1.0), &circle(1.5), 400), expected);
+
+        assert!(circle(1.0).contains((1.4, 2.0)) && !circle(1.0).contains((1.6, 2.0)));
+        assert_eq!(iou(&Shape::Unknown { score: 1.0 }, &Shape::Unknown { score: 1.0 }, 10), 0.0);
+    }
+
+    #[test]
+    fn shapes_are_displayed_with_units()
+    {
+        let circle = Shape::Circle(Circle { centre: (1.2, -0.3), radius: 0.25, score: 0.001 });
+        assert_eq!(circle.to_string(), "Circle r=0.25m at (1.20, -0.30) score=0.0010");
+
+        // the sides are given in full, not from the centre.
+        let mut rectangle = rectle((1.2, -0.3), 0.3, 0.2, PI / 6.0);
+        rectangle.score = 0.002;
+        assert_eq!(rectangle.to_string(), "Rectangle 0.60m x 0.40m at (1.20, -0.30) rotated 30.0° s=6 score=0.0020");
+
+        assert_eq!(Shape::Unknown { score: 0.5 }.to_string(), "Unknown shape, best score=0.5000");
+    }
+
+    #[test]
+    fn a_square_is_not_mistaken_for_a_circle()
+    {
+        // a square is the rectangle that looks most like a circle, and the
+        // circle is scored at a different sharpness to the rectangle, so the
+        // scores have to mean the same thing for this to come out right.
+        let square = rectangle_outline((0.4, 0.1), 0.2, 0.2, 0.0, 0.01);
+
+        let classification = classify(&square, (0.4, 0.1), 0.2, 0.2, None, 0.1, DEFAULT_MARGIN);
+
+        assert!(match classification.best { Shape::Rectle(_) => true, _ => false }, "{:?}", classification);
+
+        // and the other way around.
+        let circle = circle_points((0.4, 0.1), 0.2, 100);
+
+        let classification = classify(&circle, (0.4, 0.1), 0.2, 0.2, None, 0.1, DEFAULT_MARGIN);
+
+        assert!(match classification.best { Shape::Circle(_) => true, _ => false }, "{:?}", classification);
+    }
+
+    #[test]
+    fn rectangles_come_out_the_same_whichever_way_they_were_found()
+    {
+        let quarter = PI / 2.0;
+
+        for &t in [-2.0, -0.4, 0.0, 0.3, 1.2, 2.5, 4.0].iter()
+        {
+            // the same 0.3 x 0.5 rectangle, described both ways around.
+            let one = Rectle::scored(0.3, 0.5, 1.0, 2.0, t, 0.0);
+            let other = Rectle::scored(0.5, 0.3, 1.0, 2.0, t + quarter, 0.0);
+
+            assert!(one.approx_eq(&other, 1e-9), "{} vs {}", one, other);
+
+            assert!(one.width <= one.length, "{}", one);
+            assert!(one.rotation >= 0.0 && one.rotation < PI, "{}", one);
+
+            // and it's still the same rectangle.
+            let (a, b, u) = (one.width, one.length, one.rotation);
+            let (x, y) = (0.25 * t.cos() - 0.45 * t.sin(), 0.25 * t.sin() + 0.45 * t.cos());
+
+            assert!(Shape::Rectle(one).contains((1.0 + x, 2.0 + y)), "{} {} {}", a, b, u);
+        }
+    }
+
+    #[test]
+    fn fine_maps_are_searched_in_a_bounded_number_of_steps()
+    {
+        let steps = SearchSteps::for_resolution(0.001);
+
+        // without the cap, this would be 300 values.
+        assert_eq!(search_range(-0.3, 0.3, 2.0 * steps.step).count(), MAX_STEPS_PER_PARAMETER as usize);
+
+        // the default steps are fine enough already.
+        let default = SearchSteps::default();
+        assert_eq!(search_range(-0.3, 0.3, 2.0 * default.step).count(), range_iter(-0.3, 0.3, 2.0 * default.step).count());
+
+        let points = circle_points((0.4, 0.1), 0.2, 100);
+        let (circle, evaluations) = fit_circle(&points, (0.4, 0.1), 0.2, steps, None);
+
+        assert!(evaluations <= (MAX_STEPS_PER_PARAMETER as usize).pow(3), "{}", evaluations);
+        assert!(circle.approx_eq(&Circle { centre: (0.4, 0.1), radius: 0.2, score: 0.0 }, 0.01), "{}", circle);
+    }
+
+    #[test]
+    fn rasterized_circles_are_round()
+    {
+        use ::common::map_utils::MapBuilder;
+        use std::collections::HashSet;
+
+        let map = MapBuilder::new(40, 40).build();
+        let res = map.info.resolution as Num;
+
+        // centred on the corner between four cells, so that it should cover
+        // them the same way around in every direction.
+        let circle = Shape::Circle(Circle { centre: (0.0, 0.0), radius: 0.48, score: 0.0 });
+        let cells = rasterize(&circle, &map);
+
+        let covered: HashSet<(MapPoint, i8)> = cells.iter().cloned().collect();
+
+        for &((row, col), cost) in cells.iter()
+        {
+            for &mirrored in [(39 - row, col), (row, 39 - col), (39 - col, 39 - row)].iter()
+            {
+                assert!(covered.contains(&(mirrored, cost)), "{:?} but not {:?}", (row, col), mirrored);
+            }
+        }
+
+        let area = cells.iter().map(|&(_, cost)| cost as Num / 100.0).sum::<Num>() * res * res;
+
+        assert!((area - circle.area()).abs() < 0.05 * circle.area(), "{} vs {}", area, circle.area());
+
+        // hanging off the edge of the map, it only covers the cells that are
+        // on the map.
+        let edge = Shape::Circle(Circle { centre: (0.95, 0.0), radius: 0.3, score: 0.0 });
+        let cells = rasterize(&edge, &map);
+
+        assert!(!cells.is_empty());
+        assert!(cells.iter().all(|&((row, col), _)| row < 40 && col < 40));
+        assert!(cells.iter().any(|&((_, col), cost)| col == 39 && cost == 100));
+    }
+
+    #[test]
+    fn ht_score_agrees_in_f32_and_f64()
+    {
+        let points = rectangle_outline((0.5, -0.2), 0.3, 0.2, 0.3, 0.01);
+        let single: Vec<(f32, f32)> = points.iter().map(|&(x, y)| (x as f32, y as f32)).collect();
+
+        // a fit that's close, and one that's a long way off.
+        for &(a, b, p, q, t) in [(0.3, 0.2, 0.5, -0.2, 0.3), (0.25, 0.35, 0.4, 0.0, 1.0)].iter()
+        {
+            for &robust in [true, false].iter()
+            {
+                let double = ht_score::<f64>(&points, a, b, p, q, t, 4, robust);
+                let single = ht_score::<f32>(&single, a as f32, b as f32, p as f32, q as f32, t as f32, 4, robust);
+
+                assert!((single as f64 - double).abs() <= 1e-4 * double.max(1.0), "{} vs {}", single, double);
+            }
+        }
+
+        // and the points can be had in either straight from the map.
+        let map = ::common::map_utils::MapBuilder::new(10, 10).build();
+        let cells = vec![(2, 7), (5, 5)];
+
+        let double = map_utils::transform::<f64, _>(&map, cells.iter().cloned());
+        let single = map_utils::transform::<f32, _>(&map, cells.iter().cloned());
+
+        for (d, s) in double.iter().zip(single.iter())
+        {
+            assert_eq!((d.0 as f32, d.1 as f32), *s);
+        }
+    }
+
+    #[test]
+    fn the_best_fitting_duplicate_is_kept_whatever_its_shape()
+    {
+        let points = circle_points((0.0, 0.0), 0.3, 100);
+
+        // two circles fitted to the same obstacle, both a bit off, and an
+        // ellipse that's spot on.
+        let circle = |centre: Point| Circle { centre, radius: 0.3, score: radial_error(&points, &SuperellipseParams::circle(centre.0, centre.1, 0.3)) };
+
+        let near = circle((0.02, 0.0));
+        let far = circle((0.0, -0.04));
+
+        let ellipse = SuperellipseParams { a: 0.3, b: 0.3, p: 0.0, q: 0.0, t: 0.5, s: 1.0 };
+        let ellipse = Rectle::with_params(ellipse, radial_error(&points, &ellipse));
+
+        assert!(ellipse.score < near.score && near.score < far.score, "{} {} {}", ellipse, near, far);
+
+        let shapes = vec![Shape::Circle(far), Shape::Rectle(ellipse), Shape::Circle(near)];
+
+        assert_eq!(non_duplicates(&shapes, 0.2, 0.1), vec![false, true, false]);
+    }
+
+    #[test]
+    fn ht_score_and_grad_matches_finite_differences()
+    {
+        let points = rectangle_outline((0.5, -0.2), 0.3, 0.2, 0.3, 0.01);
+
+        // a bit off from the rectangle, so the gradient isn't zero. The
+        // sharpness isn't a whole number, and gets rounded for the score.
+        let model = SuperellipseParams { a: 0.32, b: 0.18, p: 0.52, q: -0.19, t: 0.25, s: 2.3 };
+
+        for &robust in [true, false].iter()
+        {
+            let (score, grad) = ht_score_and_grad(&points, &model, robust);
+
+            let score_at = |m: &SuperellipseParams| ht_score(&points, m.a, m.b, m.p, m.q, m.t, m.sharpness(), robust);
+
+            assert!((score - score_at(&model)).abs() < 1e-12, "{} vs {}", score, score_at(&model));
+
+            let h = 1e-6;
+
+            for i in 0..5
+            {
+                let nudged = |d: Num|
+                {
+                    let mut m = model;
+                    match i { 0 => m.a += d, 1 => m.b += d, 2 => m.p += d, 3 => m.q += d, _ => m.t += d }
+                    m
+                };
+
+                let numeric = (score_at(&nudged(h)) - score_at(&nudged(-h))) / (2.0 * h);
+
+                assert!((grad[i] - numeric).abs() <= 1e-4 * numeric.abs().max(1.0), "{} (robust: {}): {} vs {}", i, robust, grad[i], numeric);
+            }
+
+            // and it's the same as the gradient at the sharpness that's scored.
+            let rounded = SuperellipseParams { s: 2.0, ..model };
+            assert_eq!(ht_score_and_grad(&points, &rounded, robust), (score, grad));
+        }
+    }
+
+    #[test]
+    fn a_stray_point_does_not_throw_the_circle_off()
+    {
+        let mut points = circle_points((0.0, 0.0), 0.2, 60);
+        points.push((0.45, 0.0));
+
+        let (centre, radius) = least_squares_circle(&points).unwrap();
+        assert!(centre.0.abs() < 0.01 && centre.1.abs() < 0.01 && (radius - 0.2).abs() < 0.01, "{:?} {}", centre, radius);
+
+        let (circle, _) = fit_circle(&points, (0.0, 0.0), 0.2, SearchSteps::default(), None);
+        assert!(circle.approx_eq(&Circle { centre: (0.0, 0.0), radius: 0.2, score: 0.0 }, 0.02), "{}", circle);
+
+        // too few points, or all on a line.
+        assert_eq!(least_squares_circle(&vec![(0.0, 0.0), (1.0, 1.0)]), None);
+        assert_eq!(least_squares_circle(&vec![(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)]), None);
+    }
+
+    #[test]
+    fn sharpness_below_one_is_scored_as_an_ellipse()
+    {
+        let points = circle_points((0.1, 0.2), 0.3, 40);
+        let score = |s| ht_score(&points, 0.3, 0.25, 0.1, 0.2, 0.0, s, false);
+
+        // with s = 0 every point would score the same, whatever the shape.
+        assert_eq!(score(0), score(MIN_SHARPNESS));
+        assert_eq!(score(-3), score(MIN_SHARPNESS));
+        assert!(score(0).is_finite());
+        assert!(score(0) != score(2));
+    }
+
+    #[test]
+    fn nothing_inside_a_shape_is_further_away_than_its_enclosing_radius()
+    {
+        let circle = Shape::Circle(Circle { centre: (1.0, -1.0), radius: 0.25, score: 0.0 });
+        assert_eq!(circle.enclosing_radius(), 0.25);
+
+        // a 0.6m by 0.8m rectangle has a diagonal of 1m, however it's turned.
+        for &rotation in [0.0, 0.3, PI / 2.0, 2.0].iter()
+        {
+            let rectangle = Shape::Rectle(Rectle { centre: (1.0, -1.0), width: 0.3, length: 0.4, rotation, score: 0.0, sharpness: 20 });
+            let reach = rectangle.enclosing_radius();
+
+            assert!((reach - 0.5).abs() < 1e-12, "{}", reach);
+
+            // check a grid of points around it.
+            let mut furthest: Num = 0.0;
+
+            for i in 0..101
+            {
+                for j in 0..101
+                {
+                    let point = (0.4 + i as Num * 0.012, -1.6 + j as Num * 0.012);
+
+                    if rectangle.contains(point)
+                    {
+                        furthest = furthest.max((point.0 - 1.0).hypot(point.1 + 1.0));
+                    }
+                }
+            }
+
+            // and with corners that sharp, it very nearly reaches it.
+            assert!(furthest <= reach && furthest > 0.95 * reach, "{} vs {}", furthest, reach);
+        }
+
+        assert_eq!(Shape::Unknown { score: 1.0 }.enclosing_radius(), 0.0);
+    }
+
+    #[test]
+    fn scoring_in_a_batch_gives_the_same_scores_as_one_at_a_time()
+    {
+        // enough points to be summed in several chunks.
+        let mut points = rectangle_outline((0.3, -0.2), 0.4, 0.25, 0.5, 0.002);
+        points.extend(circle_points((0.2, 0.1), 0.3, SUM_CHUNK + 7));
+        assert!(points.len() > 2 * SUM_CHUNK);
+
+        let candidates: Vec<SuperellipseParams> = [0, 1, 2, 6].iter()
+        .flat_map(|&s| [0.0, 0.5, 2.0].iter().map(move |&t| SuperellipseParams { a: 0.4, b: 0.25, p: 0.3, q: -0.2, t, s: s as Num }))
+        .chain(Some(SuperellipseParams::circle(0.2, 0.1, 0.3)))
+        .collect();
+
+        for &robust in [false, true].iter()
+        {
+            let batch = ht_score_batch(&points, &candidates, robust);
+            let single: Vec<Num> = candidates.iter().map(|c| ht_score(&points, c.a, c.b, c.p, c.q, c.t, c.sharpness(), robust)).collect();
+
+            // exactly, not just nearly.
+            assert_eq!(batch, single);
+        }
+
+        assert!(ht_score_batch(&points, &[], false).is_empty());
+    }
+
+    #[test]
+    fn rounded_rectangles_get_variances_at_their_own_sharpness()
+    {
+        // points on the outline of a rectangle with well rounded corners.
+        let (a, b, s) = (0.3, 0.2, 2);
+
+        let points: Points = (0..200).map(|i|
+        {
+            let theta = 2.0 * PI * i as Num / 200.0;
+            let (c, d) = (theta.cos(), theta.sin());
+
+            (1.0 + a * c.signum() * c.abs().powf(1.0 / s as Num), -0.5 + b * d.signum() * d.abs().powf(1.0 / s as Num))
+        })
+        .collect();
+
+        let rounded = Rectle { centre: (1.0, -0.5), width: a, length: b, rotation: 0.0, score: 0.0, sharpness: s };
+        let sharp = Rectle { sharpness: RECTLE_SHARPNESS, ..rounded };
+
+        let expected = variance_along(|x| ht_score(&points, x, b, 1.0, -0.5, 0.0, s, true), a, points.len() as Num);
+
+        let v = variance(&points, &Shape::Rectle(rounded));
+
+        assert_eq!(v.size.0, expected);
+        assert!(v.size.0 != variance(&points, &Shape::Rectle(sharp)).size.0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Remembers which maps have been processed recently.
+//!
+//! `gmapping` sometimes publishes the same map several times over, and there's
+//! no point finding the same obstacles in it again. Maps are compared by their
+//! `map_utils::fingerprint`, so only a hash is kept for each one.
+
+use ::common::prelude::*;
+
+use std::collections::VecDeque;
+
+use ::common::map_utils::Map;
+
+/// The fingerprints of the last few maps that were processed, most recent
+/// first.
+#[derive(Debug, Clone)]
+pub struct RecentMaps
+{
+    capacity: usize,
+    fingerprints: VecDeque<u64>,
+}
+
+impl RecentMaps
+{
+    /// Remembers up to `capacity` maps. A capacity of zero remembers nothing,
+    /// so every map gets processed.
+    pub fn new(capacity: usize) -> Self
+    {
+        RecentMaps
+        {
+            capacity,
+            fingerprints: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Whether the map should be processed, i.e it isn't the same as one of the
+    /// recently processed maps. Either way, the map becomes the most recent.
+    ///
+    /// Once full, the map that was seen least recently is forgotten.
+    pub fn should_process(&mut self, map: &Map) -> bool
+    {
+        if self.capacity == 0
+        {
+            return true;
+        }
+
+        let fingerprint = map_utils::fingerprint(map);
+
+        let seen = match self.fingerprints.iter().position(|&f| f == fingerprint)
+        {
+            Some(i) =>
+            {
+                self.fingerprints.remove(i);
+                true
+            },
+
+            None => false,
+        };
+
+        self.fingerprints.push_front(fingerprint);
+        self.fingerprints.truncate(self.capacity);
+
+        !seen
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use ::common::map_utils::MapBuilder;
+
+    // a small map with one occupied cell, so that each `i` gives a different
+    // map.
+    fn map(i: usize) -> Map
+    {
+        MapBuilder::new(5, 5).cell((i / 5, i % 5), 100).build()
+    }
+
+    #[test]
+    fn repeated_maps_are_skipped_until_they_are_forgotten()
+    {
+        let mut recent = RecentMaps::new(2);
+
+        assert!(recent.should_process(&map(0)));
+        assert!(!recent.should_process(&map(0)));
+
+        assert!(recent.should_process(&map(1)));
+
+        // seeing `0` again makes it the most recent, so `1` is forgotten first.
+        assert!(!recent.should_process(&map(0)));
+        assert!(recent.should_process(&map(2)));
+
+        assert!(!recent.should_process(&map(0)));
+        assert!(recent.should_process(&map(1)));
+    }
+
+    #[test]
+    fn a_capacity_of_zero_processes_everything()
+    {
+        let mut recent = RecentMaps::new(0);
+
+        assert!(recent.should_process(&map(0)));
+        assert!(recent.should_process(&map(0)));
+    }
+}
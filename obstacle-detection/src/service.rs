@@ -0,0 +1,139 @@
+//! Classifying points on request.
+//!
+//! Other nodes can call the `classify_points` service with a list of points
+//! and get the fitted shape back straight away, rather than waiting for it to
+//! turn up in a map. This is the handler for that service, minus the ROS
+//! plumbing, so that it can be run without a master.
+
+use ::common::prelude::*;
+
+use ::common::msg::geometry_msgs::Pose2D as RosPose2D;
+use ::common::msg::od2rs_msgs::
+{
+    ClassifyPointsReq,
+    ClassifyPointsRes,
+    Shape as ShapeMsg,
+};
+
+use model3::{self, Shape};
+
+use std::f64::{INFINITY, NEG_INFINITY};
+
+/// Answers a `classify_points` request, by fitting a shape to the points with
+/// `model3::hough_transform`. The search starts from the centroid of the
+/// points, with the sides of the rectangle (and the radius of the circle)
+/// seeded from how far the points spread out along x and y.
+///
+/// If nothing fits better than `reject_threshold`, the response holds an
+/// `UNKNOWN` shape. A request with no points in it is an error, since there's
+/// nothing to fit.
+pub fn classify_points(request: &ClassifyPointsReq, reject_threshold: Num) -> Result<ClassifyPointsRes, String>
+{
+    if request.points.is_empty()
+    {
+        return Err("Recieved a request with no points in it".to_string());
+    }
+
+    let points: Vec<(Num, Num)> = request.points.iter().map(|p| (p.x, p.y)).collect();
+
+    let n = points.len() as Num;
+
+    let centroid =
+    (
+        points.iter().map(|p| p.0).sum::<Num>() / n,
+        points.iter().map(|p| p.1).sum::<Num>() / n,
+    );
+
+    let (min_x, max_x, min_y, max_y) = points.iter()
+    .fold((INFINITY, NEG_INFINITY, INFINITY, NEG_INFINITY), |(x0, x1, y0, y1), p|
+    {
+        (x0.min(p.0), x1.max(p.0), y0.min(p.1), y1.max(p.1))
+    });
+
+    let a = (max_x - min_x) / 2.0;
+    let b = (max_y - min_y) / 2.0;
+
+    let shape = model3::hough_transform(&points, centroid, a, b, None, reject_threshold);
+
+    Ok(ClassifyPointsRes { shape: shape_message(&shape) })
+}
+
+/// Converts a shape into its message.
+pub fn shape_message(shape: &Shape) -> ShapeMsg
+{
+    let mut message = ShapeMsg::default();
+
+    match *shape
+    {
+        Shape::Circle(ref c) =>
+        {
+            message.kind = ShapeMsg::CIRCLE;
+            message.pose = RosPose2D { x: c.centre.0, y: c.centre.1, theta: 0.0 };
+            message.width = c.radius;
+            message.length = c.radius;
+            message.sharpness = 1;
+            message.score = c.score;
+        },
+
+        Shape::Rectle(ref r) =>
+        {
+            message.kind = ShapeMsg::RECTANGLE;
+            message.pose = RosPose2D { x: r.centre.0, y: r.centre.1, theta: r.rotation };
+            message.width = r.width;
+            message.length = r.length;
+            message.sharpness = r.sharpness;
+            message.score = r.score;
+        },
+
+        Shape::Unknown { score } =>
+        {
+            message.kind = ShapeMsg::UNKNOWN;
+            message.score = score;
+        },
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use ::common::msg::geometry_msgs::Point;
+
+    // a request for the points on a circle.
+    fn circle_request(centre: (Num, Num), radius: Num) -> ClassifyPointsReq
+    {
+        let points = (0..60).map(|i|
+        {
+            let angle = i as Num * 2.0 * ::std::f64::consts::PI / 60.0;
+            Point { x: centre.0 + radius * angle.cos(), y: centre.1 + radius * angle.sin(), z: 0.0 }
+        })
+        .collect();
+
+        ClassifyPointsReq { points }
+    }
+
+    #[test]
+    fn points_on_a_circle_come_back_as_a_circle()
+    {
+        let response = classify_points(&circle_request((1.0, -0.5), 0.2), 0.1).unwrap();
+        let shape = response.shape;
+
+        assert_eq!(shape.kind, ShapeMsg::CIRCLE, "{:?}", shape);
+        assert!((shape.pose.x - 1.0).abs() < 0.02 && (shape.pose.y + 0.5).abs() < 0.02, "{:?}", shape);
+        assert!((shape.width - 0.2).abs() < 0.02 && shape.width == shape.length, "{:?}", shape);
+        assert!(shape.score <= 0.1, "{:?}", shape);
+    }
+
+    #[test]
+    fn bad_requests_are_refused_or_unknown()
+    {
+        assert!(classify_points(&ClassifyPointsReq::default(), 0.1).is_err());
+
+        // nothing can fit better than a threshold of zero.
+        let response = classify_points(&circle_request((0.0, 0.0), 0.2), 0.0).unwrap();
+        assert_eq!(response.shape.kind, ShapeMsg::UNKNOWN, "{:?}", response.shape);
+    }
+}
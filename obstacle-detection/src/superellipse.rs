@@ -0,0 +1,140 @@
+//! The parameters of the model, in the one form that everything shares.
+//!
+//! The Hough search (`model3`), the analytic derivatives (`diff2`) and the
+//! gradient descent in `model.rs` all fit the same superellipse,
+//!
+//! ```text
+//! (R/a)^(2s) + (C/b)^(2s) = 1
+//! ```
+//!
+//! where `(R, C)` is the point relative to the centre `(p, q)`, rotated by `t`.
+//! They used to each have their own set of parameters, which drifted apart;
+//! now they all convert to and from `SuperellipseParams`, so a fit from one can
+//! be handed straight to another.
+
+use ::common::prelude::*;
+
+use std::f64::consts::PI;
+
+use model3;
+
+/// The parameters of the model; see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuperellipseParams
+{
+    /// Half the size of the shape along its rotated x axis.
+    pub a: Num,
+
+    /// Half the size of the shape along its rotated y axis.
+    pub b: Num,
+
+    /// The x coordinate of the centre.
+    pub p: Num,
+
+    /// The y coordinate of the centre.
+    pub q: Num,
+
+    /// The rotation, in radians, anticlockwise.
+    pub t: Num,
+
+    /// How sharp the corners are; `1` is an ellipse, and the corners get
+    /// sharper as it goes up. The shape itself only uses whole numbers, so
+    /// `ht_score` and the field in `diff2` both use `sharpness()`; only the
+    /// derivative with respect to `s` in `diff2` treats it as continuous.
+    pub s: Num,
+}
+
+impl SuperellipseParams
+{
+    /// A circle of radius `r`, centred on `(p, q)`.
+    pub fn circle(p: Num, q: Num, r: Num) -> Self
+    {
+        SuperellipseParams { a: r, b: r, p, q, t: 0.0, s: 1.0 }
+    }
+
+    /// `s`, rounded to the whole number that `ht_score` takes.
+    pub fn sharpness(&self) -> i32
+    {
+        self.s.round() as i32
+    }
+
+    /// The area inside the shape, in square metres. This takes the sharpness
+    /// into account, so it's `PI * a * b` for an ellipse, and gets closer to
+    /// `4 * a * b` as the corners get sharper.
+    pub fn area(&self) -> Num
+    {
+        let n = 2.0 * self.s;
+
+        4.0 * self.a * self.b * gamma(1.0 + 1.0 / n).powi(2) / gamma(1.0 + 2.0 / n)
+    }
+
+    /// The score of the points against the model; see `model3::ht_score`.
+    pub fn score(&self, points: &[(Num, Num)], robust: bool) -> Num
+    {
+        model3::ht_score(points, self.a, self.b, self.p, self.q, self.t, self.sharpness(), robust)
+    }
+}
+
+// the gamma function, using the Lanczos approximation, which is good to about
+// 15 digits; `area` only needs it for arguments between 1 and 2.
+fn gamma(x: Num) -> Num
+{
+    const G: Num = 7.0;
+
+    const COEFFICIENTS: [Num; 9] =
+    [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5
+    {
+        // the reflection formula.
+        return PI / ((PI * x).sin() * gamma(1.0 - x));
+    }
+
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+
+    let sum = COEFFICIENTS.iter().enumerate().skip(1)
+    .fold(COEFFICIENTS[0], |sum, (i, c)| sum + c / (x + i as Num));
+
+    (2.0 * PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn gamma_matches_the_factorials()
+    {
+        for &(x, expected) in [(1.0, 1.0), (2.0, 1.0), (3.0, 2.0), (5.0, 24.0), (0.5, PI.sqrt())].iter()
+        {
+            assert!((gamma(x) - expected).abs() < 1e-9 * expected, "gamma({}) = {}", x, gamma(x));
+        }
+    }
+
+    #[test]
+    fn area_goes_from_an_ellipse_to_a_rectangle()
+    {
+        let shape = |s| SuperellipseParams { a: 0.3, b: 0.2, p: 1.0, q: -1.0, t: 0.4, s };
+
+        assert!((shape(1.0).area() - PI * 0.3 * 0.2).abs() < 1e-9);
+        assert!((SuperellipseParams::circle(0.0, 0.0, 0.5).area() - PI * 0.25).abs() < 1e-9);
+
+        // the corners fill in as they get sharper, but never past the box.
+        let areas: Vec<Num> = [1.0, 2.0, 4.0, 8.0, 1000.0].iter().map(|&s| shape(s).area()).collect();
+
+        assert!(areas.windows(2).all(|w| w[0] < w[1]), "{:?}", areas);
+        assert!(areas[4] < 4.0 * 0.3 * 0.2 && areas[4] > 0.99 * 4.0 * 0.3 * 0.2, "{:?}", areas);
+    }
+}
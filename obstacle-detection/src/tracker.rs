@@ -0,0 +1,258 @@
+//! Tracking of obstacles from one map to the next.
+//!
+//! The detections from each map jitter around a bit because of noise in the
+//! map, so the tracker matches up the detections in each new map with the
+//! obstacles it already knows about, and smooths their positions over time.
+
+use ::common::prelude::*;
+
+type Point = (Num, Num);
+
+/// An obstacle that has been seen in one or more maps.
+#[derive(Debug, Clone)]
+pub struct Track
+{
+    /// Identifies the obstacle; stays the same for as long as it's tracked.
+    pub id: usize,
+
+    /// The smoothed position of the obstacle.
+    pub position: Point,
+
+    /// The number of maps in a row in which the obstacle was not seen.
+    pub missed: usize,
+}
+
+/// Keeps track of obstacles across maps.
+#[derive(Debug)]
+pub struct ObstacleTracker
+{
+    tracks: Vec<Track>,
+    next_id: usize,
+
+    /// How much weight a new detection gets when smoothing, between 0 and 1.
+    alpha: Num,
+
+    /// How far (in metres) a detection can be from a track and still be
+    /// considered the same obstacle.
+    max_distance: Num,
+
+    /// How many maps in a row an obstacle can be missing from before it is
+    /// forgotten.
+    max_missed: usize,
+
+    /// How far the coordinates of the detections have moved since the first
+    /// map; see `shift_frame`.
+    offset: Point,
+}
+
+impl ObstacleTracker
+{
+    /// Creates a tracker that isn't tracking anything yet.
+    pub fn new(alpha: Num, max_distance: Num, max_missed: usize) -> Self
+    {
+        ObstacleTracker
+        {
+            tracks: Vec::new(),
+            next_id: 0,
+            alpha: alpha.max(0.0).min(1.0),
+            max_distance,
+            max_missed,
+            offset: (0.0, 0.0),
+        }
+    }
+
+    /// Tells the tracker that the coordinates of the detections have moved by
+    /// `delta` since the last map, e.g because the map's origin moved; see
+    /// `map_utils::coordinate_shift`.
+    ///
+    /// The tracks stay where they are, in the coordinates of the first map,
+    /// and later detections are moved back into those coordinates before
+    /// they're matched. So an obstacle that hasn't moved keeps the same
+    /// position, however often the map's origin jumps around.
+    pub fn shift_frame(&mut self, delta: Point)
+    {
+        self.offset = (self.offset.0 + delta.0, self.offset.1 + delta.1);
+    }
+
+    /// The obstacles currently being tracked, in the coordinates of the first
+    /// map; see `shift_frame`.
+    pub fn tracks(&self) -> &[Track]
+    {
+        &self.tracks
+    }
+
+    /// The obstacles currently being tracked, moved into the coordinates of the
+    /// latest map, i.e the same coordinates as the detections. This is what to
+    /// publish along with that map.
+    pub fn current_tracks(&self) -> Vec<Track>
+    {
+        let offset = self.offset;
+
+        self.tracks.iter()
+        .map(|track| Track { position: (track.position.0 + offset.0, track.position.1 + offset.1), ..track.clone() })
+        .collect()
+    }
+
+    /// Updates the tracks with the positions of the obstacles detected in a
+    /// new map, and returns the updated tracks.
+    ///
+    /// Detections are matched to tracks closest-pair-first, and each track
+    /// gets at most one detection, so two nearby obstacles can't swap
+    /// identities just because one of them moved a bit. Detections that don't
+    /// match any track start a new one.
+    ///
+    /// The tracks are given in the coordinates of the first map; see
+    /// `shift_frame`.
+    pub fn update(&mut self, detections: &[Point]) -> &[Track]
+    {
+        let offset = self.offset;
+
+        let detections: Vec<Point> = detections.iter()
+        .map(|d| (d.0 - offset.0, d.1 - offset.1))
+        .collect();
+
+        // every pair of track and detection that is close enough to match.
+        let mut pairs = Vec::new();
+
+        for (t, track) in self.tracks.iter().enumerate()
+        {
+            for (d, detection) in detections.iter().enumerate()
+            {
+                let distance = distance(track.position, *detection);
+
+                if distance <= self.max_distance { pairs.push((distance, t, d)); }
+            }
+        }
+
+        pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(::std::cmp::Ordering::Equal));
+
+        let mut track_matched = vec![false; self.tracks.len()];
+        let mut detection_matched = vec![false; detections.len()];
+
+        for (_distance, t, d) in pairs
+        {
+            if track_matched[t] || detection_matched[d] { continue; }
+
+            track_matched[t] = true;
+            detection_matched[d] = true;
+
+            let track = &mut self.tracks[t];
+            let detection = detections[d];
+
+            track.position =
+            (
+                self.alpha * detection.0 + (1.0 - self.alpha) * track.position.0,
+                self.alpha * detection.1 + (1.0 - self.alpha) * track.position.1,
+            );
+
+            track.missed = 0;
+        }
+
+        for (track, matched) in self.tracks.iter_mut().zip(track_matched.into_iter())
+        {
+            if !matched { track.missed += 1; }
+        }
+
+        let max_missed = self.max_missed;
+        self.tracks.retain(|track| track.missed <= max_missed);
+
+        for (detection, matched) in detections.iter().zip(detection_matched.into_iter())
+        {
+            if matched { continue; }
+
+            self.tracks.push(Track { id: self.next_id, position: *detection, missed: 0 });
+            self.next_id += 1;
+        }
+
+        &self.tracks
+    }
+}
+
+// the distance between two points.
+fn distance(a: Point, b: Point) -> Num
+{
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn ids_and_positions(tracks: &[Track]) -> Vec<(usize, Point)>
+    {
+        tracks.iter().map(|track| (track.id, track.position)).collect()
+    }
+
+    #[test]
+    fn detections_are_smoothed_into_the_nearest_track()
+    {
+        let mut tracker = ObstacleTracker::new(0.5, 0.3, 1);
+
+        assert_eq!(ids_and_positions(tracker.update(&[(0.0, 0.0), (1.0, 0.0)])), vec![(0, (0.0, 0.0)), (1, (1.0, 0.0))]);
+
+        // each track moves halfway towards its detection; the one that's too
+        // far away from both starts a new track.
+        assert_eq!(
+            ids_and_positions(tracker.update(&[(1.2, 0.0), (0.2, 0.0), (3.0, 3.0)])),
+            vec![(0, (0.1, 0.0)), (1, (1.1, 0.0)), (2, (3.0, 3.0))]
+        );
+    }
+
+    #[test]
+    fn a_track_only_gets_one_detection()
+    {
+        let mut tracker = ObstacleTracker::new(1.0, 0.5, 1);
+
+        tracker.update(&[(0.0, 0.0)]);
+
+        // both are close enough, but the closer one wins, and the other one
+        // becomes a new obstacle.
+        let tracks = ids_and_positions(tracker.update(&[(0.3, 0.0), (0.1, 0.0)]));
+
+        assert_eq!(tracks, vec![(0, (0.1, 0.0)), (1, (0.3, 0.0))]);
+    }
+
+    #[test]
+    fn missing_obstacles_are_forgotten()
+    {
+        let mut tracker = ObstacleTracker::new(0.5, 0.3, 2);
+
+        tracker.update(&[(0.0, 0.0)]);
+
+        for missed in 1..3
+        {
+            let tracks = tracker.update(&[]);
+            assert_eq!(tracks.len(), 1);
+            assert_eq!(tracks[0].missed, missed);
+        }
+
+        assert!(tracker.update(&[]).is_empty());
+
+        // and an obstacle that comes back later is a new one.
+        assert_eq!(tracker.update(&[(0.0, 0.0)])[0].id, 1);
+    }
+
+    #[test]
+    fn tracks_stay_put_when_the_origin_moves()
+    {
+        let mut tracker = ObstacleTracker::new(0.5, 0.3, 1);
+
+        tracker.update(&[(1.0, 1.0)]);
+
+        // the origin moves half a metre, so the same obstacle now shows up
+        // half a metre over, which is further than `max_distance`.
+        tracker.shift_frame((0.5, 0.0));
+        tracker.update(&[(1.5, 1.0)]);
+
+        assert_eq!(tracker.tracks().len(), 1);
+        assert_eq!(tracker.tracks()[0].position, (1.0, 1.0));
+
+        // but it's published where it is in the new map.
+        let current = tracker.current_tracks();
+
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].id, tracker.tracks()[0].id);
+        assert_eq!(current[0].position, (1.5, 1.0));
+    }
+}
@@ -0,0 +1,163 @@
+//! A watchdog that complains when the node hasn't recieved a map.
+//!
+//! If the ROS master is up but nothing is publishing the map, the node would
+//! otherwise sit in `spin()` forever without saying anything.
+
+use ::common::prelude::*;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the watchdog checks for a map.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// What the watchdog should do next.
+#[derive(Debug, PartialEq)]
+pub enum Action
+{
+    /// Nothing to report yet.
+    Wait,
+
+    /// Log a warning that no map has arrived.
+    Warn,
+
+    /// Give up and exit the node.
+    Exit,
+}
+
+/// The timeouts used by the watchdog.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts
+{
+    /// How long to wait for a map before warning about it.
+    pub warn_after: Duration,
+
+    /// How long to wait for a map before exiting the node, if at all.
+    pub exit_after: Option<Duration>,
+}
+
+impl Timeouts
+{
+    /// Decides what to do, given how long it has been since the watchdog was
+    /// started and whether the warning has already been given.
+    ///
+    /// This doesn't look at the clock itself, so that the timing logic can be
+    /// checked without having to actually wait.
+    pub fn action(&self, elapsed: Duration, warned: bool) -> Action
+    {
+        if let Some(exit_after) = self.exit_after
+        {
+            if elapsed >= exit_after { return Action::Exit; }
+        }
+
+        if !warned && elapsed >= self.warn_after { return Action::Warn; }
+
+        Action::Wait
+    }
+}
+
+/// Converts a number of seconds (as read from the parameter server) into a
+/// `Duration`.
+pub fn seconds(secs: Num) -> Duration
+{
+    Duration::from_millis((secs.max(0.0) * 1000.0) as u64)
+}
+
+/// Starts the watchdog thread.
+///
+/// `map_received` should be set once the first map comes in, at which point
+/// the watchdog stops.
+pub fn spawn(timeouts: Timeouts, map_received: Arc<AtomicBool>) -> thread::JoinHandle<()>
+{
+    thread::spawn(move ||
+    {
+        let start = Instant::now();
+        let mut warned = false;
+
+        while rosrust::is_ok() && !map_received.load(Ordering::SeqCst)
+        {
+            match timeouts.action(start.elapsed(), warned)
+            {
+                Action::Wait => {},
+
+                Action::Warn =>
+                {
+                    println!("WARNING! No map recieved after {:?}. Is anything publishing the map?", timeouts.warn_after);
+                    warned = true;
+                },
+
+                Action::Exit =>
+                {
+                    println!("ERROR! No map recieved after {:?}. Node is shutting down", start.elapsed());
+                    ::std::process::exit(1);
+                },
+            }
+
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // runs the watchdog's decisions against a pretend clock, which ticks once
+    // every `POLL_INTERVAL_MS`, and returns the time (in ms) of each action
+    // other than `Wait`.
+    fn run(timeouts: Timeouts, until_ms: u64) -> Vec<(u64, Action)>
+    {
+        let mut warned = false;
+        let mut actions = Vec::new();
+
+        for now in (0..until_ms / POLL_INTERVAL_MS + 1).map(|tick| tick * POLL_INTERVAL_MS)
+        {
+            match timeouts.action(Duration::from_millis(now), warned)
+            {
+                Action::Wait => {},
+
+                Action::Warn =>
+                {
+                    warned = true;
+                    actions.push((now, Action::Warn));
+                },
+
+                Action::Exit =>
+                {
+                    actions.push((now, Action::Exit));
+                    break;
+                },
+            }
+        }
+
+        actions
+    }
+
+    #[test]
+    fn warns_once_then_exits()
+    {
+        let timeouts = Timeouts { warn_after: seconds(1.0), exit_after: Some(seconds(2.5)) };
+
+        assert_eq!(run(timeouts, 10_000), vec![(1000, Action::Warn), (2500, Action::Exit)]);
+    }
+
+    #[test]
+    fn waits_forever_without_a_hard_timeout()
+    {
+        let timeouts = Timeouts { warn_after: seconds(0.5), exit_after: None };
+
+        assert_eq!(run(timeouts, 60_000), vec![(500, Action::Warn)]);
+    }
+
+    #[test]
+    fn exiting_beats_warning()
+    {
+        let timeouts = Timeouts { warn_after: seconds(5.0), exit_after: Some(seconds(1.0)) };
+
+        assert_eq!(run(timeouts, 10_000), vec![(1000, Action::Exit)]);
+        assert_eq!(seconds(-3.0), Duration::from_millis(0));
+    }
+}
@@ -0,0 +1,99 @@
+//! Runs a small hand-built map, containing one circle and one rectangle,
+//! through `detection::process_map`, exactly as the node would, and checks
+//! that it finds exactly those two shapes, in the right places.
+//!
+//! This doesn't need ROS running, so it's a quick way to check that a change
+//! hasn't broken anything end-to-end:
+//!
+//! ```text
+//! cargo test --release -p obstacle-detection --test fixture
+//! ```
+
+extern crate common;
+extern crate obstacle_detection;
+
+use common::prelude::*;
+use map_utils::{Map, MapBuilder};
+use obstacle_detection::model3::Shape;
+use obstacle_detection::detection::{self, DetectionParams};
+
+// a 3m x 3m arena (at 5cm per cell) with a circle of radius 25cm and a 40cm x
+// 60cm rectangle in it.
+fn fixture() -> Map
+{
+    with_obstacles(MapBuilder::new(60, 60)).build()
+}
+
+// the circle and the rectangle of the fixture.
+fn with_obstacles(map: MapBuilder) -> MapBuilder
+{
+    map.ring((15, 15), 5.0, 100)
+        .rect((36, 30), (36, 41), 100)
+        .rect((43, 30), (43, 41), 100)
+        .rect((36, 30), (43, 30), 100)
+        .rect((36, 41), (43, 41), 100)
+}
+
+// where a cell of the fixture is, in map coordinates.
+fn position(map: &Map, cell: (usize, usize)) -> (Num, Num)
+{
+    map_utils::transform::<Num, _>(map, vec![cell])[0]
+}
+
+fn close(a: (Num, Num), b: (Num, Num), tol: Num) -> bool
+{
+    (a.0 - b.0).hypot(a.1 - b.1) <= tol
+}
+
+#[test]
+fn finds_the_circle_and_the_rectangle()
+{
+    let map = fixture();
+
+    let shapes = detection::process_map(&map, &DetectionParams::default());
+
+    assert_eq!(shapes.len(), 2, "{:?}", shapes);
+
+    let circle = shapes.iter().filter_map(|s| if let Shape::Circle(ref c) = *s { Some(c) } else { None }).next();
+    let rectle = shapes.iter().filter_map(|s| if let Shape::Rectle(ref r) = *s { Some(r) } else { None }).next();
+
+    let circle = circle.unwrap_or_else(|| panic!("no circle in {:?}", shapes));
+    let rectle = rectle.unwrap_or_else(|| panic!("no rectangle in {:?}", shapes));
+
+    // the points are the centres of the cells, so both shapes are measured
+    // from those, and can be out by about a cell.
+    let cell = map.info.resolution as Num;
+
+    assert!(close(circle.centre, position(&map, (15, 15)), cell), "{}", circle);
+    assert!((circle.radius - 0.25).abs() <= cell, "{}", circle);
+
+    let (top_left, bottom_right) = (position(&map, (36, 30)), position(&map, (43, 41)));
+    let centre = ((top_left.0 + bottom_right.0) / 2.0, (top_left.1 + bottom_right.1) / 2.0);
+
+    assert!(close(rectle.centre, centre, cell), "{}", rectle);
+    assert!((2.0 * rectle.width - 0.35).abs() <= cell && (2.0 * rectle.length - 0.55).abs() <= cell, "{}", rectle);
+}
+
+#[test]
+fn skips_the_walls_and_the_noise()
+{
+    // the walls of the arena run around the edge of the map, and there are a
+    // couple of stray cells that are too small to be anything.
+    let map = with_obstacles(MapBuilder::new(60, 60))
+        .rect((0, 0), (0, 59), 100)
+        .rect((59, 0), (59, 59), 100)
+        .rect((0, 0), (59, 0), 100)
+        .rect((0, 59), (59, 59), 100)
+        .cell((50, 10), 100)
+        .cell((10, 45), 100)
+        .build();
+
+    let shapes = detection::process_map(&map, &DetectionParams::default());
+
+    assert_eq!(shapes.len(), 2, "{:?}", shapes);
+
+    // and they're the same shapes as without the walls and the noise.
+    let alone = detection::process_map(&fixture(), &DetectionParams::default());
+
+    assert_eq!(format!("{:?}", shapes), format!("{:?}", alone));
+}
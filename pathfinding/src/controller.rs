@@ -0,0 +1,193 @@
+//! Works out the velocity commands to send, without talking to ROS, so that it
+//! can be checked without a master running.
+
+use common::prelude::*;
+
+use common::msg::geometry_msgs::{Twist, Vector3};
+
+use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
+
+/// How many times the stop command is sent on shutdown, in case some of them
+/// get lost.
+pub const STOP_REPEATS: usize = 5;
+
+/// A forward (linear) speed, in m/s.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct LinearVel(pub Num);
+
+impl LinearVel
+{
+    /// A speed given in metres per second.
+    pub fn m_per_s(v: Num) -> Self
+    {
+        LinearVel(v)
+    }
+}
+
+/// A turning (angular) speed, in rad/s. Positive is anticlockwise.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct AngularVel(pub Num);
+
+impl AngularVel
+{
+    /// A turning speed given in radians per second.
+    pub fn rad_per_s(w: Num) -> Self
+    {
+        AngularVel(w)
+    }
+
+    /// A turning speed given in degrees per second, which is easier to get a
+    /// feel for, e.g `deg_per_s(90.0)` takes four seconds to turn around once.
+    pub fn deg_per_s(w: Num) -> Self
+    {
+        AngularVel(w.to_radians())
+    }
+}
+
+/// The command to send while running: spin in a circle, driving forwards at
+/// `linear` while turning at `angular`.
+pub fn spin(linear: LinearVel, angular: AngularVel) -> Twist
+{
+    let mut msg = Twist::default();
+
+    msg.linear.x = linear.0;
+    msg.angular.z = angular.0;
+
+    msg
+}
+
+/// The command that stops the robot.
+pub fn stop() -> Twist
+{
+    Twist::default()
+}
+
+/// The commands to send when the node is shutting down. The robot keeps doing
+/// whatever it was last told to, so if the node just stopped publishing, it
+/// would carry on spinning forever.
+pub fn shutdown_sequence() -> Vec<Twist>
+{
+    (0..STOP_REPEATS).map(|_| stop()).collect()
+}
+
+/// Sends each of the `shutdown_sequence` with `send`, waiting `interval`
+/// between them. They're all tried even if some of them can't be sent; there's
+/// nothing more that can be done about it at that point, so the errors are
+/// only logged.
+pub fn send_shutdown_sequence<E: Debug, F: FnMut(Twist) -> Result<(), E>>(mut send: F, interval: Duration)
+{
+    for msg in shutdown_sequence()
+    {
+        if let Err(e) = send(msg)
+        {
+            println!("ERROR! Could not send the stop command: {:?}", e);
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+/// Moves `current` towards `target`, changing each component of the linear
+/// velocity by at most `max_lin_accel * dt`, and each component of the angular
+/// velocity by at most `max_ang_accel * dt`.
+///
+/// Jumping straight to full speed makes the wheels slip, so each command sent
+/// should be the ramped version of what we actually want, where `current` is
+/// the command sent last time and `dt` is the time between commands.
+pub fn ramp(current: &Twist, target: &Twist, max_lin_accel: Num, max_ang_accel: Num, dt: Num) -> Twist
+{
+    let mut msg = Twist::default();
+
+    msg.linear  = ramp_vector(&current.linear,  &target.linear,  max_lin_accel * dt);
+    msg.angular = ramp_vector(&current.angular, &target.angular, max_ang_accel * dt);
+
+    msg
+}
+
+// moves each component of `current` towards `target` by at most `max_change`.
+fn ramp_vector(current: &Vector3, target: &Vector3, max_change: Num) -> Vector3
+{
+    let step = |from: f64, to: f64| from + (to - from).max(-max_change).min(max_change);
+
+    let mut v = Vector3::default();
+
+    v.x = step(current.x, target.x);
+    v.y = step(current.y, target.y);
+    v.z = step(current.z, target.z);
+
+    v
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn shutdown_sequence_stops_the_robot()
+    {
+        let sequence = shutdown_sequence();
+
+        assert_eq!(sequence.len(), STOP_REPEATS);
+        assert!(sequence.iter().all(|msg| *msg == stop()));
+
+        // every one is tried, even once some of them have failed.
+        let mut sent = Vec::new();
+
+        send_shutdown_sequence(|msg|
+        {
+            sent.push(msg);
+            if sent.len() == 2 { Err("lost") } else { Ok(()) }
+        },
+        Duration::from_millis(0));
+
+        assert_eq!(sent, sequence);
+    }
+
+    #[test]
+    fn ramp_reaches_the_target_without_jumping()
+    {
+        let target = spin(LinearVel::m_per_s(0.2), AngularVel::rad_per_s(2.0));
+
+        let mut command = stop();
+        let mut steps = 0;
+
+        while command != target
+        {
+            let next = ramp(&command, &target, 0.5, 4.0, 0.1);
+
+            assert!((next.linear.x - command.linear.x).abs() <= 0.05 + 1e-12, "{:?}", next);
+            assert!((next.angular.z - command.angular.z).abs() <= 0.4 + 1e-12, "{:?}", next);
+
+            command = next;
+            steps += 1;
+
+            assert!(steps < 100, "never got to {:?}", target);
+        }
+
+        // the turning is what takes longest to get up to speed.
+        assert_eq!(steps, 5);
+
+        // and it doesn't overshoot, or slow down once it's there.
+        assert_eq!(ramp(&target, &target, 0.5, 4.0, 0.1), target);
+    }
+
+    #[test]
+    fn degrees_per_second_are_converted_to_radians()
+    {
+        use std::f64::consts::PI;
+
+        assert!((AngularVel::deg_per_s(90.0).0 - PI / 2.0).abs() < 1e-12);
+        assert!((AngularVel::deg_per_s(-180.0).0 + PI).abs() < 1e-12);
+        assert_eq!(AngularVel::deg_per_s(0.0), AngularVel::rad_per_s(0.0));
+
+        // the default turning speed in main survives the trip through degrees.
+        let default = AngularVel::rad_per_s(2.0);
+        assert!((AngularVel::deg_per_s(default.0.to_degrees()).0 - default.0).abs() < 1e-12);
+
+        // and it's what ends up in the command.
+        assert_eq!(spin(LinearVel::m_per_s(0.2), AngularVel::deg_per_s(90.0)).angular.z, AngularVel::deg_per_s(90.0).0);
+    }
+}
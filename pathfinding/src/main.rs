@@ -10,33 +10,125 @@ extern crate common;
 
 use common::prelude::*;
 
-use msg::
+use common::msg::geometry_msgs::Twist;
+
+use std::time::Duration;
+
+/// The velocity commands.
+mod controller;
+
+/// Stops the robot when it's dropped, however `main` finishes (e.g by returning
+/// an error, or panicking), while the publisher is still around to send the
+/// stop commands. rosrust doesn't have a shutdown hook to do this from, and the
+/// robot keeps going with whatever it was last told otherwise.
+struct StopOnDrop
+{
+    publisher: rosrust::Publisher<Twist>,
+
+    /// How long to wait between the stop commands.
+    interval: Duration,
+}
+
+impl Drop for StopOnDrop
 {
-    geometry_msgs,
-};
+    fn drop(&mut self)
+    {
+        println!("stopping...");
+
+        let publisher = &mut self.publisher;
+
+        controller::send_shutdown_sequence(|msg| publisher.send(msg), self.interval);
+    }
+}
 
 fn main() -> Result<(), rosrust::error::Error>
 {
     rosrust::init("pathfinder");
     println!("pathfinder init");
 
-    // init the subscriber and set up callback
-    let mut _pub = rosrust::publish("/cmd_vel")?;
+    // the topic to send velocity commands on.
+    let cmd_vel_topic = params::get_or("~cmd_vel_topic", "/cmd_vel".to_string());
+
+    // the master can be unreachable for a little while, e.g if it's being
+    // restarted, so setting up the topic is retried for a bit before giving up.
+    let backoff = retry::Backoff::default();
+
+    let what = format!("publish to {}", cmd_vel_topic);
 
-    let mut rate = rosrust::rate(10.0);
+    let rate_hz = 10.0;
+    let mut rate = rosrust::rate(rate_hz);
+
+    // init the publisher. From here on, the robot is stopped on the way out,
+    // whatever the reason.
+    let mut _pub = StopOnDrop
+    {
+        publisher: retry::with_backoff(backoff, &what, || rosrust::publish(&cmd_vel_topic))?,
+        interval: Duration::from_millis((1000.0 / rate_hz) as u64),
+    };
+
+    // how quickly the robot can speed up (or slow down), in m/s^2 and rad/s^2.
+    let max_lin_accel = params::get_or::<Num>("~max_lin_accel", 0.5);
+    let max_ang_accel = params::get_or::<Num>("~max_ang_accel", 4.0);
+
+    // how fast to go around the circle, in m/s and deg/s. The default turning
+    // speed is quite fast.
+    let default_turn_rate = controller::AngularVel::rad_per_s(2.0);
+
+    let forward_speed = controller::LinearVel::m_per_s(params::get_or::<Num>("~forward_speed", 0.2));
+    let turn_rate = controller::AngularVel::deg_per_s(params::get_or::<Num>("~turn_rate_deg", default_turn_rate.0.to_degrees()));
+
+    let target = controller::spin(forward_speed, turn_rate);
+
+    // the robot starts out still, and is ramped up to speed from there.
+    let mut command = controller::stop();
 
     println!("spinning...");
 
+    let mut result = Ok(());
+
+    // a restarted master has forgotten the topic, so it's set up again when
+    // the master's run id changes. Asking the master every loop would be a
+    // lot of traffic, so it's only checked about once a second.
+    let mut run_id = retry::RunIdWatcher::new();
+    let mut loops = 0;
+
     while rosrust::is_ok()
     {
-        let mut msg = geometry_msgs::Twist::default();
+        command = controller::ramp(&command, &target, max_lin_accel, max_ang_accel, 1.0 / rate_hz);
+
+        loops += 1;
+        let restarted = loops % rate_hz as usize == 0 && run_id.restarted();
+
+        if restarted
+        {
+            println!("WARNING! The master has restarted. Setting up {} again", cmd_vel_topic);
+        }
+
+        let sent = _pub.publisher.send(command.clone());
+
+        if let Err(ref e) = sent
+        {
+            // the connection may have gone with the master, so try setting
+            // the topic up again before giving up on it.
+            println!("WARNING! Could not send a velocity command: {:?}", e);
+        }
 
-        msg.angular.z = 2.0;
-        msg.linear.x = 0.2;
+        if restarted || sent.is_err()
+        {
+            match retry::with_backoff(backoff, &what, || rosrust::publish(&cmd_vel_topic))
+            {
+                Ok(p) => _pub.publisher = p,
+                Err(e) =>
+                {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
 
-        _pub.send(msg)?;
         rate.sleep();
     }
 
-    Ok(())
+    // `_pub` stops the robot as it's dropped.
+    result
 }